@@ -0,0 +1,92 @@
+/*
+ * rarity 节点插件的 C ABI, 与 rarity-engine 的 plugin 模块一一对应.
+ *
+ * 插件是导出 rarity_plugin_entry 的动态库, 入口返回的描述在库卸载之前一直有效.
+ * 字符串都是以 0 结尾的 UTF-8, 事件中的 RarityStr 除外.
+ * 描述不合法 (声道数为 0, 参数默认值超出范围, 数量不为 0 的数组为 NULL 等) 时拒绝加载.
+ *
+ * 线程: create 和 prepare 在编辑图的线程调用, process 在音频线程调用,
+ * destroy 在任一线程调用. 实例随图在线程间移动, 但同一实例的调用不会并发,
+ * 实例不能依赖创建它的线程 (如线程局部存储). process 不能阻塞或分配内存.
+ */
+#ifndef RARITY_PLUGIN_H
+#define RARITY_PLUGIN_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define RARITY_PLUGIN_ABI_VERSION 1
+
+#define RARITY_NODE_AUDIO_EFFECT 0
+#define RARITY_NODE_AUDIO_SOURCE 1
+
+#define RARITY_EVENT_FLOAT 0
+#define RARITY_EVENT_ENUM 1
+#define RARITY_EVENT_NOTE_ON 2
+#define RARITY_EVENT_NOTE_OFF 3
+#define RARITY_EVENT_CONTROL_CHANGE 4
+#define RARITY_EVENT_PITCH_BEND 5
+
+/* 不以 0 结尾的 UTF-8 字符串 */
+typedef struct RarityStr {
+    const uint8_t *ptr;
+    size_t len;
+} RarityStr;
+
+/*
+ * 发给节点的控制信息, 按帧排序, 只在 process 期间有效.
+ * 参数事件的 name 为参数名, value 为取值或选项下标; 音符事件的 number 为音高,
+ * value 为力度; 控制器事件的 number 为控制器号; 弯音事件的 value 为弯音值.
+ */
+typedef struct RarityEvent {
+    uint32_t frame;
+    uint32_t kind;
+    RarityStr name;
+    uint8_t number;
+    double value;
+} RarityEvent;
+
+/*
+ * option_count 为 0 时是浮点参数, default_value 需要在 min 和 max 之间;
+ * 否则是以选项下标取值的枚举参数, default_value 需要是选项下标.
+ */
+typedef struct RarityParameter {
+    const char *name;
+    double min;
+    double max;
+    double default_value;
+    const char *const *options;
+    size_t option_count;
+} RarityParameter;
+
+typedef struct RarityNodeType {
+    /* 全局唯一的类型名, 如 "team.reverb" */
+    const char *id;
+    const char *name;
+    /* RARITY_NODE_AUDIO_EFFECT 或 RARITY_NODE_AUDIO_SOURCE, 音频效果只有一个输入 */
+    uint32_t kind;
+    /* 输入输出的声道数, 不能为 0 */
+    uint32_t channels;
+    const RarityParameter *parameters;
+    size_t parameter_count;
+    /* JSON 对象形式的构造参数默认值, 可以为 NULL */
+    const char *default_args;
+    /* 按 JSON 形式的构造参数创建实例, 失败时返回 NULL */
+    void *(*create)(const char *args);
+    void (*destroy)(void *instance);
+    /* 准备处理, 返回输出相对输入延迟的帧数 */
+    uint32_t (*prepare)(void *instance, double sample_rate);
+    /* 在音频线程调用. 每个声道一个指针, 输出已经清零; 音频源的 input 为 NULL */
+    void (*process)(void *instance, size_t frames, const double *const *input,
+                    double *const *output, const RarityEvent *events, size_t event_count);
+} RarityNodeType;
+
+typedef struct RarityPlugin {
+    uint32_t abi_version;
+    const RarityNodeType *node_types;
+    size_t node_type_count;
+} RarityPlugin;
+
+const RarityPlugin *rarity_plugin_entry(void);
+
+#endif
@@ -1,134 +1,408 @@
-use std::{cmp::Ordering, fmt};
+use std::{fmt, marker::PhantomData, ops::Range, slice};
 
-/// 左右声道交替的音频数据
-pub struct AudioBuffer(Vec<f64>, usize);
+use super::Sample;
 
-pub struct AudioBufferMut<'a>(&'a mut [f64], &'a mut [f64]);
+/// 样本在缓冲中的排列方式
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum BufferLayout {
+    /// 每个声道的样本连续存放, 一个块内的每个声道都是一段连续的切片, 便于向量化处理
+    #[default]
+    Planar,
+    /// 各声道交替存放, 每帧依次存放所有声道的样本, 与多数音频接口的格式相同
+    Interleaved,
+}
 
-#[derive(Clone, Copy)]
-pub struct AudioBufferRef<'a>(&'a [f64], &'a [f64]);
+/// 环形的音频缓冲, 可以是任意声道数和样本类型
+///
+/// 声道按常见的顺序排列: 双声道为左, 右; 5.1 为左, 右, 中置, 低音, 左环绕, 右环绕;
+/// Ambisonics 按 ACN 顺序.
+pub struct AudioBuffer<S = f64> {
+    data: Vec<S>,
+    pos: usize,
+    channels: usize,
+    layout: BufferLayout,
+}
+
+/// 缓冲中连续若干帧的只读视图, 跨过缓冲末尾时从开头继续
+pub struct AudioBufferRef<'a, S = f64> {
+    data: *const S,
+    /// 整个缓冲的帧数
+    capacity: usize,
+    start: usize,
+    frames: usize,
+    channels: usize,
+    layout: BufferLayout,
+    _marker: PhantomData<&'a [S]>,
+}
+
+/// 缓冲中连续若干帧的可写视图, 跨过缓冲末尾时从开头继续
+pub struct AudioBufferMut<'a, S = f64> {
+    data: *mut S,
+    capacity: usize,
+    start: usize,
+    frames: usize,
+    channels: usize,
+    layout: BufferLayout,
+    _marker: PhantomData<&'a mut [S]>,
+}
+
+// 视图和切片一样只允许通过借用规则访问数据
+unsafe impl<S: Sync> Send for AudioBufferRef<'_, S> {}
+unsafe impl<S: Sync> Sync for AudioBufferRef<'_, S> {}
+unsafe impl<S: Send> Send for AudioBufferMut<'_, S> {}
+unsafe impl<S: Sync> Sync for AudioBufferMut<'_, S> {}
+
+impl<S> Clone for AudioBufferRef<'_, S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<S> Copy for AudioBufferRef<'_, S> {}
 
-pub struct AudioBufferIterMut<'a>(AudioBufferMut<'a>, usize);
+/// 按左右声道成对遍历, 只能用于双声道缓冲
+pub struct AudioBufferIterMut<'a, S = f64>(AudioBufferMut<'a, S>, usize);
 
+/// 按左右声道成对遍历, 只能用于双声道缓冲
 #[derive(Clone, Copy)]
-pub struct AudioBufferIter<'a>(AudioBufferRef<'a>, usize);
+pub struct AudioBufferIter<'a, S = f64>(AudioBufferRef<'a, S>, usize);
 
 impl AudioBuffer {
+    /// 双声道的 f64 缓冲
     pub fn new(len: usize) -> Self {
+        Self::with_channels(2, len)
+    }
+}
+
+impl<S: Sample> AudioBuffer<S> {
+    pub fn with_channels(channels: usize, len: usize) -> Self {
+        Self::with_layout(BufferLayout::default(), channels, len)
+    }
+    pub fn with_layout(layout: BufferLayout, channels: usize, len: usize) -> Self {
         if len == 0 {
             panic!("尝试声明空缓冲")
         }
-        Self(vec![0.0; len * 2], 0)
+        if channels == 0 {
+            panic!("声道数不能为 0")
+        }
+        Self {
+            data: vec![S::default(); len * channels],
+            pos: 0,
+            channels,
+            layout,
+        }
+    }
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+    pub fn layout(&self) -> BufferLayout {
+        self.layout
     }
-    pub fn next_n_frames_mut(&mut self, frames: usize) -> AudioBufferMut {
+    pub fn next_n_frames_mut(&mut self, frames: usize) -> AudioBufferMut<'_, S> {
         if frames > self.len() {
             panic!("超过最大容量")
         }
-        if self.0.len() >= (self.1 + frames) * 2 {
-            let (_, r) = self.0.split_at_mut(self.1 * 2);
-            let (m, _) = r.split_at_mut(frames * 2);
-            AudioBufferMut(m, &mut [])
-        } else {
-            let rest = (self.1 + frames) - self.len();
-            let (l, r) = self.0.split_at_mut(self.1 * 2);
-            let (ll, _) = l.split_at_mut(rest * 2);
-            AudioBufferMut(r, ll)
+        AudioBufferMut {
+            data: self.data.as_mut_ptr(),
+            capacity: self.len(),
+            start: self.pos,
+            frames,
+            channels: self.channels,
+            layout: self.layout,
+            _marker: PhantomData,
         }
     }
-    pub fn next_n_frames_ref(&self, frames: usize) -> AudioBufferRef {
+    pub fn next_n_frames_ref(&self, frames: usize) -> AudioBufferRef<'_, S> {
+        self.n_frames_ref_from(self.pos, frames)
+    }
+    /// 当前位置之前的 n 帧, 即最近写入的 n 帧
+    pub fn prev_n_frames_ref(&self, frames: usize) -> AudioBufferRef<'_, S> {
         if frames > self.len() {
             panic!("超过最大容量")
         }
-        if self.0.len() >= (self.1 + frames) * 2 {
-            let (_, r) = self.0.split_at(self.1 * 2);
-            let (m, _) = r.split_at(frames * 2);
-            AudioBufferRef(m, &[])
-        } else {
-            let rest = (self.1 + frames) - self.len();
-            let (l, r) = self.0.split_at(self.1 * 2);
-            let (ll, _) = l.split_at(rest * 2);
-            AudioBufferRef(r, ll)
+        self.n_frames_ref_from((self.pos + self.len() - frames) % self.len(), frames)
+    }
+    /// 从当前位置之前 delay 帧开始的 n 帧, delay 不超过 n 时包含当前位置之后的内容
+    pub fn delayed_n_frames_ref(&self, delay: usize, frames: usize) -> AudioBufferRef<'_, S> {
+        if delay > self.len() {
+            panic!("超过最大容量")
+        }
+        self.n_frames_ref_from((self.pos + self.len() - delay) % self.len(), frames)
+    }
+    fn n_frames_ref_from(&self, start: usize, frames: usize) -> AudioBufferRef<'_, S> {
+        if frames > self.len() {
+            panic!("超过最大容量")
+        }
+        AudioBufferRef {
+            data: self.data.as_ptr(),
+            capacity: self.len(),
+            start,
+            frames,
+            channels: self.channels,
+            layout: self.layout,
+            _marker: PhantomData,
         }
     }
     pub fn len(&self) -> usize {
-        self.0.len() / 2
+        self.data.len() / self.channels
     }
     pub fn is_empty(&self) -> bool {
-        self.0.is_empty()
+        self.data.is_empty()
     }
     pub fn forward(&mut self, frames: usize) {
-        self.1 = (self.1 + frames) % self.len();
+        self.pos = (self.pos + frames) % self.len();
+    }
+    /// 回到缓冲的开头, 之后的块都从开头开始, 不会跨过缓冲末尾
+    pub fn rewind(&mut self) {
+        self.pos = 0;
     }
 }
 
-impl<'a> AudioBufferMut<'a> {
-    pub fn len(&self) -> usize {
-        (self.0.len() + self.1.len()) / 2
-    }
-    pub fn is_empty(&self) -> bool {
-        self.0.is_empty() && self.1.is_empty()
+/// 视图共有的下标计算, 视图本身只是一组下标和指针
+macro_rules! impl_view {
+    ($view:ident) => {
+        impl<'a, S: Sample> $view<'a, S> {
+            pub fn channels(&self) -> usize {
+                self.channels
+            }
+            pub fn layout(&self) -> BufferLayout {
+                self.layout
+            }
+            pub fn len(&self) -> usize {
+                self.frames
+            }
+            pub fn is_empty(&self) -> bool {
+                self.frames == 0
+            }
+            /// 平面排列且没有跨过缓冲末尾, 此时每个声道都是一段连续的切片
+            ///
+            /// 图交给节点的缓冲总是连续的.
+            pub fn is_contiguous(&self) -> bool {
+                self.layout == BufferLayout::Planar && self.start + self.frames <= self.capacity
+            }
+            /// 第 frame 帧第 channel 个声道的样本在数据中的下标, 越界时 panic
+            fn index(&self, frame: usize, channel: usize) -> usize {
+                assert!(
+                    frame < self.frames && channel < self.channels,
+                    "({}, {}) 超出范围 ({}, {})",
+                    frame,
+                    channel,
+                    self.frames,
+                    self.channels
+                );
+                let mut frame = self.start + frame;
+                if frame >= self.capacity {
+                    frame -= self.capacity;
+                }
+                match self.layout {
+                    BufferLayout::Planar => channel * self.capacity + frame,
+                    BufferLayout::Interleaved => frame * self.channels + channel,
+                }
+            }
+            fn split_view(&self, mid: usize) -> (usize, usize) {
+                assert!(mid <= self.frames);
+                (self.start, (self.start + mid) % self.capacity)
+            }
+        }
+    };
+}
+
+impl_view!(AudioBufferRef);
+impl_view!(AudioBufferMut);
+
+impl<'a, S: Sample> AudioBufferMut<'a, S> {
+    /// 块在数据中占据的若干段连续下标
+    fn runs(&self) -> impl Iterator<Item = Range<usize>> {
+        let first = self.start..(self.start + self.frames).min(self.capacity);
+        let second = 0..(self.start + self.frames).saturating_sub(self.capacity);
+        let (capacity, channels) = (self.capacity, self.channels);
+        let planar = self.layout == BufferLayout::Planar;
+        [first, second]
+            .into_iter()
+            .filter(|r| !r.is_empty())
+            .flat_map(move |r| {
+                let per_channel = if planar { channels } else { 1 };
+                (0..per_channel).map(move |c| match planar {
+                    true => c * capacity + r.start..c * capacity + r.end,
+                    false => r.start * channels..r.end * channels,
+                })
+            })
     }
     pub fn clear(&mut self) {
-        self.0.fill(0.0);
-        self.1.fill(0.0);
-    }
-    pub fn split_at_mut(self, mid: usize) -> (AudioBufferMut<'a>, AudioBufferMut<'a>) {
-        assert!(mid <= self.len());
-        match self.0.len().cmp(&(mid * 2)) {
-            Ordering::Less => {
-                let (l, r) = self.1.split_at_mut(mid * 2 - self.0.len());
-                (AudioBufferMut(self.0, l), AudioBufferMut(r, &mut []))
+        let data = self.data;
+        for run in self.runs() {
+            unsafe { slice::from_raw_parts_mut(data.add(run.start), run.len()) }.fill(S::default());
+        }
+    }
+    pub fn get(&self, frame: usize, channel: usize) -> S {
+        unsafe { *self.data.add(self.index(frame, channel)) }
+    }
+    pub fn get_mut(&mut self, frame: usize, channel: usize) -> &mut S {
+        unsafe { &mut *self.data.add(self.index(frame, channel)) }
+    }
+    /// 第 channel 个声道在块内的样本, 缓冲块需要是连续的
+    pub fn channel_mut(&mut self, channel: usize) -> &mut [S] {
+        assert!(self.is_contiguous(), "缓冲块不连续");
+        assert!(channel < self.channels);
+        // 连续的块在数据中从 start 开始, 块长可以为 0
+        let start = channel * self.capacity + self.start;
+        unsafe { slice::from_raw_parts_mut(self.data.add(start), self.frames) }
+    }
+    /// 依次取出所有声道在块内的样本, 缓冲块需要是连续的
+    pub fn channels_mut(&mut self) -> impl Iterator<Item = &mut [S]> + '_ {
+        assert!(self.is_contiguous(), "缓冲块不连续");
+        let (data, frames, capacity) = (self.data, self.frames, self.capacity);
+        let start = self.start;
+        // 不同声道的切片互不重叠
+        (0..self.channels).map(move |c| unsafe {
+            slice::from_raw_parts_mut(data.add(c * capacity + start), frames)
+        })
+    }
+    pub fn as_ref(&self) -> AudioBufferRef<'_, S> {
+        AudioBufferRef {
+            data: self.data,
+            capacity: self.capacity,
+            start: self.start,
+            frames: self.frames,
+            channels: self.channels,
+            layout: self.layout,
+            _marker: PhantomData,
+        }
+    }
+    /// 复制 src 的内容, 两者的声道数需要相同, 排列方式和样本类型不同时逐个转换
+    pub fn copy_from<T: Sample>(&mut self, src: AudioBufferRef<T>) {
+        assert_eq!(self.channels, src.channels, "声道数不一致");
+        let frames = self.frames.min(src.frames);
+        for c in 0..self.channels {
+            for i in 0..frames {
+                *self.get_mut(i, c) = S::from_sample(src.get(i, c));
             }
-            Ordering::Equal => (
-                AudioBufferMut(self.0, &mut []),
-                AudioBufferMut(self.1, &mut []),
-            ),
-            Ordering::Greater => {
-                let (l, r) = self.0.split_at_mut(mid * 2);
-                (AudioBufferMut(l, &mut []), AudioBufferMut(r, self.1))
+        }
+    }
+    /// 把 src 混合到当前内容上, 声道数不同时按 [`mix_gain`] 上混或下混
+    pub fn mix_from<T: Sample>(&mut self, src: AudioBufferRef<T>) {
+        let frames = self.frames.min(src.frames);
+        for tc in 0..self.channels {
+            for sc in 0..src.channels {
+                let gain = mix_gain(src.channels, self.channels, sc, tc);
+                if gain == 1.0 {
+                    for i in 0..frames {
+                        *self.get_mut(i, tc) += S::from_sample(src.get(i, sc));
+                    }
+                } else if gain != 0.0 {
+                    for i in 0..frames {
+                        *self.get_mut(i, tc) += S::from_f64(src.get(i, sc).to_f64() * gain);
+                    }
+                }
             }
         }
     }
+    /// 块内 range 范围的帧
+    pub fn slice_mut(&mut self, range: Range<usize>) -> AudioBufferMut<'_, S> {
+        assert!(range.start <= range.end && range.end <= self.frames);
+        AudioBufferMut {
+            start: (self.start + range.start) % self.capacity,
+            frames: range.len(),
+            ..*self
+        }
+    }
+    pub fn split_at_mut(self, mid: usize) -> (AudioBufferMut<'a, S>, AudioBufferMut<'a, S>) {
+        let (first, second) = self.split_view(mid);
+        // 两个视图的帧互不重叠
+        (
+            AudioBufferMut {
+                start: first,
+                frames: mid,
+                ..self
+            },
+            AudioBufferMut {
+                start: second,
+                frames: self.frames - mid,
+                ..self
+            },
+        )
+    }
 }
 
-impl<'a> AudioBufferRef<'a> {
-    pub fn len(&self) -> usize {
-        (self.0.len() + self.1.len()) / 2
+impl<'a, S: Sample> AudioBufferRef<'a, S> {
+    pub fn get(&self, frame: usize, channel: usize) -> S {
+        unsafe { *self.data.add(self.index(frame, channel)) }
     }
-    pub fn is_empty(&self) -> bool {
-        self.0.is_empty() && self.1.is_empty()
+    /// 第 channel 个声道在块内的样本, 缓冲块需要是连续的
+    pub fn channel(&self, channel: usize) -> &'a [S] {
+        assert!(self.is_contiguous(), "缓冲块不连续");
+        assert!(channel < self.channels);
+        // 连续的块在数据中从 start 开始, 块长可以为 0
+        let start = channel * self.capacity + self.start;
+        unsafe { slice::from_raw_parts(self.data.add(start), self.frames) }
     }
-    pub fn iter(&self) -> AudioBufferIter {
-        AudioBufferIter(*self, 0)
+    pub fn iter(&self) -> AudioBufferIter<'a, S> {
+        self.into_iter()
     }
-    pub fn split_at(&self, mid: usize) -> (AudioBufferRef<'a>, AudioBufferRef<'a>) {
-        assert!(mid <= self.len());
-        match self.0.len().cmp(&(mid * 2)) {
-            Ordering::Less => {
-                let (l, r) = self.1.split_at(mid * 2 - self.0.len());
-                (AudioBufferRef(self.0, l), AudioBufferRef(r, &[]))
-            }
-            Ordering::Equal => (AudioBufferRef(self.0, &[]), AudioBufferRef(self.1, &[])),
-            Ordering::Greater => {
-                let (l, r) = self.0.split_at(mid * 2);
-                (AudioBufferRef(l, &[]), AudioBufferRef(r, self.1))
-            }
+    /// 块内 range 范围的帧
+    pub fn slice(&self, range: Range<usize>) -> AudioBufferRef<'a, S> {
+        assert!(range.start <= range.end && range.end <= self.frames);
+        AudioBufferRef {
+            start: (self.start + range.start) % self.capacity,
+            frames: range.len(),
+            ..*self
         }
     }
+    pub fn split_at(&self, mid: usize) -> (AudioBufferRef<'a, S>, AudioBufferRef<'a, S>) {
+        let (first, second) = self.split_view(mid);
+        (
+            AudioBufferRef {
+                start: first,
+                frames: mid,
+                ..*self
+            },
+            AudioBufferRef {
+                start: second,
+                frames: self.frames - mid,
+                ..*self
+            },
+        )
+    }
+}
+
+/// 声道数从 from 变为 to 时, 来源第 src 个声道混入目标第 tgt 个声道的增益
+///
+/// - 声道数相同时逐声道对应
+/// - 单声道上混时送入前两个声道(左右)
+/// - 下混为单声道时取所有声道的平均
+/// - 5.1 下混为双声道时, 中置和同侧环绕按 -3dB 并入左右, 舍弃低音声道
+/// - 其余情况逐声道对应, 多出的来源声道被舍弃, 多出的目标声道保持不变
+pub fn mix_gain(from: usize, to: usize, src: usize, tgt: usize) -> f64 {
+    const MINUS_3DB: f64 = std::f64::consts::FRAC_1_SQRT_2;
+    let same = if src == tgt { 1.0 } else { 0.0 };
+    match (from, to) {
+        (a, b) if a == b => same,
+        (1, _) => (tgt < 2) as u8 as f64,
+        (n, 1) => 1.0 / n as f64,
+        (6, 2) => match (src, tgt) {
+            (0, 0) | (1, 1) => 1.0,
+            (2, _) | (4, 0) | (5, 1) => MINUS_3DB,
+            _ => 0.0,
+        },
+        _ => same,
+    }
 }
 
-impl<'a> AudioBufferIterMut<'a> {
-    pub fn into_mut(self) -> AudioBufferMut<'a> {
+impl<'a, S> AudioBufferIterMut<'a, S> {
+    pub fn into_mut(self) -> AudioBufferMut<'a, S> {
         self.0
     }
 }
 
-impl fmt::Debug for AudioBuffer {
+impl<S: Sample> fmt::Debug for AudioBuffer<S> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut data = String::default();
-        let buffer_ref = AudioBufferRef(&self.0, &[]);
-        for (i, (l, r)) in buffer_ref.iter().enumerate() {
-            data += &format!("{}, {}", l, r);
+        let buffer_ref = self.n_frames_ref_from(0, self.len());
+        for i in 0..self.len() {
+            let frame = (0..self.channels)
+                .map(|c| buffer_ref.get(i, c).to_string())
+                .collect::<Vec<_>>();
+            data += &frame.join(", ");
             if i != self.len() - 1 {
                 data += "|";
             }
@@ -137,34 +411,27 @@ impl fmt::Debug for AudioBuffer {
     }
 }
 
-impl<'a> IntoIterator for AudioBufferMut<'a> {
-    type Item = (&'a mut f64, &'a mut f64);
-    type IntoIter = AudioBufferIterMut<'a>;
+impl<'a, S: Sample> IntoIterator for AudioBufferMut<'a, S> {
+    type Item = (&'a mut S, &'a mut S);
+    type IntoIter = AudioBufferIterMut<'a, S>;
 
     fn into_iter(self) -> Self::IntoIter {
+        assert_eq!(self.channels, 2, "不是双声道缓冲");
         AudioBufferIterMut(self, 0)
     }
 }
 
-impl<'a> Iterator for AudioBufferIterMut<'a> {
-    type Item = (&'a mut f64, &'a mut f64);
+impl<'a, S: Sample> Iterator for AudioBufferIterMut<'a, S> {
+    type Item = (&'a mut S, &'a mut S);
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.1 < self.0 .0.len() / 2 {
+        if self.1 < self.0.len() {
+            let view = &self.0;
+            // 每帧的两个样本只会被取出一次
             let res = unsafe {
                 (
-                    self.0 .0.as_mut_ptr().add(self.1 * 2).as_mut().unwrap(),
-                    self.0 .0.as_mut_ptr().add(self.1 * 2 + 1).as_mut().unwrap(),
-                )
-            };
-            self.1 += 1;
-            Some(res)
-        } else if self.1 < self.0.len() {
-            let ind = self.1 - self.0 .0.len() / 2;
-            let res = unsafe {
-                (
-                    self.0 .1.as_mut_ptr().add(ind * 2).as_mut().unwrap(),
-                    self.0 .1.as_mut_ptr().add(ind * 2 + 1).as_mut().unwrap(),
+                    &mut *view.data.add(view.index(self.1, 0)),
+                    &mut *view.data.add(view.index(self.1, 1)),
                 )
             };
             self.1 += 1;
@@ -179,37 +446,39 @@ impl<'a> Iterator for AudioBufferIterMut<'a> {
     }
 }
 
-impl<'a> ExactSizeIterator for AudioBufferIterMut<'a> {}
+impl<'a, S: Sample> ExactSizeIterator for AudioBufferIterMut<'a, S> {}
 
-impl<'a> IntoIterator for AudioBufferRef<'a> {
-    type Item = (&'a f64, &'a f64);
-    type IntoIter = AudioBufferIter<'a>;
+impl<'a, S: Sample> IntoIterator for AudioBufferRef<'a, S> {
+    type Item = (&'a S, &'a S);
+    type IntoIter = AudioBufferIter<'a, S>;
 
     fn into_iter(self) -> Self::IntoIter {
+        assert_eq!(self.channels, 2, "不是双声道缓冲");
         AudioBufferIter(self, 0)
     }
 }
 
-impl<'a> IntoIterator for &AudioBufferRef<'a> {
-    type Item = (&'a f64, &'a f64);
+impl<'a, S: Sample> IntoIterator for &AudioBufferRef<'a, S> {
+    type Item = (&'a S, &'a S);
 
-    type IntoIter = AudioBufferIter<'a>;
+    type IntoIter = AudioBufferIter<'a, S>;
     fn into_iter(self) -> Self::IntoIter {
-        AudioBufferIter(*self, 0)
+        (*self).into_iter()
     }
 }
 
-impl<'a> Iterator for AudioBufferIter<'a> {
-    type Item = (&'a f64, &'a f64);
+impl<'a, S: Sample> Iterator for AudioBufferIter<'a, S> {
+    type Item = (&'a S, &'a S);
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.1 < self.0 .0.len() / 2 {
-            let res = (&self.0 .0[self.1 * 2], &self.0 .0[self.1 * 2 + 1]);
-            self.1 += 1;
-            Some(res)
-        } else if self.1 < self.0.len() {
-            let ind = self.1 - self.0 .0.len() / 2;
-            let res = (&self.0 .1[ind * 2], &self.0 .1[ind * 2 + 1]);
+        if self.1 < self.0.len() {
+            let view = &self.0;
+            let res = unsafe {
+                (
+                    &*view.data.add(view.index(self.1, 0)),
+                    &*view.data.add(view.index(self.1, 1)),
+                )
+            };
             self.1 += 1;
             Some(res)
         } else {
@@ -222,4 +491,4 @@ impl<'a> Iterator for AudioBufferIter<'a> {
     }
 }
 
-impl<'a> ExactSizeIterator for AudioBufferIter<'a> {}
+impl<'a, S: Sample> ExactSizeIterator for AudioBufferIter<'a, S> {}
@@ -36,7 +36,7 @@ impl MessageBuffer {
     pub fn clear(&mut self) {
         self.0.clear();
     }
-    pub fn iter(&self) -> MessageBufferIter {
+    pub fn iter(&self) -> MessageBufferIter<'_> {
         MessageBufferIter(&self.0, 0)
     }
     pub fn get(&self, index: usize) -> Option<(&usize, &Message)> {
@@ -1,4 +1,7 @@
 mod audio;
 pub use audio::*;
+mod sample;
+pub(crate) use sample::sealed::{AnyAudioBuffer, AnyAudioMut, AnyAudioRef, AudioIo, Sealed};
+pub use sample::*;
 mod message;
 pub use message::*;
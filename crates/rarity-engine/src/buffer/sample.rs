@@ -0,0 +1,170 @@
+use std::{
+    fmt,
+    ops::{Add, AddAssign, Div, Mul, Neg, Sub},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{AudioBuffer, AudioBufferMut, AudioBufferRef};
+
+/// 样本的格式, 对应 [`Sample`] 的具体类型
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SampleFormat {
+    F32,
+    #[default]
+    F64,
+}
+
+/// 音频样本的类型, 目前支持 f32 和 f64
+///
+/// 节点以某种样本类型处理音频, 图在样本类型不同的节点之间插入转换.
+pub trait Sample:
+    sealed::Sealed
+    + Copy
+    + Default
+    + PartialEq
+    + PartialOrd
+    + fmt::Debug
+    + fmt::Display
+    + Send
+    + Sync
+    + 'static
+    + Add<Output = Self>
+    + AddAssign
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + Neg<Output = Self>
+{
+    const FORMAT: SampleFormat;
+    fn from_f64(value: f64) -> Self;
+    fn to_f64(self) -> f64;
+    fn from_sample<T: Sample>(value: T) -> Self {
+        Self::from_f64(value.to_f64())
+    }
+}
+
+/// 擦除样本类型的缓冲, 只在图内部使用
+pub(crate) mod sealed {
+    use super::*;
+
+    pub enum AnyAudioBuffer {
+        F32(AudioBuffer<f32>),
+        F64(AudioBuffer<f64>),
+    }
+
+    pub enum AnyAudioRef<'a> {
+        F32(AudioBufferRef<'a, f32>),
+        F64(AudioBufferRef<'a, f64>),
+    }
+
+    pub enum AnyAudioMut<'a> {
+        F32(AudioBufferMut<'a, f32>),
+        F64(AudioBufferMut<'a, f64>),
+    }
+
+    /// 节点的音频输入和输出
+    pub enum AudioIo<'a> {
+        F32(Vec<AudioBufferRef<'a, f32>>, Vec<AudioBufferMut<'a, f32>>),
+        F64(Vec<AudioBufferRef<'a, f64>>, Vec<AudioBufferMut<'a, f64>>),
+    }
+
+    pub trait Sealed: Sized {
+        fn buffer(any: &AnyAudioBuffer) -> &AudioBuffer<Self>;
+        fn buffer_mut(any: &mut AnyAudioBuffer) -> &mut AudioBuffer<Self>;
+        fn wrap_ref(buffer: AudioBufferRef<Self>) -> AnyAudioRef;
+        fn wrap_mut(buffer: AudioBufferMut<Self>) -> AnyAudioMut;
+        fn wrap_io<'a>(
+            audio_in: Vec<AudioBufferRef<'a, Self>>,
+            audio_out: Vec<AudioBufferMut<'a, Self>>,
+        ) -> AudioIo<'a>;
+        #[allow(clippy::type_complexity)]
+        fn unwrap_io(io: AudioIo) -> (Vec<AudioBufferRef<Self>>, Vec<AudioBufferMut<Self>>);
+    }
+
+    impl AnyAudioBuffer {
+        pub fn new(format: SampleFormat, channels: usize, len: usize) -> Self {
+            match format {
+                SampleFormat::F32 => Self::F32(AudioBuffer::with_channels(channels, len)),
+                SampleFormat::F64 => Self::F64(AudioBuffer::with_channels(channels, len)),
+            }
+        }
+
+        pub fn forward(&mut self, frames: usize) {
+            match self {
+                Self::F32(b) => b.forward(frames),
+                Self::F64(b) => b.forward(frames),
+            }
+        }
+    }
+}
+
+use sealed::*;
+
+macro_rules! impl_sample {
+    ($t:ty, $variant:ident) => {
+        impl Sample for $t {
+            const FORMAT: SampleFormat = SampleFormat::$variant;
+            fn from_f64(value: f64) -> Self {
+                value as $t
+            }
+            fn to_f64(self) -> f64 {
+                self as f64
+            }
+        }
+
+        impl Sealed for $t {
+            fn buffer(any: &AnyAudioBuffer) -> &AudioBuffer<Self> {
+                match any {
+                    AnyAudioBuffer::$variant(b) => b,
+                    _ => panic!("样本格式不一致"),
+                }
+            }
+            fn buffer_mut(any: &mut AnyAudioBuffer) -> &mut AudioBuffer<Self> {
+                match any {
+                    AnyAudioBuffer::$variant(b) => b,
+                    _ => panic!("样本格式不一致"),
+                }
+            }
+            fn wrap_ref(buffer: AudioBufferRef<Self>) -> AnyAudioRef {
+                AnyAudioRef::$variant(buffer)
+            }
+            fn wrap_mut(buffer: AudioBufferMut<Self>) -> AnyAudioMut {
+                AnyAudioMut::$variant(buffer)
+            }
+            fn wrap_io<'a>(
+                audio_in: Vec<AudioBufferRef<'a, Self>>,
+                audio_out: Vec<AudioBufferMut<'a, Self>>,
+            ) -> AudioIo<'a> {
+                AudioIo::$variant(audio_in, audio_out)
+            }
+            fn unwrap_io(io: AudioIo) -> (Vec<AudioBufferRef<Self>>, Vec<AudioBufferMut<Self>>) {
+                match io {
+                    AudioIo::$variant(audio_in, audio_out) => (audio_in, audio_out),
+                    _ => panic!("样本格式不一致"),
+                }
+            }
+        }
+    };
+}
+
+impl_sample!(f32, F32);
+impl_sample!(f64, F64);
+
+/// 按样本格式选出具体的样本类型, 在 body 中以 S 指代
+macro_rules! with_sample {
+    ($format:expr, $S:ident => $body:expr) => {
+        match $format {
+            $crate::SampleFormat::F32 => {
+                type $S = f32;
+                $body
+            }
+            $crate::SampleFormat::F64 => {
+                type $S = f64;
+                $body
+            }
+        }
+    };
+}
+pub(crate) use with_sample;
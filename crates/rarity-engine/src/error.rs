@@ -8,26 +8,50 @@ pub enum GraphError {
     RepeatedName(String),
     #[error("node name {0} not found")]
     UnknownName(String),
+    #[error("node name {0} is invalid, ':' is used to separate ports")]
+    InvalidName(String),
     #[error("link error")]
     LinkError(#[from] LinkError),
     #[error("tap error")]
     TapError(#[from] TapError),
+    #[error("node {0} has audio inputs or outputs but no channels")]
+    NoChannels(String),
+    #[error("feedback delay must be at least one frame")]
+    InvalidFeedbackDelay,
+    #[error("graph {0} has not been prepared, the sample rate is unknown")]
+    NotPrepared(String),
+    #[error("parameter {0} has an invalid range")]
+    InvalidParameter(String),
+    #[error("too many updates are waiting for the player, try again later")]
+    UpdatePending,
+    #[error("the player of this graph is gone")]
+    PlayerDisconnected,
 }
 
 #[derive(Error, Debug)]
 pub enum LinkError {
     #[error("node name {0} not found")]
     UnknownName(String),
+    #[error("port {0} not found")]
+    UnknownPort(String),
+    #[error("link {0} -> {1} not found")]
+    UnknownLink(String, String),
+    #[deprecated(note = "扇出是允许的, 不再返回; 重复的连接返回 DuplicateLink")]
     #[error("link source {0} already linked")]
     LinkedSource(String),
+    #[deprecated(note = "扇入是允许的, 不再返回; 重复的连接返回 DuplicateLink")]
     #[error("link target {0} already linked")]
     LinkedTarget(String),
+    #[error("link {0} -> {1} already exists")]
+    DuplicateLink(String, String),
     #[error("{0} is not a link source")]
     InvalidLinkSource(String),
     #[error("{0} is not a link target")]
     InvalidLinkTarget(String),
     #[error("{0} {1} are already tapped")]
     LinkIsTapped(String, String),
+    #[error("link would form a cycle {}", .0.join(" -> "))]
+    Cycle(Vec<String>),
 }
 
 #[derive(Error, Debug)]
@@ -42,6 +66,65 @@ pub enum TapError {
     TapIsLinked(String, String),
 }
 
+#[derive(Error, Debug)]
+pub enum ParameterError {
+    #[error("parameter {0} not found")]
+    UnknownParameter(String),
+    #[error("value {1} is out of range for parameter {0}")]
+    OutOfRange(String, f64),
+    #[error("message type does not match parameter {0}")]
+    TypeMismatch(String),
+    #[error("cannot parse {1:?} as a value of parameter {0}")]
+    InvalidText(String, String),
+}
+
+#[derive(Error, Debug)]
+pub enum PresetError {
+    #[error("invalid preset line {0}: {1:?}")]
+    InvalidLine(usize, String),
+}
+
+#[derive(Error, Debug)]
+pub enum DescriptionError {
+    #[error("node type {0} not found")]
+    UnknownType(String),
+    #[error("invalid arguments for node {0}: {1}")]
+    InvalidArgs(String, String),
+    #[error("node {0} was not created from a description")]
+    MissingSpec(String),
+    #[error("graph error")]
+    GraphError(#[from] GraphError),
+    #[error("link error")]
+    LinkError(#[from] LinkError),
+    #[error("parameter error")]
+    ParameterError(#[from] ParameterError),
+    #[error("json error")]
+    JsonError(#[from] serde_json::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum RegistryError {
+    #[error("node type {0} is already registered")]
+    RepeatedType(String),
+}
+
+#[derive(Error, Debug)]
+pub enum PluginError {
+    #[error("library error")]
+    LibraryError(#[from] libloading::Error),
+    #[error("plugin ABI version {0} is not supported")]
+    AbiVersion(u32),
+    #[error("invalid plugin descriptor: {0}")]
+    InvalidDescriptor(String),
+    #[error("registry error")]
+    RegistryError(#[from] RegistryError),
+}
+
 pub type GraphResult<T> = Result<T, GraphError>;
 pub type LinkResult<T> = Result<T, LinkError>;
 pub type TapResult<T> = Result<T, TapError>;
+pub type ParameterResult<T> = Result<T, ParameterError>;
+pub type PresetResult<T> = Result<T, PresetError>;
+pub type DescriptionResult<T> = Result<T, DescriptionError>;
+pub type RegistryResult<T> = Result<T, RegistryError>;
+pub type PluginResult<T> = Result<T, PluginError>;
@@ -0,0 +1,660 @@
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    ops::Range,
+};
+
+use crate::{port_index, RawDesc, SampleFormat, A_IN_NODE, A_OUT_NODE};
+
+use super::{topology::Topology, Link, Operation};
+
+/// 编译结果: 执行序列以及需要分配的缓冲
+pub(crate) struct Compiled {
+    pub sequences: Vec<Operation>,
+    /// 执行序列按节点划分的任务
+    pub tasks: Vec<Task>,
+    pub audio_buffers: Vec<BufferSpec>,
+    pub message_buffers: usize,
+    /// 需要保留历史内容的音频缓冲及其容纳的帧数
+    pub persistent: Vec<(usize, usize)>,
+    /// 图输出相对输入的延迟
+    pub latency: usize,
+}
+
+/// 执行序列中连续的一段操作, 以及必须在它之前完成的任务
+pub(crate) struct Task {
+    pub operations: Range<usize>,
+    pub deps: Vec<usize>,
+}
+
+/// 音频缓冲的声道数和样本格式
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub(crate) struct BufferSpec {
+    pub channels: usize,
+    pub format: SampleFormat,
+}
+
+/// 缓冲分配器, 释放的缓冲会被之后的节点复用
+///
+/// 只复用布局相同且使用者都是当前任务祖先的缓冲, 这样复用不会在并行的分支之间引入依赖.
+#[derive(Default)]
+struct Allocator {
+    free: Vec<usize>,
+    /// 每个缓冲的布局, 控制缓冲都为默认值
+    layouts: Vec<BufferSpec>,
+    persistent: Vec<(usize, usize)>,
+    /// 每个缓冲自上次分配以来被哪些任务使用过
+    users: Vec<BTreeSet<usize>>,
+    /// 当前任务以及它的所有祖先
+    scope: BTreeSet<usize>,
+}
+
+impl Allocator {
+    fn alloc(&mut self, layout: BufferSpec) -> usize {
+        let reusable = self
+            .free
+            .iter()
+            .rposition(|b| self.layouts[*b] == layout && self.users[*b].is_subset(&self.scope));
+        match reusable {
+            Some(i) => {
+                let buffer = self.free.remove(i);
+                self.users[buffer].clear();
+                buffer
+            }
+            None => {
+                self.layouts.push(layout);
+                self.users.push(BTreeSet::new());
+                self.layouts.len() - 1
+            }
+        }
+    }
+
+    /// 分配一个不会被复用, 能容纳 frames 帧的缓冲
+    ///
+    /// 总是新建缓冲: 持久缓冲每个块都会前移, 复用其它缓冲会让读写它的节点拿到不连续的块.
+    fn alloc_persistent(&mut self, layout: BufferSpec, frames: usize) -> usize {
+        self.layouts.push(layout);
+        self.users.push(BTreeSet::new());
+        let buffer = self.layouts.len() - 1;
+        self.persistent.push((buffer, frames));
+        buffer
+    }
+
+    fn release(&mut self, index: usize) {
+        debug_assert!(!self.free.contains(&index));
+        if !self.persistent.iter().any(|p| p.0 == index) {
+            self.free.push(index);
+        }
+    }
+
+    /// 进入新的任务, scope 包含任务本身和它的所有祖先
+    fn enter(&mut self, scope: BTreeSet<usize>) {
+        self.scope = scope;
+    }
+
+    /// 记录当前任务使用了 buffer
+    fn touch(&mut self, buffer: usize, task: usize) {
+        self.users[buffer].insert(task);
+    }
+}
+
+/// 某个节点输出所在的缓冲, 以及还有多少个下游节点要读取它
+struct Output {
+    buffer: usize,
+    readers: usize,
+}
+
+impl Output {
+    /// 读取一次, 最后一个读者读完后释放缓冲
+    fn consume(&mut self, allocator: &mut Allocator) {
+        self.readers -= 1;
+        if self.readers == 0 {
+            allocator.release(self.buffer);
+        }
+    }
+}
+
+/// 沿连接寻找一条从 from 到 to 的路径, 路径至少经过一个连接
+pub(crate) fn find_path(links: &[&Link], from: &str, to: &str) -> Option<Vec<String>> {
+    let mut parent = HashMap::<&str, &str>::new();
+    let mut queue = VecDeque::from([from]);
+    while let Some(curr) = queue.pop_front() {
+        for Link(a, b, ..) in links.iter().filter(|l| l.0 == curr) {
+            if parent.contains_key(b.as_str()) {
+                continue;
+            }
+            parent.insert(b, a);
+            if b == to {
+                let mut path = vec![to.to_string()];
+                let mut curr = to;
+                loop {
+                    curr = parent[curr];
+                    path.push(curr.to_string());
+                    if curr == from {
+                        break;
+                    }
+                }
+                path.reverse();
+                return Some(path);
+            }
+            queue.push_back(b);
+        }
+    }
+    None
+}
+
+/// 按拓扑序排列节点, 同一时刻可执行的节点按名字排序以保证结果确定
+///
+/// 存在环时返回环上的节点路径
+pub(crate) fn topo_sort(
+    node_descs: &HashMap<String, RawDesc>,
+    links: &[&Link],
+) -> Result<Vec<String>, Vec<String>> {
+    let mut in_degree = node_descs
+        .keys()
+        .map(|name| (name.as_str(), 0))
+        .collect::<HashMap<_, _>>();
+    let mut next = HashMap::<&str, Vec<&str>>::new();
+    for Link(from, to, ..) in links.iter().copied() {
+        if node_descs.contains_key(from) && node_descs.contains_key(to) {
+            *in_degree.get_mut(to.as_str()).unwrap() += 1;
+            next.entry(from).or_default().push(to);
+        }
+    }
+    let mut ready = in_degree
+        .iter()
+        .filter(|(_, d)| **d == 0)
+        .map(|(name, _)| *name)
+        .collect::<BTreeSet<_>>();
+    let mut order = Vec::with_capacity(node_descs.len());
+    while let Some(name) = ready.pop_first() {
+        order.push(name.to_string());
+        for to in next.get(name).into_iter().flatten() {
+            let d = in_degree.get_mut(to).unwrap();
+            *d -= 1;
+            if *d == 0 {
+                ready.insert(to);
+            }
+        }
+    }
+    if order.len() == node_descs.len() {
+        return Ok(order);
+    }
+    let mut rest = in_degree
+        .into_iter()
+        .filter(|(_, d)| *d > 0)
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+    rest.sort();
+    Err(rest
+        .into_iter()
+        .find_map(|name| find_path(links, name, name))
+        .unwrap_or_default())
+}
+
+/// 将节点和连接编译为执行序列, 连接需事先经过检查
+///
+/// 音频连接接入目标节点指定的输入, 同一输入的多个来源会先混合到临时缓冲,
+/// 没有来源的输入读取静音缓冲. 控制连接读取来源节点的第一个控制输出.
+/// 反馈连接的来源输出会复制到独占的历史缓冲, 目标在处理前取出上一个块的内容.
+/// 节点的所有输入以及图的输出按来源的延迟对齐, 延迟较小的来源先经过延迟线.
+/// 样本格式或声道数与目标不同的来源在对齐之后先转换格式, 再上混或下混,
+/// 图的输入输出缓冲使用 io 的布局, 每个块不超过 max_block 帧.
+/// 被监听的节点处理完后, 把第一个音频输出和第一个控制输出送入监听通道.
+pub(crate) fn compile(
+    order: &[String],
+    node_descs: &HashMap<String, RawDesc>,
+    topology: &Topology,
+    io: BufferSpec,
+    max_block: usize,
+) -> Compiled {
+    let audio_links = &topology.audio_links;
+    let message_links = &topology.message_links;
+    let feedback_links = &topology.feedback_links;
+
+    let mut sequences = vec![];
+    let mut audio = Allocator::default();
+    let mut message = Allocator::default();
+    let mut audio_outputs = HashMap::<(&str, usize), Output>::new();
+    let mut message_outputs = HashMap::<&str, Output>::new();
+
+    let out_port = |l: &Link| match l.0 == A_IN_NODE {
+        true => 0,
+        false => port_index(&node_descs[&l.0].audio_out, l.2.as_deref()).unwrap(),
+    };
+    let in_port = |l: &Link| match l.1 == A_OUT_NODE {
+        true => 0,
+        false => port_index(&node_descs[&l.1].audio_in, l.3.as_deref()).unwrap(),
+    };
+
+    let mut tasks = vec![];
+    let mut task_of = HashMap::<&str, usize>::new();
+    let mut ancestors = HashMap::<&str, BTreeSet<usize>>::new();
+    audio.enter(BTreeSet::from([0]));
+    message.enter(BTreeSet::from([0]));
+
+    let mut latencies = HashMap::from([(A_IN_NODE, 0)]);
+    let mut history = HashMap::<(&str, usize), usize>::new();
+    for l in feedback_links {
+        history
+            .entry((&l.0, out_port(l)))
+            .or_insert_with(|| audio.alloc_persistent(layout(&node_descs[&l.0]), max_block * 2));
+    }
+
+    let readers = audio_links.iter().filter(|l| l.0 == A_IN_NODE).count();
+    let input_tap = topology.taps.get(A_IN_NODE);
+    if readers > 0 || input_tap.is_some() {
+        let buffer = audio.alloc(io);
+        sequences.push(Operation::AudioFromInput(vec![buffer]));
+        if let Some(tap) = input_tap {
+            sequences.push(Operation::Tap(tap.clone(), Some(buffer), None));
+        }
+        if readers > 0 {
+            audio_outputs.insert((A_IN_NODE, 0), Output { buffer, readers });
+        } else {
+            audio.release(buffer);
+        }
+    }
+
+    let readers = message_links.iter().filter(|l| l.0 == A_IN_NODE).count();
+    if readers > 0 {
+        let buffer = message.alloc(BufferSpec::default());
+        sequences.push(Operation::MessageFromInput(vec![(
+            buffer,
+            A_IN_NODE.to_string(),
+        )]));
+        message_outputs.insert(A_IN_NODE, Output { buffer, readers });
+    }
+
+    cut(&mut tasks, &sequences, &mut audio, &mut message);
+    for name in order {
+        let desc = &node_descs[name];
+        let node_layout = layout(desc);
+        let task = tasks.len();
+        let mut scope = BTreeSet::from([0, task]);
+        for l in audio_links.iter().chain(message_links) {
+            if &l.1 == name && l.0 != A_IN_NODE {
+                scope.insert(task_of[l.0.as_str()]);
+                scope.extend(&ancestors[l.0.as_str()]);
+            }
+        }
+        task_of.insert(name, task);
+        audio.enter(scope.clone());
+        message.enter(scope.clone());
+        scope.remove(&task);
+        ancestors.insert(name, scope);
+        let mut zeros = vec![];
+        let mut temps = vec![];
+
+        let sources = audio_links
+            .iter()
+            .filter(|l| &l.1 == name)
+            .map(|l| ((l.0.as_str(), out_port(l)), in_port(l)))
+            .collect::<Vec<_>>();
+        let latency = sources
+            .iter()
+            .map(|((from, _), _)| latencies[from])
+            .max()
+            .unwrap_or(0);
+        latencies.insert(name, latency + desc.latency);
+        let mut silence = None;
+        let mut audio_in = Vec::with_capacity(desc.audio_in.len());
+        for port in 0..desc.audio_in.len() {
+            let mut inputs = vec![];
+            for (from, _) in sources.iter().filter(|(_, to)| *to == port) {
+                let buffer = audio_outputs[from].buffer;
+                let frames = latency - latencies[from.0];
+                let buffer = delay(
+                    &mut sequences,
+                    &mut audio,
+                    &mut temps,
+                    buffer,
+                    frames,
+                    max_block,
+                );
+                inputs.push(adapt(
+                    &mut sequences,
+                    &mut audio,
+                    &mut temps,
+                    buffer,
+                    node_layout,
+                ));
+            }
+            for l in feedback_links
+                .iter()
+                .filter(|l| &l.1 == name && in_port(l) == port)
+            {
+                let source = history[&(l.0.as_str(), out_port(l))];
+                let tmp = audio.alloc(audio.layouts[source]);
+                temps.push(tmp);
+                sequences.push(Operation::AudioFromPrevious(source, tmp));
+                inputs.push(adapt(
+                    &mut sequences,
+                    &mut audio,
+                    &mut temps,
+                    tmp,
+                    node_layout,
+                ));
+            }
+            match inputs.as_slice() {
+                [] => {
+                    let silence = *silence.get_or_insert_with(|| {
+                        let silence = audio.alloc(node_layout);
+                        temps.push(silence);
+                        zeros.push(silence);
+                        silence
+                    });
+                    audio_in.push(silence);
+                }
+                [buffer] => audio_in.push(*buffer),
+                [first, rest @ ..] => {
+                    let tmp = audio.alloc(node_layout);
+                    temps.push(tmp);
+                    sequences.push(Operation::AudioClone(*first, vec![tmp]));
+                    sequences.push(Operation::AudioMerge(tmp, rest.to_vec()));
+                    audio_in.push(tmp);
+                }
+            }
+        }
+
+        let audio_out = (0..desc.audio_out.len())
+            .map(|_| audio.alloc(node_layout))
+            .collect::<Vec<_>>();
+        zeros.extend(&audio_out);
+        if !zeros.is_empty() {
+            sequences.push(Operation::AudioZeros(zeros));
+        }
+
+        let message_in = message.alloc(BufferSpec::default());
+        sequences.push(Operation::MessageFromInput(vec![(
+            message_in,
+            name.clone(),
+        )]));
+        let message_sources = message_links
+            .iter()
+            .filter(|l| &l.1 == name)
+            .map(|l| message_outputs[l.0.as_str()].buffer)
+            .collect::<Vec<_>>();
+        if !message_sources.is_empty() {
+            sequences.push(Operation::MessageMerge(message_in, message_sources));
+        }
+        let message_out = (0..desc.message_out)
+            .map(|_| message.alloc(BufferSpec::default()))
+            .collect::<Vec<_>>();
+        if !message_out.is_empty() {
+            sequences.push(Operation::MessageZeros(message_out.clone()));
+        }
+
+        sequences.push(Operation::Process(
+            name.clone(),
+            audio_in,
+            audio_out.clone(),
+            message_in,
+            message_out.clone(),
+        ));
+        // 历史缓冲是环形的, 节点先写入连续的缓冲再复制过去
+        for (i, buffer) in audio_out.iter().enumerate() {
+            if let Some(history) = history.get(&(name.as_str(), i)) {
+                sequences.push(Operation::AudioClone(*buffer, vec![*history]));
+            }
+        }
+        if let Some(tap) = topology.taps.get(name) {
+            sequences.push(Operation::Tap(
+                tap.clone(),
+                audio_out.first().copied(),
+                message_out.first().copied(),
+            ));
+        }
+
+        for tmp in temps {
+            audio.release(tmp);
+        }
+        message.release(message_in);
+        for (from, _) in sources {
+            audio_outputs.get_mut(&from).unwrap().consume(&mut audio);
+        }
+        for l in message_links.iter().filter(|l| &l.1 == name) {
+            message_outputs
+                .get_mut(l.0.as_str())
+                .unwrap()
+                .consume(&mut message);
+        }
+
+        for (i, buffer) in audio_out.into_iter().enumerate() {
+            let readers = audio_links
+                .iter()
+                .filter(|l| &l.0 == name && out_port(l) == i)
+                .count();
+            if readers == 0 {
+                audio.release(buffer);
+            } else {
+                audio_outputs.insert((name, i), Output { buffer, readers });
+            }
+        }
+        for (i, buffer) in message_out.into_iter().enumerate() {
+            let readers = if i == 0 {
+                message_links.iter().filter(|l| &l.0 == name).count()
+            } else {
+                0
+            };
+            if readers == 0 {
+                message.release(buffer);
+            } else {
+                message_outputs.insert(name, Output { buffer, readers });
+            }
+        }
+        cut(&mut tasks, &sequences, &mut audio, &mut message);
+    }
+
+    audio.enter((0..=tasks.len()).collect());
+    message.enter((0..=tasks.len()).collect());
+    let sources = audio_links
+        .iter()
+        .filter(|l| l.1 == A_OUT_NODE)
+        .map(|l| (l.0.as_str(), out_port(l)))
+        .collect::<Vec<_>>();
+    let latency = sources
+        .iter()
+        .map(|from| latencies[from.0])
+        .max()
+        .unwrap_or(0);
+    let mut temps = vec![];
+    let to_output = sources
+        .iter()
+        .map(|from| {
+            let buffer = audio_outputs[from].buffer;
+            let frames = latency - latencies[from.0];
+            let buffer = delay(
+                &mut sequences,
+                &mut audio,
+                &mut temps,
+                buffer,
+                frames,
+                max_block,
+            );
+            adapt(&mut sequences, &mut audio, &mut temps, buffer, io)
+        })
+        .collect::<Vec<_>>();
+    if let Some(tap) = topology.taps.get(A_OUT_NODE) {
+        let tmp = audio.alloc(io);
+        sequences.push(Operation::AudioZeros(vec![tmp]));
+        sequences.push(Operation::AudioMerge(tmp, to_output.clone()));
+        sequences.push(Operation::Tap(tap.clone(), Some(tmp), None));
+    }
+    if !to_output.is_empty() {
+        sequences.push(Operation::AudioToOutput(to_output));
+    }
+    cut(&mut tasks, &sequences, &mut audio, &mut message);
+
+    Compiled {
+        tasks: dependencies(&sequences, tasks),
+        sequences,
+        audio_buffers: audio.layouts,
+        message_buffers: message.layouts.len(),
+        persistent: audio.persistent,
+        latency,
+    }
+}
+
+/// 把 buffer 延迟 frames 帧后放入新的临时缓冲, 不需要延迟时直接返回 buffer
+fn delay(
+    sequences: &mut Vec<Operation>,
+    audio: &mut Allocator,
+    temps: &mut Vec<usize>,
+    buffer: usize,
+    frames: usize,
+    max_block: usize,
+) -> usize {
+    if frames == 0 {
+        return buffer;
+    }
+    let layout = audio.layouts[buffer];
+    let line = audio.alloc_persistent(layout, frames + max_block);
+    let tmp = audio.alloc(layout);
+    temps.push(tmp);
+    sequences.push(Operation::AudioDelay(buffer, line, tmp, frames));
+    tmp
+}
+
+/// 把 buffer 转换为 layout 的样本格式并上混或下混为 layout 的声道数, 放入新的临时缓冲,
+/// 布局相同时直接返回 buffer
+fn adapt(
+    sequences: &mut Vec<Operation>,
+    audio: &mut Allocator,
+    temps: &mut Vec<usize>,
+    buffer: usize,
+    layout: BufferSpec,
+) -> usize {
+    let mut buffer = buffer;
+    let source = audio.layouts[buffer];
+    if source.format != layout.format {
+        let tmp = audio.alloc(BufferSpec {
+            channels: source.channels,
+            format: layout.format,
+        });
+        temps.push(tmp);
+        sequences.push(Operation::AudioConvert(buffer, tmp));
+        buffer = tmp;
+    }
+    if source.channels != layout.channels {
+        let tmp = audio.alloc(layout);
+        temps.push(tmp);
+        sequences.push(Operation::AudioMix(buffer, tmp));
+        buffer = tmp;
+    }
+    buffer
+}
+
+fn layout(desc: &RawDesc) -> BufferSpec {
+    BufferSpec {
+        channels: desc.channels,
+        format: desc.format,
+    }
+}
+
+/// 把上一个任务结束之后的操作划为一个新任务, 并记录任务用到的缓冲
+fn cut(
+    tasks: &mut Vec<Range<usize>>,
+    sequences: &[Operation],
+    audio: &mut Allocator,
+    message: &mut Allocator,
+) {
+    let start = tasks.last().map_or(0, |t| t.end);
+    for op in &sequences[start..] {
+        let [audio_read, audio_write, message_read, message_write] = accesses(op);
+        for buffer in audio_read.into_iter().chain(audio_write) {
+            audio.touch(buffer, tasks.len());
+        }
+        for buffer in message_read.into_iter().chain(message_write) {
+            message.touch(buffer, tasks.len());
+        }
+    }
+    tasks.push(start..sequences.len());
+}
+
+/// 操作读写的缓冲: 音频读, 音频写, 控制读, 控制写
+fn accesses(op: &Operation) -> [Vec<usize>; 4] {
+    match op {
+        Operation::AudioZeros(tgt) | Operation::AudioFromInput(tgt) => {
+            [vec![], tgt.clone(), vec![], vec![]]
+        }
+        Operation::AudioToOutput(src) => [src.clone(), vec![], vec![], vec![]],
+        Operation::AudioClone(src, tgt) => [vec![*src], tgt.clone(), vec![], vec![]],
+        Operation::AudioMerge(tgt, src) => [src.clone(), vec![*tgt], vec![], vec![]],
+        Operation::AudioFromPrevious(src, tgt)
+        | Operation::AudioMix(src, tgt)
+        | Operation::AudioConvert(src, tgt) => [vec![*src], vec![*tgt], vec![], vec![]],
+        Operation::AudioDelay(src, line, tgt, _) => [vec![*src], vec![*line, *tgt], vec![], vec![]],
+        Operation::MessageZeros(tgt) => [vec![], vec![], vec![], tgt.clone()],
+        Operation::MessageFromInput(tgt) => {
+            [vec![], vec![], vec![], tgt.iter().map(|t| t.0).collect()]
+        }
+        Operation::MessageClone(src, tgt) => [vec![], vec![], vec![*src], tgt.clone()],
+        Operation::MessageMerge(tgt, src) => [vec![], vec![], src.clone(), vec![*tgt]],
+        Operation::Process(_, audio_in, audio_out, message_in, message_out) => [
+            audio_in.clone(),
+            audio_out.clone(),
+            vec![*message_in],
+            message_out.clone(),
+        ],
+        Operation::Tap(_, audio, message) => [
+            audio.iter().copied().collect(),
+            vec![],
+            message.iter().copied().collect(),
+            vec![],
+        ],
+    }
+}
+
+/// 每个缓冲最后一次被写入的任务, 以及之后读取过它的任务
+#[derive(Default)]
+struct Hazards {
+    writer: HashMap<usize, usize>,
+    readers: HashMap<usize, Vec<usize>>,
+}
+
+impl Hazards {
+    fn read(&mut self, buffer: usize, task: usize, deps: &mut BTreeSet<usize>) {
+        deps.extend(self.writer.get(&buffer));
+        self.readers.entry(buffer).or_default().push(task);
+    }
+
+    fn write(&mut self, buffer: usize, task: usize, deps: &mut BTreeSet<usize>) {
+        deps.extend(self.writer.insert(buffer, task));
+        deps.extend(self.readers.remove(&buffer).into_iter().flatten());
+    }
+}
+
+/// 根据缓冲的读写顺序求出任务之间的依赖, 按依赖并行执行与按顺序执行结果相同
+///
+/// 缓冲会被不同节点复用, 因此除了读写同一份数据, 复用前后的任务之间也会产生依赖.
+fn dependencies(sequences: &[Operation], tasks: Vec<Range<usize>>) -> Vec<Task> {
+    let mut audio = Hazards::default();
+    let mut message = Hazards::default();
+    tasks
+        .into_iter()
+        .enumerate()
+        .map(|(task, operations)| {
+            let mut deps = BTreeSet::new();
+            for op in &sequences[operations.clone()] {
+                let [audio_read, audio_write, message_read, message_write] = accesses(op);
+                for buffer in audio_read {
+                    audio.read(buffer, task, &mut deps);
+                }
+                for buffer in message_read {
+                    message.read(buffer, task, &mut deps);
+                }
+                for buffer in audio_write {
+                    audio.write(buffer, task, &mut deps);
+                }
+                for buffer in message_write {
+                    message.write(buffer, task, &mut deps);
+                }
+            }
+            deps.remove(&task);
+            Task {
+                operations,
+                deps: deps.into_iter().collect(),
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,260 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    path, BoxedNode, DescriptionError, DescriptionResult, EnumMessage, FloatMessage, Message,
+    MessageValue, NodeSpec, NodeState, ParaRange, ParameterError, RawDesc, SampleFormat,
+};
+
+use super::{topology::endpoint, FeedbackDelay, Graph, Link};
+
+/// 子图节点的类型, 构造参数为子图的描述
+pub const GRAPH_TYPE: &str = "rarity.graph";
+
+/// 图的描述, 以 JSON 保存, 修改后不需要重新编译就能加载
+///
+/// 连接的两端写作 `节点名` 或 `节点名:端口`, 与 [`Graph::add_audio_link`] 相同.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct GraphDescription {
+    #[serde(default)]
+    pub name: String,
+    /// 图的输入输出的声道数, 默认为双声道
+    #[serde(default = "default_channels")]
+    pub channels: usize,
+    #[serde(default)]
+    pub sample_format: SampleFormat,
+    /// 反馈连接的延迟帧数, 省略时延迟一个完整的处理块
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub feedback_delay: Option<usize>,
+    pub nodes: Vec<NodeDescription>,
+    #[serde(default)]
+    pub audio_links: Vec<(String, String)>,
+    #[serde(default)]
+    pub message_links: Vec<(String, String)>,
+    #[serde(default)]
+    pub feedback_links: Vec<(String, String)>,
+}
+
+/// 节点的描述: 类型, 名字, 构造参数和参数的初始值
+///
+/// 参数以相对节点的路径为键, 如 `Volume`, 子图中的参数为 `vol/gain`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NodeDescription {
+    #[serde(rename = "type")]
+    pub type_id: String,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub args: Value,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub parameters: BTreeMap<String, ParameterValue>,
+}
+
+/// 参数的初始值, 数字为实际值或选项的下标, 文本按参数的单位或选项标签解析
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ParameterValue {
+    Number(f64),
+    Text(String),
+}
+
+fn default_channels() -> usize {
+    2
+}
+
+impl GraphDescription {
+    pub fn from_json(text: &str) -> DescriptionResult<Self> {
+        Ok(serde_json::from_str(text)?)
+    }
+
+    pub fn to_json(&self) -> DescriptionResult<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+impl Graph {
+    /// 按描述创建图并 prepare, 然后设置参数的初始值
+    ///
+    /// factory 按节点的描述创建节点, 类型未知或参数不合法时返回错误; 类型为 [`GRAPH_TYPE`] 的节点
+    /// 由构造参数中的描述创建子图, 不经过 factory.
+    pub fn from_description(
+        desc: &GraphDescription,
+        sample_rate: f64,
+        max_block_size: usize,
+        factory: &mut dyn FnMut(&NodeDescription) -> DescriptionResult<BoxedNode>,
+    ) -> DescriptionResult<Graph> {
+        let invalid =
+            |reason: &str| DescriptionError::InvalidArgs(desc.name.clone(), reason.to_string());
+        if desc.channels == 0 {
+            return Err(invalid("channels must be at least 1"));
+        }
+        if desc.feedback_delay == Some(0) {
+            return Err(invalid("feedback_delay must be at least 1"));
+        }
+        let mut graph = Graph::new(&desc.name);
+        graph.set_channels(desc.channels);
+        graph.set_sample_format(desc.sample_format);
+        if let Some(frames) = desc.feedback_delay {
+            graph.set_feedback_delay(FeedbackDelay::Frames(frames))?;
+        }
+        for node in &desc.nodes {
+            let boxed = if node.type_id == GRAPH_TYPE {
+                let mut sub = GraphDescription::deserialize(&node.args)
+                    .map_err(|e| DescriptionError::InvalidArgs(node.name.clone(), e.to_string()))?;
+                sub.name = node.name.clone();
+                let sub = Graph::from_description(&sub, sample_rate, max_block_size, factory)?;
+                BoxedNode::audio_effect(sub)
+            } else {
+                factory(node)?
+            };
+            let spec = NodeSpec {
+                type_id: node.type_id.clone(),
+                args: node.args.clone(),
+            };
+            graph.add_node(boxed.with_spec(spec))?;
+        }
+        for (from, to) in &desc.audio_links {
+            graph.add_audio_link(from, to)?;
+        }
+        for (from, to) in &desc.message_links {
+            graph.add_message_link(from, to)?;
+        }
+        for (from, to) in &desc.feedback_links {
+            graph.add_audio_feedback_link(from, to)?;
+        }
+        graph.prepare(sample_rate, max_block_size)?;
+
+        let mut preset = NodeState::new();
+        for node in &desc.nodes {
+            for (key, value) in &node.parameters {
+                preset.0.push(graph.initial_value(&node.name, key, value)?);
+            }
+        }
+        graph.load_preset(&preset)?;
+        Ok(graph)
+    }
+
+    /// 当前的结构和参数取值的描述, 所有节点都需要是按描述创建的
+    pub fn to_description(&self) -> DescriptionResult<GraphDescription> {
+        let mut names = self.nodes.keys().collect::<Vec<_>>();
+        names.sort();
+        let preset = self.save_preset();
+        let mut nodes = vec![];
+        for name in names {
+            let spec = self.nodes[name]
+                .spec
+                .as_ref()
+                .ok_or_else(|| DescriptionError::MissingSpec(name.clone()))?;
+            let parameters = preset
+                .node(name)
+                .0
+                .into_iter()
+                .filter_map(|m| self.describe_value(name, m))
+                .collect();
+            nodes.push(NodeDescription {
+                type_id: spec.type_id.clone(),
+                name: name.clone(),
+                args: spec.args.clone(),
+                parameters,
+            });
+        }
+        // 解析为序号的端口尽量写回名字
+        let port = |node: &String, port: &Option<String>, ports: fn(&RawDesc) -> &[String]| {
+            let name = port.as_ref().map(|port| {
+                let desc = self.node_descs.get(node);
+                match (desc, port.parse::<usize>()) {
+                    (Some(desc), Ok(index)) => match ports(desc).get(index) {
+                        Some(name) if !name.is_empty() => name.clone(),
+                        _ => port.clone(),
+                    },
+                    _ => port.clone(),
+                }
+            });
+            endpoint(node, &name)
+        };
+        let links = |links: &[Link]| {
+            links
+                .iter()
+                .map(|Link(from, to, from_port, to_port)| {
+                    (
+                        port(from, from_port, |d| &d.audio_out),
+                        port(to, to_port, |d| &d.audio_in),
+                    )
+                })
+                .collect()
+        };
+        Ok(GraphDescription {
+            name: self.name.clone(),
+            channels: self.channels,
+            sample_format: self.sample_format,
+            feedback_delay: match self.feedback_delay {
+                FeedbackDelay::Block => None,
+                FeedbackDelay::Frames(frames) => Some(frames),
+            },
+            nodes,
+            audio_links: links(&self.topology.audio_links),
+            message_links: links(&self.topology.message_links),
+            feedback_links: links(&self.topology.feedback_links),
+        })
+    }
+
+    /// 节点 node 的参数 key 的初始值对应的控制信息, 取值需要在范围内
+    fn initial_value(
+        &self,
+        node: &str,
+        key: &str,
+        value: &ParameterValue,
+    ) -> DescriptionResult<Message> {
+        let mut addr = key.split('/').map(str::to_string).collect::<Vec<_>>();
+        let name = addr.pop().unwrap_or_default();
+        addr.reverse();
+        addr.push(node.to_string());
+        let message = match value {
+            ParameterValue::Text(text) => self.parameters.parse(&addr, &name, text)?,
+            ParameterValue::Number(value) => {
+                let value = match self.parameters.get(&addr, &name) {
+                    Some(ParaRange::Float(_)) => MessageValue::Float(FloatMessage {
+                        name,
+                        value: *value,
+                    }),
+                    // 枚举参数的数值是选项下标, 需要是非负整数
+                    Some(ParaRange::Enum(_)) if *value < 0.0 || value.fract() != 0.0 => {
+                        return Err(ParameterError::OutOfRange(path(&addr, &name), *value).into())
+                    }
+                    Some(ParaRange::Enum(_)) => MessageValue::Enum(EnumMessage {
+                        name,
+                        value: *value as usize,
+                    }),
+                    None => return Err(ParameterError::UnknownParameter(path(&addr, &name)).into()),
+                };
+                Message { addr, value }
+            }
+        };
+        self.parameters.check(&message)?;
+        Ok(message)
+    }
+
+    /// 节点状态中的一条控制信息在描述中的键和取值, 枚举参数写作选项的标签
+    fn describe_value(&self, node: &str, message: Message) -> Option<(String, ParameterValue)> {
+        let (name, value) = match &message.value {
+            MessageValue::Float(msg) => (&msg.name, ParameterValue::Number(msg.value)),
+            MessageValue::Enum(msg) => {
+                let mut addr = message.addr.clone();
+                addr.push(node.to_string());
+                let label = match self.parameters.get(&addr, &msg.name) {
+                    Some(ParaRange::Enum(range)) => range.label(msg.value),
+                    _ => None,
+                };
+                let value = match label {
+                    Some(label) => ParameterValue::Text(label.to_string()),
+                    None => ParameterValue::Number(msg.value as f64),
+                };
+                (&msg.name, value)
+            }
+            MessageValue::Midi(_) => return None,
+        };
+        let key = path(&message.addr, name);
+        Some((key[1..].to_string(), value))
+    }
+}
@@ -0,0 +1,403 @@
+use std::{
+    collections::HashMap,
+    sync::mpsc::{Receiver, SyncSender, TrySendError},
+};
+
+use crate::{
+    AudioEffectNode, AudioSourceNode, BoxedNode, GraphError, GraphResult, LinkResult,
+    MidiEffectNode, MultiAudioNode, NodeState, RawDesc, RawNode, Sample, SampleFormat, TapReceiver,
+    TapResult,
+};
+
+use super::{topology::Topology, FeedbackDelay, Graph, Link, ParameterTree};
+
+/// 同时在途(已提交但尚未被播放器取走或回收)的更新数量上限
+pub(crate) const PENDING_UPDATES: usize = 4;
+
+/// 在非音频线程编辑正在播放的图
+///
+/// 编辑器持有图的拓扑副本, 新加入的节点在编辑器所在线程 prepare.
+/// [`GraphEditor::commit`] 在当前线程编译出完整的新图并交给播放器,
+/// 播放器在音频线程只做节点的转移和交换, 不加锁也不分配内存;
+/// 被换下的旧图会送回编辑器, 在编辑器所在线程释放.
+pub struct GraphEditor {
+    name: String,
+    sample_rate: f64,
+    feedback_delay: FeedbackDelay,
+    max_block_size: usize,
+    channels: usize,
+    sample_format: SampleFormat,
+    topology: Topology,
+    node_descs: HashMap<String, RawDesc>,
+    pending: HashMap<String, RawNode>,
+    updates: SyncSender<Box<Graph>>,
+    garbage: Receiver<Box<Graph>>,
+    in_flight: usize,
+}
+
+impl GraphEditor {
+    pub(crate) fn new(
+        graph: &Graph,
+        updates: SyncSender<Box<Graph>>,
+        garbage: Receiver<Box<Graph>>,
+    ) -> Self {
+        Self {
+            name: graph.name.clone(),
+            sample_rate: graph.sample_rate,
+            feedback_delay: graph.feedback_delay,
+            max_block_size: graph.max_block_size,
+            channels: graph.channels,
+            sample_format: graph.sample_format,
+            topology: graph.topology.clone(),
+            node_descs: graph.node_descs.clone(),
+            pending: HashMap::default(),
+            updates,
+            garbage,
+            in_flight: 0,
+        }
+    }
+
+    pub fn add_audio_source<S: Sample, T: AudioSourceNode<S>>(
+        &mut self,
+        node: T,
+    ) -> GraphResult<()> {
+        self.add_node(BoxedNode::audio_source(node))
+    }
+
+    pub fn add_audio_effect<S: Sample, T: AudioEffectNode<S>>(
+        &mut self,
+        node: T,
+    ) -> GraphResult<()> {
+        self.add_node(BoxedNode::audio_effect(node))
+    }
+
+    pub fn add_midi_effect<T: MidiEffectNode>(&mut self, node: T) -> GraphResult<()> {
+        self.add_node(BoxedNode::midi_effect(node))
+    }
+
+    pub fn add_multi_audio<S: Sample, T: MultiAudioNode<S>>(&mut self, node: T) -> GraphResult<()> {
+        self.add_node(BoxedNode::multi_audio(node))
+    }
+
+    pub fn add_node(&mut self, node: BoxedNode) -> GraphResult<()> {
+        self.topology.add_node(&node.name(), *node.node_type())?;
+        self.insert_node(node.0);
+        Ok(())
+    }
+
+    /// 把 sub 作为子图加入, 加入前先编译子图, 子图有误时返回错误
+    pub fn add_subgraph(&mut self, mut sub: Graph) -> GraphResult<()> {
+        sub.prepare(self.sample_rate, sub.max_block_size)?;
+        self.add_audio_effect(sub)
+    }
+
+    fn insert_node(&mut self, node: RawNode) {
+        let name = node.name();
+        self.node_descs
+            .insert(name.clone(), node.prepare(self.sample_rate));
+        self.pending.insert(name, node);
+    }
+
+    /// 移除节点以及与它相关的所有连接, 节点会在提交后随旧图一起被回收
+    pub fn remove_node(&mut self, name: &str) -> GraphResult<()> {
+        self.topology.remove_node(name)?;
+        self.node_descs.remove(name);
+        self.pending.remove(name);
+        Ok(())
+    }
+
+    /// 用新节点替换名为 name 的节点, 原有的连接会转移到新节点上
+    pub fn replace_node(&mut self, name: &str, node: BoxedNode) -> GraphResult<()> {
+        self.topology
+            .replace_node(name, &node.name(), *node.node_type())?;
+        self.node_descs.remove(name);
+        self.pending.remove(name);
+        self.insert_node(node.0);
+        Ok(())
+    }
+
+    pub fn add_audio_link(&mut self, from: &str, to: &str) -> LinkResult<()> {
+        self.topology
+            .add_audio_link(Link::parse(from, to), &self.node_descs)
+    }
+
+    pub fn add_message_link(&mut self, from: &str, to: &str) -> LinkResult<()> {
+        self.topology.add_message_link(from, to)
+    }
+
+    pub fn remove_audio_link(&mut self, from: &str, to: &str) -> LinkResult<()> {
+        self.topology.remove_audio_link(from, to, &self.node_descs)
+    }
+
+    pub fn remove_message_link(&mut self, from: &str, to: &str) -> LinkResult<()> {
+        self.topology.remove_message_link(from, to)
+    }
+
+    pub fn add_audio_feedback_link(&mut self, from: &str, to: &str) -> LinkResult<()> {
+        self.topology
+            .add_audio_feedback_link(Link::parse(from, to), &self.node_descs)
+    }
+
+    pub fn remove_audio_feedback_link(&mut self, from: &str, to: &str) -> LinkResult<()> {
+        self.topology
+            .remove_audio_feedback_link(from, to, &self.node_descs)
+    }
+
+    pub fn add_tap(&mut self, name: &str) -> TapResult<TapReceiver> {
+        self.topology.add_tap(name)
+    }
+
+    /// 当前拓扑中所有节点声明的参数, 可以在发送控制信息前检查
+    pub fn parameters(&self) -> ParameterTree {
+        ParameterTree::new(&self.node_descs)
+    }
+
+    pub fn remove_tap(&mut self, name: &str) -> TapResult<()> {
+        self.topology.remove_tap(name)
+    }
+
+    pub fn set_feedback_delay(&mut self, delay: FeedbackDelay) -> GraphResult<()> {
+        delay.check()?;
+        self.feedback_delay = delay;
+        Ok(())
+    }
+
+    /// 编译当前的拓扑并交给播放器, 播放器会在下一次处理开始时换上新图
+    ///
+    /// 在途的更新过多时返回 [`GraphError::UpdatePending`], 稍后重试即可.
+    pub fn commit(&mut self) -> GraphResult<()> {
+        self.commit_with(None)
+    }
+
+    /// 提交当前的拓扑, 并在播放器换上新图后的第一个块开头把预设发给各个节点
+    ///
+    /// 预设含有图中不存在的节点时不做任何改变.
+    pub fn load_preset(&mut self, preset: &NodeState) -> GraphResult<()> {
+        let unknown = preset
+            .0
+            .iter()
+            .filter_map(|m| m.addr.last())
+            .find(|name| !self.topology.node_types.contains_key(*name));
+        if let Some(name) = unknown {
+            return Err(GraphError::UnknownName(name.clone()));
+        }
+        self.commit_with(Some(preset))
+    }
+
+    fn commit_with(&mut self, preset: Option<&NodeState>) -> GraphResult<()> {
+        self.collect_garbage();
+        if self.in_flight >= PENDING_UPDATES {
+            return Err(GraphError::UpdatePending);
+        }
+        let mut graph = Graph::new(&self.name);
+        graph.sample_rate = self.sample_rate;
+        graph.feedback_delay = self.feedback_delay;
+        graph.max_block_size = self.max_block_size;
+        graph.channels = self.channels;
+        graph.sample_format = self.sample_format;
+        graph.topology = self.topology.clone();
+        graph.node_descs = self.node_descs.clone();
+        graph.build()?;
+        graph.nodes = HashMap::with_capacity(self.topology.node_types.len());
+        graph.nodes.extend(self.pending.drain());
+        if let Some(preset) = preset {
+            graph.queue_preset(preset);
+        }
+        match self.updates.try_send(Box::new(graph)) {
+            Ok(()) => {
+                self.in_flight += 1;
+                Ok(())
+            }
+            Err(TrySendError::Full(mut graph)) => {
+                self.pending.extend(graph.nodes.drain());
+                Err(GraphError::UpdatePending)
+            }
+            Err(TrySendError::Disconnected(mut graph)) => {
+                self.pending.extend(graph.nodes.drain());
+                Err(GraphError::PlayerDisconnected)
+            }
+        }
+    }
+
+    /// 已提交但播放器还没有换上的更新数量
+    pub fn pending_updates(&mut self) -> usize {
+        self.collect_garbage();
+        self.in_flight
+    }
+
+    /// 释放播放器送回的旧图
+    fn collect_garbage(&mut self) {
+        while let Ok(graph) = self.garbage.try_recv() {
+            drop(graph);
+            self.in_flight -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use crate::{
+        AudioBufferMut, AudioSourceDesc, FloatMessage, GraphPlayer, Message, MessageBuffer,
+        MessageValue, PlayHead, A_OUT_NODE,
+    };
+
+    use super::super::test::{run_with, Constant, Gain, Volume};
+    use super::*;
+
+    /// 每帧输出递增的值, 用于检查节点状态是否被保留
+    struct Ramp(String, f64);
+
+    impl AudioSourceNode for Ramp {
+        fn name(&self) -> String {
+            self.0.clone()
+        }
+
+        fn prepare(&mut self, _sample_rate: f64) -> AudioSourceDesc {
+            AudioSourceDesc::default()
+        }
+
+        fn process(
+            &mut self,
+            _playhead: &PlayHead,
+            _frames: usize,
+            audio_out: AudioBufferMut,
+            _message_in: &MessageBuffer,
+        ) {
+            for (l, r) in audio_out {
+                self.1 += 1.0;
+                *l += self.1;
+                *r += self.1;
+            }
+        }
+    }
+
+    fn run(player: &mut GraphPlayer) -> Vec<(f64, f64)> {
+        run_with(0.0, |playhead, frames, audio_in, audio_out, message_in| {
+            player.process(playhead, frames, audio_in, audio_out, message_in)
+        })
+    }
+
+    #[test]
+    fn hot_swap() {
+        let mut graph = Graph::new("test");
+        graph
+            .add_audio_source(Ramp("ramp".to_string(), 0.0))
+            .unwrap();
+        graph
+            .add_audio_effect(Gain("gain".to_string(), 1.0))
+            .unwrap();
+        graph.add_audio_link("ramp", "gain").unwrap();
+        graph.add_audio_link("gain", A_OUT_NODE).unwrap();
+        graph.prepare(48000.0, 16).unwrap();
+        let mut player = GraphPlayer::new(graph);
+        let mut editor = player.editor();
+        assert_eq!(run(&mut player)[15], (16.0, 16.0));
+
+        let mut editor = thread::spawn(move || {
+            editor
+                .replace_node(
+                    "gain",
+                    BoxedNode::audio_effect(Gain("gain".to_string(), 2.0)),
+                )
+                .unwrap();
+            editor
+                .add_audio_source(Constant("dc".to_string(), 0.5))
+                .unwrap();
+            editor.add_audio_link("dc", A_OUT_NODE).unwrap();
+            editor.commit().unwrap();
+            editor
+        })
+        .join()
+        .unwrap();
+        assert_eq!(editor.pending_updates(), 1);
+
+        let out = run(&mut player);
+        assert_eq!(out[0], (34.5, 34.5));
+        assert_eq!(out[15], (64.5, 64.5));
+        assert_eq!(editor.pending_updates(), 0);
+        assert_eq!(player.graph.nodes.len(), 3);
+
+        editor.remove_node("dc").unwrap();
+        assert!(matches!(
+            editor.add_audio_link("dc", A_OUT_NODE),
+            Err(crate::LinkError::UnknownName(_))
+        ));
+        for _ in 0..PENDING_UPDATES {
+            editor.commit().unwrap();
+        }
+        assert!(matches!(editor.commit(), Err(GraphError::UpdatePending)));
+        assert_eq!(run(&mut player)[0], (66.0, 66.0));
+        assert_eq!(editor.pending_updates(), 0);
+        assert_eq!(player.graph.nodes.len(), 2);
+    }
+
+    #[test]
+    fn preset() {
+        let mut graph = Graph::new("test");
+        graph
+            .add_audio_source(Constant("dc".to_string(), 1.0))
+            .unwrap();
+        graph
+            .add_audio_effect(Volume("vol".to_string(), 1.0))
+            .unwrap();
+        graph.add_audio_link("dc", "vol").unwrap();
+        graph.add_audio_link("vol", A_OUT_NODE).unwrap();
+        graph.prepare(48000.0, 16).unwrap();
+        let mut player = GraphPlayer::new(graph);
+        let mut editor = player.editor();
+
+        editor
+            .load_preset(&"float /vol/gain 3".parse().unwrap())
+            .unwrap();
+        assert!(run(&mut player).iter().all(|f| *f == (3.0, 3.0)));
+        // 播放器还没换上时再次提交, 预设仍然会送出
+        editor
+            .load_preset(&"float /vol/gain 1.5".parse().unwrap())
+            .unwrap();
+        editor.commit().unwrap();
+        assert!(run(&mut player).iter().all(|f| *f == (1.5, 1.5)));
+        assert!(matches!(
+            editor.load_preset(&"float /none/gain 1".parse().unwrap()),
+            Err(GraphError::UnknownName(name)) if name == "none"
+        ));
+
+        let mut messages = MessageBuffer(vec![], 16);
+        messages.add(
+            0,
+            Message {
+                addr: vec!["vol".to_string()],
+                value: MessageValue::Float(FloatMessage {
+                    name: "gain".to_string(),
+                    value: 2.0,
+                }),
+            },
+        );
+        run_with(0.0, |playhead, frames, audio_in, audio_out, _| {
+            player.process(playhead, frames, audio_in, audio_out, &messages)
+        });
+        assert_eq!(player.save_preset().to_string(), "float /vol/gain 2\n");
+    }
+
+    #[test]
+    fn editor_dropped() {
+        let mut graph = Graph::new("test");
+        graph
+            .add_audio_source(Constant("dc".to_string(), 1.0))
+            .unwrap();
+        graph.add_audio_link("dc", A_OUT_NODE).unwrap();
+        graph.prepare(48000.0, 16).unwrap();
+        let mut player = GraphPlayer::new(graph);
+        let mut editor = player.editor();
+        editor.commit().unwrap();
+        editor.commit().unwrap();
+        drop(editor);
+
+        // 送不回编辑器的旧图留在播放器里, 不在处理时释放
+        assert!(run(&mut player).iter().all(|f| *f == (1.0, 1.0)));
+        assert_eq!(player.retired.len(), 2);
+        let _editor = player.editor();
+        assert!(player.retired.is_empty());
+    }
+}
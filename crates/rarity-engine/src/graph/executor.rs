@@ -0,0 +1,110 @@
+use std::{
+    hint, io,
+    ptr::{self, null_mut},
+    sync::{
+        atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+struct Shared {
+    /// 指向当前任务 `&(dyn Fn() + Sync)` 的指针, 没有任务时为空
+    job: AtomicPtr<()>,
+    /// 正在检查或执行任务的工作线程数量
+    active: AtomicUsize,
+    stop: AtomicBool,
+}
+
+/// 固定数量工作线程组成的执行器, 用于并行处理图中互不依赖的节点
+///
+/// 工作线程在创建执行器时启动, 处理期间只通过原子变量和 park/unpark 协作,
+/// 不加锁也不分配内存. 实时调度优先级与平台相关, 用 [`Executor::with_init`]
+/// 在每个工作线程启动时设置, 例如设为与音频回调线程相同的优先级.
+pub struct Executor {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Executor {
+    /// 创建包含 threads 个工作线程的执行器, 调用处理的线程也会参与执行
+    pub fn new(threads: usize) -> io::Result<Self> {
+        Self::with_init(threads, |_| {})
+    }
+
+    /// 与 [`Executor::new`] 相同, 每个工作线程开始等待任务前先以自己的序号调用 init,
+    /// 用于设置实时调度优先级或绑定处理器核心
+    ///
+    /// 启动线程失败时已经启动的线程会被停止.
+    pub fn with_init(
+        threads: usize,
+        init: impl Fn(usize) + Send + Sync + 'static,
+    ) -> io::Result<Self> {
+        let init = Arc::new(init);
+        let mut executor = Self {
+            shared: Arc::new(Shared {
+                job: AtomicPtr::new(null_mut()),
+                active: AtomicUsize::new(0),
+                stop: AtomicBool::new(false),
+            }),
+            workers: Vec::with_capacity(threads),
+        };
+        for i in 0..threads {
+            let shared = executor.shared.clone();
+            let init = init.clone();
+            let worker = thread::Builder::new()
+                .name(format!("rarity-worker-{}", i))
+                .spawn(move || {
+                    init(i);
+                    work(&shared)
+                })?;
+            executor.workers.push(worker);
+        }
+        Ok(executor)
+    }
+
+    pub fn threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// 在当前线程和所有工作线程上同时执行 f, 所有线程都离开 f 后才返回
+    ///
+    /// f 需要自行分配工作, 并且在工作全部完成之前不返回.
+    pub(crate) fn broadcast(&mut self, f: &(dyn Fn() + Sync)) {
+        let job = &f as *const &(dyn Fn() + Sync) as *mut ();
+        self.shared.job.store(job, Ordering::SeqCst);
+        for worker in &self.workers {
+            worker.thread().unpark();
+        }
+        f();
+        self.shared.job.store(null_mut(), Ordering::SeqCst);
+        // 工作线程可能仍持有任务的指针, 等它们全部离开
+        while self.shared.active.load(Ordering::SeqCst) != 0 {
+            hint::spin_loop();
+        }
+    }
+}
+
+fn work(shared: &Shared) {
+    while !shared.stop.load(Ordering::SeqCst) {
+        shared.active.fetch_add(1, Ordering::SeqCst);
+        let job = shared.job.load(Ordering::SeqCst);
+        if !ptr::eq(job, null_mut()) {
+            // broadcast 在 active 归零前不会返回, 因此 job 在此期间有效
+            let f = unsafe { *(job as *const &(dyn Fn() + Sync)) };
+            f();
+        }
+        shared.active.fetch_sub(1, Ordering::SeqCst);
+        thread::park();
+    }
+}
+
+impl Drop for Executor {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::SeqCst);
+        for worker in self.workers.drain(..) {
+            worker.thread().unpark();
+            let _ = worker.join();
+        }
+    }
+}
@@ -0,0 +1,2551 @@
+#![allow(dead_code)]
+mod compiler;
+mod description;
+pub use description::*;
+mod editor;
+pub use editor::*;
+mod executor;
+pub use executor::*;
+mod parameters;
+pub use parameters::*;
+mod subgraph;
+mod topology;
+use std::{
+    collections::HashMap,
+    hint, mem,
+    ops::Range,
+    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
+};
+
+use atomic_refcell::{AtomicRef, AtomicRefCell, AtomicRefMut};
+
+use crate::{
+    port_index, with_sample, AnyAudioBuffer, AnyAudioMut, AnyAudioRef, AudioBuffer, AudioBufferMut,
+    AudioBufferRef, AudioEffectNode, AudioSourceNode, BoxedNode, GraphError, GraphResult,
+    LinkError, LinkResult, MessageBuffer, MidiEffectNode, MultiAudioNode, NodeState, NodeType,
+    PlayHead, RawDesc, RawNode, Sample, SampleFormat, Sealed, TapReceiver, TapResult, TapSender,
+};
+use compiler::{compile, topo_sort, BufferSpec, Task};
+use topology::{endpoint, Topology, IO_PORTS};
+
+pub struct Graph {
+    name: String,
+    sample_rate: f64,
+    nodes: HashMap<String, RawNode>,
+    node_descs: HashMap<String, RawDesc>,
+    parameters: ParameterTree,
+    values: ParameterValues,
+    /// load_preset 留给节点的状态, 在下一个块的开头送出
+    state_in: MessageBuffer,
+    topology: Topology,
+    audio_buffers: Vec<AtomicRefCell<AnyAudioBuffer>>,
+    /// 每个音频缓冲的样本格式, 不需要借用缓冲就能查到
+    audio_formats: Vec<SampleFormat>,
+    /// 需要保留历史内容的缓冲, 每块之后前进; 其余缓冲总是从开头使用, 交给节点时连续
+    persistent_buffers: Vec<usize>,
+    message_buffers: Vec<AtomicRefCell<MessageBuffer>>,
+    sequences: Vec<Operation>,
+    tasks: Vec<Task>,
+    task_states: Vec<AtomicU8>,
+    finished: AtomicUsize,
+    /// 发给节点但参数不存在或类型不符而被丢弃的控制信息数
+    rejected: AtomicUsize,
+    executor: Option<Executor>,
+    feedback_delay: FeedbackDelay,
+    /// 图内部每次处理的最大帧数, 更长的块会被拆开处理
+    max_block_size: usize,
+    /// 图的输入输出的声道数
+    channels: usize,
+    /// 图内部输入输出缓冲的样本格式
+    sample_format: SampleFormat,
+    latency: usize,
+    dirty: bool,
+}
+
+/// 反馈连接的延迟
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum FeedbackDelay {
+    /// 延迟一个完整的处理块, 开销最小, 但延迟随块大小变化
+    #[default]
+    Block,
+    /// 把每个块拆成不超过给定帧数的小块处理, 反馈延迟固定为该帧数
+    ///
+    /// 超过图的最大块长时按最大块长拆分.
+    Frames(usize),
+}
+
+/// 连接: 来源节点, 目标节点, 来源的输出端口, 目标的输入端口
+///
+/// 端口为 None 时表示第一个端口, 控制连接没有端口.
+/// 音频连接在节点有描述后把端口解析为序号, 同一连接只有一种写法.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Link(String, String, Option<String>, Option<String>);
+
+impl Link {
+    /// 解析 `节点名` 或 `节点名:端口` 形式的两端
+    fn parse(from: &str, to: &str) -> Self {
+        let (from, from_port) = split_port(from);
+        let (to, to_port) = split_port(to);
+        Self(
+            from.to_string(),
+            to.to_string(),
+            from_port.map(str::to_string),
+            to_port.map(str::to_string),
+        )
+    }
+
+    /// 把两端的端口换成序号, 第一个端口换成 None; 还没有描述的节点保持原样
+    fn resolve(&mut self, node_descs: &HashMap<String, RawDesc>) -> LinkResult<()> {
+        let Link(from, to, from_port, to_port) = self;
+        let outputs = node_descs.get(from.as_str()).map(|d| &d.audio_out[..]);
+        resolve_port(from, from_port, outputs)?;
+        let inputs = node_descs.get(to.as_str()).map(|d| &d.audio_in[..]);
+        resolve_port(to, to_port, inputs)
+    }
+}
+
+fn resolve_port(node: &str, port: &mut Option<String>, ports: Option<&[String]>) -> LinkResult<()> {
+    let ports = match ports {
+        _ if node == A_IN_NODE || node == A_OUT_NODE => &IO_PORTS[..],
+        // 没有端口的节点留给连接检查报错
+        Some(ports) if !ports.is_empty() => ports,
+        _ => return Ok(()),
+    };
+    *port = match port_index(ports, port.as_deref()) {
+        Some(0) => None,
+        Some(index) => Some(index.to_string()),
+        None => return Err(LinkError::UnknownPort(endpoint(node, port))),
+    };
+    Ok(())
+}
+
+fn split_port(endpoint: &str) -> (&str, Option<&str>) {
+    match endpoint.split_once(':') {
+        Some((node, port)) => (node, Some(port)),
+        None => (endpoint, None),
+    }
+}
+
+impl FeedbackDelay {
+    pub(crate) fn check(&self) -> GraphResult<()> {
+        match *self {
+            FeedbackDelay::Frames(0) => Err(GraphError::InvalidFeedbackDelay),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub(crate) enum Operation {
+    AudioZeros(Vec<usize>),
+    AudioFromInput(Vec<usize>),
+    AudioToOutput(Vec<usize>),
+    AudioClone(usize, Vec<usize>),
+    AudioMerge(usize, Vec<usize>),
+    AudioFromPrevious(usize, usize),
+    /// 把来源上混或下混到声道数不同的目标
+    AudioMix(usize, usize),
+    /// 把来源转换为样本格式不同的目标
+    AudioConvert(usize, usize),
+    AudioDelay(usize, usize, usize, usize),
+    MessageZeros(Vec<usize>),
+    MessageFromInput(Vec<(usize, String)>),
+    MessageClone(usize, Vec<usize>),
+    MessageMerge(usize, Vec<usize>),
+    Process(String, Vec<usize>, Vec<usize>, usize, Vec<usize>),
+    Tap(TapSender, Option<usize>, Option<usize>),
+}
+
+pub static A_OUT_NODE: &str = "A_OUT_NODE";
+pub static A_IN_NODE: &str = "A_IN_NODE";
+
+/// 处理一个块所需的输入输出
+struct Block<'a> {
+    playhead: &'a PlayHead,
+    range: Range<usize>,
+    frames: usize,
+    /// 反馈连接读取的内容相对块开头延迟的帧数
+    delay: usize,
+    audio_in: AnyAudioRef<'a>,
+    audio_out: AtomicRefCell<AnyAudioMut<'a>>,
+    message_in: &'a MessageBuffer,
+}
+
+/// 并行执行时任务的状态
+const PENDING: u8 = 0;
+const RUNNING: u8 = 1;
+const DONE: u8 = 2;
+
+/// 默认的最大块长
+pub const DEFAULT_MAX_BLOCK_SIZE: usize = 4096;
+
+impl Graph {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            sample_rate: 0.0,
+            nodes: HashMap::default(),
+            node_descs: HashMap::default(),
+            parameters: ParameterTree::default(),
+            values: ParameterValues::default(),
+            state_in: MessageBuffer::new(),
+            topology: Topology::default(),
+            audio_buffers: Vec::default(),
+            audio_formats: Vec::default(),
+            persistent_buffers: Vec::default(),
+            message_buffers: Vec::default(),
+            sequences: Vec::default(),
+            tasks: Vec::default(),
+            task_states: Vec::default(),
+            finished: AtomicUsize::new(0),
+            rejected: AtomicUsize::new(0),
+            executor: None,
+            feedback_delay: FeedbackDelay::default(),
+            max_block_size: DEFAULT_MAX_BLOCK_SIZE,
+            channels: 2,
+            sample_format: SampleFormat::default(),
+            latency: 0,
+            dirty: true,
+        }
+    }
+
+    pub fn add_audio_source<S: Sample, T: AudioSourceNode<S>>(
+        &mut self,
+        node: T,
+    ) -> GraphResult<()> {
+        self.topology
+            .add_node(&node.name(), NodeType::AudioSource)?;
+        self.insert_node(RawNode::with_audio_source(node));
+        Ok(())
+    }
+
+    pub fn add_audio_effect<S: Sample, T: AudioEffectNode<S>>(
+        &mut self,
+        node: T,
+    ) -> GraphResult<()> {
+        self.topology
+            .add_node(&node.name(), NodeType::AudioEffect)?;
+        self.insert_node(RawNode::with_audio_effect(node));
+        Ok(())
+    }
+
+    pub fn add_midi_effect<T: MidiEffectNode>(&mut self, node: T) -> GraphResult<()> {
+        self.topology.add_node(&node.name(), NodeType::MidiEffect)?;
+        self.insert_node(RawNode::with_midi_effect(node));
+        Ok(())
+    }
+
+    pub fn add_multi_audio<S: Sample, T: MultiAudioNode<S>>(&mut self, node: T) -> GraphResult<()> {
+        self.topology.add_node(&node.name(), NodeType::MultiAudio)?;
+        self.insert_node(RawNode::with_multi_audio(node));
+        Ok(())
+    }
+
+    pub fn add_node(&mut self, node: BoxedNode) -> GraphResult<()> {
+        self.topology.add_node(&node.name(), *node.node_type())?;
+        self.insert_node(node.0);
+        Ok(())
+    }
+
+    /// 把 sub 作为子图加入, 加入前先按本图的采样率编译子图, 子图有误时返回错误
+    ///
+    /// 本图还没有 prepare 过时采样率未知, 返回 [`GraphError::NotPrepared`].
+    /// 用 add_audio_effect 加入的子图到 prepare 时才编译, 编译失败的子图只输出静音.
+    pub fn add_subgraph(&mut self, mut sub: Graph) -> GraphResult<()> {
+        if self.sample_rate <= 0.0 {
+            return Err(GraphError::NotPrepared(self.name.clone()));
+        }
+        sub.prepare(self.sample_rate, sub.max_block_size)?;
+        self.add_audio_effect(sub)
+    }
+
+    fn insert_node(&mut self, node: RawNode) {
+        self.nodes.insert(node.name(), node);
+        self.dirty = true;
+    }
+
+    /// 移除节点以及与它相关的所有连接, 返回被移除的节点
+    pub fn remove_node(&mut self, name: &str) -> GraphResult<BoxedNode> {
+        self.topology.remove_node(name)?;
+        self.node_descs.remove(name);
+        self.dirty = true;
+        Ok(BoxedNode(self.nodes.remove(name).unwrap()))
+    }
+
+    /// 用新节点替换名为 name 的节点, 原有的连接会转移到新节点上, 返回被替换的节点
+    ///
+    /// 新节点可以改名, 但新名字不能与其它节点重复; 新节点的类型必须能接受原有的连接.
+    pub fn replace_node(&mut self, name: &str, node: BoxedNode) -> GraphResult<BoxedNode> {
+        self.topology
+            .replace_node(name, &node.name(), *node.node_type())?;
+        let old = self.nodes.remove(name).unwrap();
+        self.node_descs.remove(name);
+        self.insert_node(node.0);
+        Ok(BoxedNode(old))
+    }
+
+    /// 连接 from 的输出和 to 的输入, 两端都可以用 `节点名:端口` 指定端口
+    pub fn add_audio_link(&mut self, from: &str, to: &str) -> LinkResult<()> {
+        self.topology
+            .add_audio_link(Link::parse(from, to), &self.node_descs)?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn add_message_link(&mut self, from: &str, to: &str) -> LinkResult<()> {
+        self.topology.add_message_link(from, to)?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn remove_audio_link(&mut self, from: &str, to: &str) -> LinkResult<()> {
+        self.topology
+            .remove_audio_link(from, to, &self.node_descs)?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn remove_message_link(&mut self, from: &str, to: &str) -> LinkResult<()> {
+        self.topology.remove_message_link(from, to)?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// 添加反馈连接, from 的输出延迟后送入 to, 允许成环
+    pub fn add_audio_feedback_link(&mut self, from: &str, to: &str) -> LinkResult<()> {
+        self.topology
+            .add_audio_feedback_link(Link::parse(from, to), &self.node_descs)?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn remove_audio_feedback_link(&mut self, from: &str, to: &str) -> LinkResult<()> {
+        self.topology
+            .remove_audio_feedback_link(from, to, &self.node_descs)?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// 监听节点的第一个音频输出和第一个控制输出, 返回可以在其它线程读取的通道
+    ///
+    /// A_IN_NODE 和 A_OUT_NODE 也可以被监听, 分别得到图的输入和输出.
+    /// 节点的输出只能被连接或者被监听: 监听已有输出连接的节点返回 [`TapIsLinked`],
+    /// 从被监听的节点连出返回 [`LinkError::LinkIsTapped`].
+    ///
+    /// [`TapIsLinked`]: crate::TapError::TapIsLinked
+    pub fn add_tap(&mut self, name: &str) -> TapResult<TapReceiver> {
+        let tap = self.topology.add_tap(name)?;
+        self.dirty = true;
+        Ok(tap)
+    }
+
+    pub fn remove_tap(&mut self, name: &str) -> TapResult<()> {
+        self.topology.remove_tap(name)?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// 设置反馈连接的延迟, 不需要重新 prepare, 延迟为 0 帧时返回错误
+    pub fn set_feedback_delay(&mut self, delay: FeedbackDelay) -> GraphResult<()> {
+        delay.check()?;
+        self.feedback_delay = delay;
+        Ok(())
+    }
+
+    pub fn feedback_delay(&self) -> FeedbackDelay {
+        self.feedback_delay
+    }
+
+    /// 所有节点声明的参数, prepare 之后才完整
+    pub fn parameters(&self) -> &ParameterTree {
+        &self.parameters
+    }
+
+    /// 处理时因参数不存在或类型不符而丢弃的控制信息数, 发送前可以用 [`ParameterTree::check`] 检查
+    ///
+    /// 只检查节点声明了参数的地址, 发给图本身或没有声明参数的节点的控制信息原样送出.
+    pub fn rejected_messages(&self) -> usize {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    pub fn max_block_size(&self) -> usize {
+        self.max_block_size
+    }
+
+    /// 所有节点的状态组成的预设, 可以用 `to_string` 保存为文本
+    ///
+    /// 节点的 save_state 返回 None 时, 保存它声明的参数最后收到的取值, 没有收到过的为默认值.
+    pub fn save_preset(&self) -> NodeState {
+        let mut names = self.nodes.keys().collect::<Vec<_>>();
+        names.sort();
+        let mut preset = NodeState::new();
+        for name in names {
+            let state = self.nodes[name]
+                .save_state()
+                .unwrap_or_else(|| self.values.node(name));
+            preset.extend_node(name, state);
+        }
+        preset
+    }
+
+    /// 恢复预设中各个节点的状态, 预设含有图中不存在的节点时不做任何改变
+    ///
+    /// 节点的 load_state 返回 false 时, 它的状态作为控制信息在下一个块的开头发给它.
+    pub fn load_preset(&mut self, preset: &NodeState) -> GraphResult<()> {
+        let unknown = preset
+            .0
+            .iter()
+            .filter_map(|m| m.addr.last())
+            .find(|name| !self.nodes.contains_key(*name));
+        if let Some(name) = unknown {
+            return Err(GraphError::UnknownName(name.clone()));
+        }
+        let mut queued = vec![];
+        for (name, node) in self.nodes.iter_mut() {
+            let state = preset.node(name);
+            if !state.is_empty() && !node.load_state(&state) {
+                queued.push(name);
+            }
+        }
+        self.state_in.set_frames(1);
+        for message in &preset.0 {
+            if message.addr.last().is_some_and(|n| queued.contains(&n)) {
+                self.state_in.add(0, message.clone());
+            }
+            self.values.record(&self.parameters, message);
+        }
+        Ok(())
+    }
+
+    /// 把预设作为控制信息在下一个块的开头发给各个节点, 用于播放中的图
+    fn queue_preset(&mut self, preset: &NodeState) {
+        self.state_in.set_frames(1);
+        for message in &preset.0 {
+            self.state_in.add(0, message.clone());
+            self.values.record(&self.parameters, message);
+        }
+    }
+
+    /// 设置图的输入输出的声道数, 默认为双声道, 需要重新 prepare
+    ///
+    /// 处理时传入的缓冲声道数不同也可以, 输入输出会按 [`mix_gain`](crate::mix_gain) 的规则转换.
+    pub fn set_channels(&mut self, channels: usize) {
+        assert!(channels > 0, "声道数不能为 0");
+        self.channels = channels;
+        self.dirty = true;
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// 设置图内部输入输出缓冲的样本格式, 默认为 f64, 需要重新 prepare
+    ///
+    /// 处理时传入的缓冲可以是任意样本类型, 节点之间按各自的样本类型转换.
+    pub fn set_sample_format(&mut self, format: SampleFormat) {
+        self.sample_format = format;
+        self.dirty = true;
+    }
+
+    pub fn sample_format(&self) -> SampleFormat {
+        self.sample_format
+    }
+
+    /// 拓扑结构在上次 prepare 之后是否改变过, 改变后需要重新 prepare 才能继续处理
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// 图的输出相对输入的总延迟, 在 prepare 之后有效
+    pub fn latency(&self) -> usize {
+        self.latency
+    }
+
+    /// 使用执行器并行处理互不依赖的节点, 返回之前的执行器
+    pub fn set_executor(&mut self, executor: Executor) -> Option<Executor> {
+        self.executor.replace(executor)
+    }
+
+    pub fn take_executor(&mut self) -> Option<Executor> {
+        self.executor.take()
+    }
+
+    /// 从旧图中取回本图拓扑中存在但尚未持有的节点和执行器, 节点的状态得以保留
+    ///
+    /// 节点表需预留足够的容量, 这样在音频线程调用时不会分配内存.
+    pub(crate) fn adopt_nodes(&mut self, old: &mut Graph) {
+        if self.executor.is_none() {
+            self.executor = old.executor.take();
+        }
+        self.values.adopt(&old.values);
+        // 旧图还没送出的预设跟着节点一起换过来, 未送出的预设比旧图记录的取值新
+        if self.state_in.is_empty() {
+            mem::swap(&mut self.state_in, &mut old.state_in);
+        }
+        for (_, message) in &self.state_in {
+            self.values.record(&self.parameters, message);
+        }
+        for name in self.topology.node_types.keys() {
+            if !self.nodes.contains_key(name) {
+                if let Some((name, node)) = old.nodes.remove_entry(name) {
+                    self.nodes.insert(name, node);
+                }
+            }
+        }
+    }
+
+    /// 准备所有节点, 并根据连接编译执行序列
+    ///
+    /// 内部缓冲按 max_block_size 帧分配, 处理时传入更长的块也可以, 会被拆成小块处理.
+    pub fn prepare(&mut self, sample_rate: f64, max_block_size: usize) -> GraphResult<()> {
+        assert!(max_block_size > 0, "最大块长不能为 0");
+        self.sample_rate = sample_rate;
+        self.max_block_size = max_block_size;
+        self.node_descs.clear();
+        for (name, node) in self.nodes.iter_mut() {
+            self.node_descs
+                .insert(name.clone(), node.prepare(sample_rate));
+        }
+        self.build()
+    }
+
+    /// 用已有的节点描述编译执行序列并分配缓冲, 不会再次调用节点的 prepare
+    fn build(&mut self) -> GraphResult<()> {
+        // 有音频输入输出的节点至少需要一个声道
+        for (name, desc) in &self.node_descs {
+            let audio = !desc.audio_in.is_empty() || !desc.audio_out.is_empty();
+            if audio && desc.channels == 0 {
+                return Err(GraphError::NoChannels(name.clone()));
+            }
+            // 音频线程会按参数的范围限制控制信息的取值
+            if let Some(p) = desc.parameters.iter().find(|p| !p.range.is_valid()) {
+                let mut addr = p.addr.clone();
+                addr.push(name.clone());
+                return Err(GraphError::InvalidParameter(path(&addr, p.range.name())));
+            }
+        }
+        self.topology.resolve_links(&self.node_descs)?;
+        self.topology.check_links(&self.node_descs)?;
+        self.topology.check_taps(&self.node_descs)?;
+        let order =
+            topo_sort(&self.node_descs, &self.topology.links()).map_err(LinkError::Cycle)?;
+        let layout = BufferSpec {
+            channels: self.channels,
+            format: self.sample_format,
+        };
+        let compiled = compile(
+            &order,
+            &self.node_descs,
+            &self.topology,
+            layout,
+            self.max_block_size,
+        );
+        // 反馈来源和延迟线的缓冲需要保留历史内容
+        self.audio_buffers = compiled
+            .audio_buffers
+            .iter()
+            .enumerate()
+            .map(|(i, layout)| {
+                let frames = compiled
+                    .persistent
+                    .iter()
+                    .find(|p| p.0 == i)
+                    .map_or(self.max_block_size, |p| p.1);
+                AtomicRefCell::new(AnyAudioBuffer::new(layout.format, layout.channels, frames))
+            })
+            .collect();
+        self.audio_formats = compiled.audio_buffers.iter().map(|l| l.format).collect();
+        self.persistent_buffers = compiled.persistent.iter().map(|p| p.0).collect();
+        self.latency = compiled.latency;
+        self.message_buffers = (0..compiled.message_buffers)
+            .map(|_| AtomicRefCell::new(MessageBuffer::new()))
+            .collect();
+        self.sequences = compiled.sequences;
+        self.task_states = compiled
+            .tasks
+            .iter()
+            .map(|_| AtomicU8::new(PENDING))
+            .collect();
+        self.tasks = compiled.tasks;
+        self.parameters = ParameterTree::new(&self.node_descs);
+        // 重新 prepare 时保留参数已经收到的取值
+        let mut values = ParameterValues::new(&self.parameters);
+        values.adopt(&self.values);
+        self.values = values;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// 处理 frames 帧, 输入输出可以是任意样本类型和声道数
+    ///
+    /// 超过最大块长的块会被拆成小块依次处理, 输入消息按所在的小块送出, 时间相对小块的开头.
+    /// 发给已知参数的控制信息先被限制到参数的范围内, 图会记录它们的取值用于保存预设.
+    /// 节点声明了参数的地址上, 参数不存在或类型不符的控制信息被丢弃, 计入 [`Graph::rejected_messages`].
+    pub fn process<S: Sample>(
+        &mut self,
+        playhead: &PlayHead,
+        frames: usize,
+        audio_in: AudioBufferRef<S>,
+        audio_out: AudioBufferMut<S>,
+        message_in: &MessageBuffer,
+    ) {
+        if self.dirty {
+            return;
+        }
+        for (_, message) in message_in {
+            self.values.record(&self.parameters, message);
+        }
+        let block_size = match self.feedback_delay {
+            FeedbackDelay::Block => self.max_block_size,
+            FeedbackDelay::Frames(n) => n.min(self.max_block_size),
+        };
+        let mut playhead = *playhead;
+        let mut audio_in = audio_in;
+        let mut audio_out = audio_out;
+        let mut offset = 0;
+        while offset < frames {
+            let len = block_size.min(frames - offset);
+            let (block_in, rest_in) = audio_in.split_at(len);
+            let (block_out, rest_out) = audio_out.split_at_mut(len);
+            audio_in = rest_in;
+            audio_out = rest_out;
+            // 最后一个小块同时接收超出块长度的消息, 与不拆分时保持一致
+            let end = if offset + len == frames {
+                usize::MAX
+            } else {
+                offset + len
+            };
+            self.process_block(
+                &playhead,
+                offset..end,
+                len,
+                S::wrap_ref(block_in),
+                S::wrap_mut(block_out),
+                message_in,
+            );
+            playhead.advance(len);
+            offset += len;
+        }
+        self.state_in.clear();
+    }
+
+    /// 处理一个小块, 只接收时间落在 range 内的输入消息, 消息时间相对小块的开头
+    fn process_block(
+        &mut self,
+        playhead: &PlayHead,
+        range: Range<usize>,
+        frames: usize,
+        audio_in: AnyAudioRef,
+        audio_out: AnyAudioMut,
+        message_in: &MessageBuffer,
+    ) {
+        // 按帧数拆分时最后的小块可能更短, 但仍读取固定帧数之前的输出
+        let delay = match self.feedback_delay {
+            FeedbackDelay::Block => frames,
+            FeedbackDelay::Frames(n) => n.min(self.max_block_size),
+        };
+        let block = Block {
+            playhead,
+            range,
+            frames,
+            delay,
+            audio_in,
+            audio_out: AtomicRefCell::new(audio_out),
+            message_in,
+        };
+        match self.executor.take() {
+            Some(mut executor) if self.tasks.len() > 1 => {
+                for state in &self.task_states {
+                    state.store(PENDING, Ordering::Relaxed);
+                }
+                self.finished.store(0, Ordering::Relaxed);
+                executor.broadcast(&|| self.run_tasks(&block));
+                self.executor = Some(executor);
+            }
+            executor => {
+                self.executor = executor;
+                for op in &self.sequences {
+                    self.run_operation(op, &block);
+                }
+            }
+        }
+        for i in &self.persistent_buffers {
+            self.audio_buffers[*i].borrow_mut().forward(frames);
+        }
+    }
+
+    /// 不断领取依赖都已完成的任务并执行, 直到所有任务完成
+    fn run_tasks(&self, block: &Block) {
+        while self.finished.load(Ordering::Acquire) < self.tasks.len() {
+            let mut progressed = false;
+            for (task, state) in self.tasks.iter().zip(&self.task_states) {
+                if state.load(Ordering::Acquire) != PENDING
+                    || task
+                        .deps
+                        .iter()
+                        .any(|d| self.task_states[*d].load(Ordering::Acquire) != DONE)
+                {
+                    continue;
+                }
+                if state
+                    .compare_exchange(PENDING, RUNNING, Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok()
+                {
+                    for op in &self.sequences[task.operations.clone()] {
+                        self.run_operation(op, block);
+                    }
+                    state.store(DONE, Ordering::Release);
+                    self.finished.fetch_add(1, Ordering::AcqRel);
+                    progressed = true;
+                }
+            }
+            if !progressed {
+                hint::spin_loop();
+            }
+        }
+    }
+
+    fn audio<S: Sample>(&self, index: usize) -> AtomicRef<'_, AudioBuffer<S>> {
+        AtomicRef::map(self.audio_buffers[index].borrow(), S::buffer)
+    }
+
+    fn audio_mut<S: Sample>(&self, index: usize) -> AtomicRefMut<'_, AudioBuffer<S>> {
+        AtomicRefMut::map(self.audio_buffers[index].borrow_mut(), S::buffer_mut)
+    }
+
+    fn run_operation(&self, op: &Operation, block: &Block) {
+        let playhead = block.playhead;
+        let range = &block.range;
+        let frames = block.frames;
+        let message_in = block.message_in;
+        match op {
+            Operation::AudioZeros(tgt) => {
+                for i in tgt {
+                    with_sample!(self.audio_formats[*i], S => {
+                        self.audio_mut::<S>(*i).next_n_frames_mut(frames).clear()
+                    });
+                }
+            }
+            Operation::AudioFromInput(tgt) => {
+                for i in tgt {
+                    with_sample!(self.audio_formats[*i], S => {
+                        let mut tgt = self.audio_mut::<S>(*i);
+                        let mut tgt = tgt.next_n_frames_mut(frames);
+                        match block.audio_in {
+                            AnyAudioRef::F32(audio_in) => assign(&mut tgt, audio_in),
+                            AnyAudioRef::F64(audio_in) => assign(&mut tgt, audio_in),
+                        }
+                    });
+                }
+            }
+            Operation::AudioToOutput(src) => {
+                let mut audio_out = block.audio_out.borrow_mut();
+                for i in src {
+                    with_sample!(self.audio_formats[*i], S => {
+                        let src = self.audio::<S>(*i);
+                        let src = src.next_n_frames_ref(frames);
+                        match &mut *audio_out {
+                            AnyAudioMut::F32(audio_out) => audio_out.mix_from(src),
+                            AnyAudioMut::F64(audio_out) => audio_out.mix_from(src),
+                        }
+                    });
+                }
+            }
+            Operation::AudioClone(src, tgt) => {
+                assert!(!tgt.contains(src));
+                with_sample!(self.audio_formats[*src], S => {
+                    let src = self.audio::<S>(*src);
+                    for i in tgt {
+                        self.audio_mut::<S>(*i)
+                            .next_n_frames_mut(frames)
+                            .copy_from(src.next_n_frames_ref(frames));
+                    }
+                });
+            }
+            Operation::AudioMerge(tgt, src) => {
+                assert!(!src.contains(tgt));
+                with_sample!(self.audio_formats[*tgt], S => {
+                    let mut tgt = self.audio_mut::<S>(*tgt);
+                    for i in src {
+                        tgt.next_n_frames_mut(frames)
+                            .mix_from(self.audio::<S>(*i).next_n_frames_ref(frames));
+                    }
+                });
+            }
+            Operation::AudioFromPrevious(src, tgt) => {
+                with_sample!(self.audio_formats[*src], S => {
+                    let src = self.audio::<S>(*src);
+                    self.audio_mut::<S>(*tgt)
+                        .next_n_frames_mut(frames)
+                        .copy_from(src.delayed_n_frames_ref(block.delay, frames));
+                });
+            }
+            Operation::AudioMix(src, tgt) => {
+                with_sample!(self.audio_formats[*src], S => {
+                    let src = self.audio::<S>(*src);
+                    let mut tgt = self.audio_mut::<S>(*tgt);
+                    let mut tgt = tgt.next_n_frames_mut(frames);
+                    tgt.clear();
+                    tgt.mix_from(src.next_n_frames_ref(frames));
+                });
+            }
+            Operation::AudioConvert(src, tgt) => {
+                with_sample!(self.audio_formats[*src], T => {
+                    let src = self.audio::<T>(*src);
+                    with_sample!(self.audio_formats[*tgt], S => {
+                        self.audio_mut::<S>(*tgt)
+                            .next_n_frames_mut(frames)
+                            .copy_from(src.next_n_frames_ref(frames));
+                    });
+                });
+            }
+            Operation::AudioDelay(src, line, tgt, delay) => {
+                with_sample!(self.audio_formats[*src], S => {
+                    let src = self.audio::<S>(*src);
+                    let mut line = self.audio_mut::<S>(*line);
+                    line.next_n_frames_mut(frames)
+                        .copy_from(src.next_n_frames_ref(frames));
+                    self.audio_mut::<S>(*tgt)
+                        .next_n_frames_mut(frames)
+                        .copy_from(line.delayed_n_frames_ref(*delay, frames));
+                });
+            }
+            Operation::MessageZeros(tgt) => {
+                for i in tgt {
+                    let mut tgt = self.message_buffers[*i].borrow_mut();
+                    tgt.clear();
+                    tgt.set_frames(frames);
+                }
+            }
+            Operation::MessageFromInput(tgt) => {
+                let tgt = tgt
+                    .iter()
+                    .map(|(i, name)| (self.message_buffers[*i].borrow_mut(), name));
+                for (mut tgt, name) in tgt {
+                    tgt.clear();
+                    tgt.set_frames(frames);
+                    // 恢复的状态在第一个小块的开头, 先于输入消息送出
+                    for mc in self.state_in.iter().chain(message_in) {
+                        if !range.contains(mc.0) {
+                            continue;
+                        }
+                        // 地址为空的控制信息发给图本身, 由 A_IN_NODE 转发
+                        let addr = mc.1.addr.last().map_or(A_IN_NODE, |a| a.as_str());
+                        if addr == name {
+                            let mut msg = mc.1.clone();
+                            if !self.parameters.admit(&mut msg) {
+                                self.rejected.fetch_add(1, Ordering::Relaxed);
+                                continue;
+                            }
+                            msg.addr.pop();
+                            tgt.add(*mc.0 - range.start, msg);
+                        }
+                    }
+                }
+            }
+            Operation::MessageClone(src, tgt) => {
+                assert!(!tgt.contains(src));
+                let src = self.message_buffers[*src].borrow();
+                let tgt = tgt.iter().map(|i| self.message_buffers[*i].borrow_mut());
+                for mut tgt in tgt {
+                    for mc in src.iter() {
+                        tgt.add(*mc.0, mc.1.clone());
+                    }
+                }
+            }
+            Operation::MessageMerge(tgt, src) => {
+                assert!(!src.contains(tgt));
+                let src = src.iter().map(|i| self.message_buffers[*i].borrow());
+                let mut tgt = self.message_buffers[*tgt].borrow_mut();
+                for src in src {
+                    for mc in src.iter() {
+                        tgt.add(*mc.0, mc.1.clone());
+                    }
+                }
+            }
+            Operation::Tap(tap, audio, message) => {
+                let message = message.map(|i| self.message_buffers[i].borrow());
+                match audio {
+                    Some(i) => with_sample!(self.audio_formats[*i], S => {
+                        let audio = self.audio::<S>(*i);
+                        tap.send(
+                            frames,
+                            Some(audio.next_n_frames_ref(frames)),
+                            message.as_deref(),
+                        );
+                    }),
+                    None => tap.send::<f64>(frames, None, message.as_deref()),
+                }
+            }
+            Operation::Process(name, audio_in, audio_out, message_in, message_out) => {
+                assert!(audio_in.iter().all(|i| !audio_out.contains(i)));
+                assert!(!message_out.contains(message_in));
+                let message_in = &*self.message_buffers[*message_in].borrow();
+                let mut message_out_borrow = message_out
+                    .iter()
+                    .map(|i| self.message_buffers[*i].borrow_mut())
+                    .collect::<Vec<_>>();
+                let message_out = message_out_borrow
+                    .iter_mut()
+                    .map(|b| &mut **b)
+                    .collect::<Vec<_>>();
+                with_sample!(self.node_descs[name].format, S => {
+                    let audio_in_borrow = audio_in
+                        .iter()
+                        .map(|i| self.audio::<S>(*i))
+                        .collect::<Vec<_>>();
+                    let mut audio_out_borrow = audio_out
+                        .iter()
+                        .map(|i| self.audio_mut::<S>(*i))
+                        .collect::<Vec<_>>();
+                    let audio_in = audio_in_borrow
+                        .iter()
+                        .map(|b| b.next_n_frames_ref(frames))
+                        .collect();
+                    let audio_out = audio_out_borrow
+                        .iter_mut()
+                        .map(|b| b.next_n_frames_mut(frames))
+                        .collect();
+                    self.nodes[name].process(
+                        playhead,
+                        frames,
+                        S::wrap_io(audio_in, audio_out),
+                        message_in,
+                        message_out,
+                    );
+                });
+            }
+        }
+    }
+}
+
+/// 把 src 复制到 tgt, 声道数不同时上混或下混
+fn assign<S: Sample, T: Sample>(tgt: &mut AudioBufferMut<S>, src: AudioBufferRef<T>) {
+    if tgt.channels() == src.channels() {
+        tgt.copy_from(src);
+    } else {
+        tgt.clear();
+        tgt.mix_from(src);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::{collections::VecDeque, sync::Arc};
+
+    use crate::{
+        create_node, node_id_of, register_node, registered_nodes, split_render, unregister_node,
+        AudioEffectDesc, AudioSourceDesc, BufferLayout, DescriptionError, EnumMessage, EnumRange,
+        FloatMessage, FloatRange, GraphError, Message, MessageValue, MidiEffectDesc,
+        MultiAudioDesc, NodeInfo, ParaRange, Parameter, ParameterError, PresetError, RegistryError,
+        SplitRender, TapError, Taper, Unit, TAP_NAME_LEN,
+    };
+
+    use super::*;
+
+    pub(super) struct Constant(pub String, pub f64);
+
+    impl AudioSourceNode for Constant {
+        fn name(&self) -> String {
+            self.0.clone()
+        }
+
+        fn prepare(&mut self, _sample_rate: f64) -> AudioSourceDesc {
+            AudioSourceDesc {
+                channels: 2,
+                latency: 0,
+                parameters: vec![],
+            }
+        }
+
+        fn process(
+            &mut self,
+            _playhead: &PlayHead,
+            _frames: usize,
+            audio_out: AudioBufferMut,
+            _message_in: &MessageBuffer,
+        ) {
+            for (l, r) in audio_out {
+                *l += self.1;
+                *r += self.1;
+            }
+        }
+    }
+
+    pub(super) struct Gain(pub String, pub f64);
+
+    impl AudioEffectNode for Gain {
+        fn name(&self) -> String {
+            self.0.clone()
+        }
+
+        fn prepare(&mut self, _sample_rate: f64) -> AudioEffectDesc {
+            AudioEffectDesc {
+                audio_in: 1,
+                channels: 2,
+                latency: 0,
+                parameters: vec![],
+            }
+        }
+
+        fn process(
+            &mut self,
+            _playhead: &PlayHead,
+            _frames: usize,
+            audio_in: Vec<AudioBufferRef>,
+            audio_out: AudioBufferMut,
+            _message_in: &MessageBuffer,
+        ) {
+            for ((li, ri), (lo, ro)) in audio_in[0].iter().zip(audio_out) {
+                *lo += li * self.1;
+                *ro += ri * self.1;
+            }
+        }
+    }
+
+    pub(super) struct Split(pub String, pub usize);
+
+    impl MidiEffectNode for Split {
+        fn name(&self) -> String {
+            self.0.clone()
+        }
+
+        fn prepare(&mut self, _sample_rate: f64) -> MidiEffectDesc {
+            MidiEffectDesc {
+                message_out: self.1,
+                parameters: vec![],
+            }
+        }
+
+        fn process(
+            &mut self,
+            _playhead: &PlayHead,
+            _frames: usize,
+            message_in: &MessageBuffer,
+            message_out: Vec<&mut MessageBuffer>,
+        ) {
+            for out in message_out {
+                for (f, msg) in message_in {
+                    out.add(*f, msg.clone());
+                }
+            }
+        }
+    }
+
+    pub(super) fn run(graph: &mut Graph, input: f64) -> Vec<(f64, f64)> {
+        run_with(
+            input,
+            |playhead, frames, audio_in, audio_out, message_in| {
+                graph.process(playhead, frames, audio_in, audio_out, message_in)
+            },
+        )
+    }
+
+    pub(crate) fn run_with(
+        input: f64,
+        process: impl FnOnce(&PlayHead, usize, AudioBufferRef, AudioBufferMut, &MessageBuffer),
+    ) -> Vec<(f64, f64)> {
+        let playhead = PlayHead {
+            upper: 4,
+            lower: 4,
+            div: 4,
+            samples_per_quarter: 0.0,
+            samples_from_last_bar: 0.0,
+        };
+        let mut audio_in = AudioBuffer::new(16);
+        for (l, r) in audio_in.next_n_frames_mut(16) {
+            *l = input;
+            *r = input;
+        }
+        let mut audio_out = AudioBuffer::new(16);
+        let message_in = MessageBuffer(vec![], 16);
+        process(
+            &playhead,
+            16,
+            audio_in.next_n_frames_ref(16),
+            audio_out.next_n_frames_mut(16),
+            &message_in,
+        );
+        audio_out
+            .next_n_frames_ref(16)
+            .into_iter()
+            .map(|(l, r)| (*l, *r))
+            .collect()
+    }
+
+    #[test]
+    fn chain() {
+        let mut graph = Graph::new("test");
+        graph
+            .add_audio_source(Constant("src".to_string(), 0.5))
+            .unwrap();
+        graph
+            .add_audio_effect(Gain("gain1".to_string(), 2.0))
+            .unwrap();
+        graph
+            .add_audio_effect(Gain("gain2".to_string(), 3.0))
+            .unwrap();
+        graph
+            .add_audio_effect(Gain("gain3".to_string(), 0.5))
+            .unwrap();
+        graph.add_audio_link("src", "gain1").unwrap();
+        graph.add_audio_link("gain1", "gain2").unwrap();
+        graph.add_audio_link("gain2", "gain3").unwrap();
+        graph.add_audio_link("gain3", A_OUT_NODE).unwrap();
+        graph.prepare(48000.0, 16).unwrap();
+        assert_eq!(graph.audio_buffers.len(), 2);
+        assert!(run(&mut graph, 0.0).iter().all(|f| *f == (1.5, 1.5)));
+    }
+
+    #[test]
+    fn merge_and_input() {
+        let mut graph = Graph::new("test");
+        graph
+            .add_audio_source(Constant("a".to_string(), 0.25))
+            .unwrap();
+        graph
+            .add_audio_source(Constant("b".to_string(), 0.5))
+            .unwrap();
+        graph
+            .add_audio_effect(Gain("gain".to_string(), 2.0))
+            .unwrap();
+        graph.add_audio_link("a", "gain").unwrap();
+        graph.add_audio_link("b", "gain").unwrap();
+        graph.add_audio_link(A_IN_NODE, "gain").unwrap();
+        graph.add_audio_link("gain", A_OUT_NODE).unwrap();
+        graph.add_audio_link(A_IN_NODE, A_OUT_NODE).unwrap();
+        graph.prepare(48000.0, 16).unwrap();
+        assert!(run(&mut graph, 1.0).iter().all(|f| *f == (4.5, 4.5)));
+    }
+
+    #[test]
+    fn unlinked_input_is_silent() {
+        let mut graph = Graph::new("test");
+        graph
+            .add_audio_effect(Gain("gain".to_string(), 2.0))
+            .unwrap();
+        graph.add_audio_link("gain", A_OUT_NODE).unwrap();
+        graph.prepare(48000.0, 16).unwrap();
+        assert!(run(&mut graph, 1.0).iter().all(|f| *f == (0.0, 0.0)));
+    }
+
+    #[test]
+    fn invalid_links() {
+        let mut graph = Graph::new("test");
+        graph
+            .add_audio_source(Constant("src".to_string(), 0.5))
+            .unwrap();
+        graph
+            .add_audio_effect(Gain("gain".to_string(), 2.0))
+            .unwrap();
+        graph
+            .add_midi_effect(Split("split".to_string(), 1))
+            .unwrap();
+        assert!(matches!(
+            graph.add_audio_link("src", "none"),
+            Err(LinkError::UnknownName(name)) if name == "none"
+        ));
+        assert!(matches!(
+            graph.add_audio_link(A_OUT_NODE, "gain"),
+            Err(LinkError::InvalidLinkSource(_))
+        ));
+        assert!(matches!(
+            graph.add_audio_link("gain", "src"),
+            Err(LinkError::InvalidLinkTarget(_))
+        ));
+        assert!(matches!(
+            graph.add_audio_link("split", "gain"),
+            Err(LinkError::InvalidLinkSource(_))
+        ));
+        assert!(matches!(
+            graph.add_message_link("src", "gain"),
+            Err(LinkError::InvalidLinkSource(_))
+        ));
+        assert!(matches!(
+            graph.add_message_link("split", A_OUT_NODE),
+            Err(LinkError::InvalidLinkTarget(_))
+        ));
+        graph.add_audio_link("src", "gain").unwrap();
+        assert!(matches!(
+            graph.add_audio_link("src", "gain"),
+            Err(LinkError::DuplicateLink(from, to)) if from == "src" && to == "gain"
+        ));
+    }
+
+    #[test]
+    fn cycle() {
+        let mut graph = Graph::new("test");
+        graph.add_audio_effect(Gain("a".to_string(), 1.0)).unwrap();
+        graph.add_audio_effect(Gain("b".to_string(), 1.0)).unwrap();
+        graph.add_midi_effect(Split("c".to_string(), 1)).unwrap();
+        graph.add_audio_link("a", "b").unwrap();
+        graph.add_message_link("c", "a").unwrap();
+        assert!(matches!(
+            graph.add_audio_link("a", "a"),
+            Err(LinkError::Cycle(path)) if path == ["a", "a"]
+        ));
+        assert!(matches!(
+            graph.add_message_link("c", "c"),
+            Err(LinkError::Cycle(_))
+        ));
+        assert!(matches!(
+            graph.add_audio_link("b", "a"),
+            Err(LinkError::Cycle(path)) if path == ["b", "a", "b"]
+        ));
+    }
+
+    #[test]
+    fn prepare_checks_descs() {
+        let mut graph = Graph::new("test");
+        graph
+            .add_midi_effect(Split("split".to_string(), 0))
+            .unwrap();
+        graph
+            .add_audio_effect(Gain("gain".to_string(), 1.0))
+            .unwrap();
+        graph.add_message_link("split", "gain").unwrap();
+        assert!(matches!(
+            graph.prepare(48000.0, 16),
+            Err(GraphError::LinkError(LinkError::InvalidLinkSource(name))) if name == "split"
+        ));
+    }
+
+    #[test]
+    fn invalid_names() {
+        let mut graph = Graph::new("test");
+        graph
+            .add_audio_source(Constant("src".to_string(), 0.5))
+            .unwrap();
+        assert!(matches!(
+            graph.add_audio_effect(Gain("src".to_string(), 2.0)),
+            Err(GraphError::RepeatedName(name)) if name == "src"
+        ));
+        assert!(matches!(
+            graph.add_midi_effect(Split(A_OUT_NODE.to_string(), 1)),
+            Err(GraphError::ReservedName(_))
+        ));
+        assert!(matches!(
+            graph.add_audio_source(Constant(A_IN_NODE.to_string(), 0.5)),
+            Err(GraphError::ReservedName(_))
+        ));
+        assert_eq!(graph.nodes.len(), 1);
+    }
+
+    #[test]
+    fn remove_and_replace() {
+        let mut graph = Graph::new("test");
+        graph
+            .add_audio_source(Constant("src".to_string(), 0.5))
+            .unwrap();
+        graph
+            .add_audio_effect(Gain("gain".to_string(), 2.0))
+            .unwrap();
+        graph.add_audio_link("src", "gain").unwrap();
+        graph.add_audio_link("gain", A_OUT_NODE).unwrap();
+        graph.prepare(48000.0, 16).unwrap();
+        assert!(!graph.is_dirty());
+
+        let old = graph
+            .replace_node(
+                "gain",
+                BoxedNode::audio_effect(Gain("gain2".to_string(), 4.0)),
+            )
+            .unwrap();
+        assert_eq!(old.name(), "gain");
+        assert!(graph.is_dirty());
+        assert!(run(&mut graph, 0.0).iter().all(|f| *f == (0.0, 0.0)));
+        graph.prepare(48000.0, 16).unwrap();
+        assert!(run(&mut graph, 0.0).iter().all(|f| *f == (2.0, 2.0)));
+
+        assert!(matches!(
+            graph.replace_node(
+                "gain2",
+                BoxedNode::audio_source(Constant("c".to_string(), 1.0))
+            ),
+            Err(GraphError::LinkError(LinkError::InvalidLinkTarget(_)))
+        ));
+        assert!(matches!(
+            graph.remove_audio_link("gain", A_OUT_NODE),
+            Err(LinkError::UnknownLink(_, _))
+        ));
+        graph.remove_audio_link("gain2", A_OUT_NODE).unwrap();
+        graph.remove_node("src").unwrap();
+        assert!(graph.topology.audio_links.is_empty());
+        assert!(matches!(
+            graph.remove_node("src"),
+            Err(GraphError::UnknownName(_))
+        ));
+        graph.prepare(48000.0, 16).unwrap();
+        assert!(run(&mut graph, 0.0).iter().all(|f| *f == (0.0, 0.0)));
+    }
+
+    #[test]
+    fn feedback() {
+        let mut graph = Graph::new("test");
+        graph
+            .add_audio_source(Constant("src".to_string(), 1.0))
+            .unwrap();
+        graph
+            .add_audio_effect(Gain("acc".to_string(), 1.0))
+            .unwrap();
+        graph.add_audio_link("src", "acc").unwrap();
+        graph.add_audio_feedback_link("acc", "acc").unwrap();
+        graph.add_audio_link("acc", A_OUT_NODE).unwrap();
+        assert!(matches!(
+            graph.add_audio_feedback_link("acc", A_OUT_NODE),
+            Err(LinkError::InvalidLinkTarget(_))
+        ));
+        graph.prepare(48000.0, 16).unwrap();
+        assert!(run(&mut graph, 0.0).iter().all(|f| *f == (1.0, 1.0)));
+        assert!(run(&mut graph, 0.0).iter().all(|f| *f == (2.0, 2.0)));
+
+        graph.set_feedback_delay(FeedbackDelay::Frames(4)).unwrap();
+        let out = run(&mut graph, 0.0);
+        assert_eq!(out[0], (3.0, 3.0));
+        assert_eq!(out[4], (4.0, 4.0));
+        assert_eq!(out[15], (6.0, 6.0));
+
+        // 每次处理的帧数不是延迟的整数倍时, 反馈延迟仍是固定的帧数
+        assert!(matches!(
+            graph.set_feedback_delay(FeedbackDelay::Frames(0)),
+            Err(GraphError::InvalidFeedbackDelay)
+        ));
+        graph.set_feedback_delay(FeedbackDelay::Frames(5)).unwrap();
+        graph.prepare(48000.0, 16).unwrap();
+        let playhead = PlayHead {
+            upper: 4,
+            lower: 4,
+            div: 4,
+            samples_per_quarter: 0.0,
+            samples_from_last_bar: 0.0,
+        };
+        let mut out = vec![];
+        for _ in 0..4 {
+            let audio_in = AudioBuffer::new(3);
+            let mut audio_out = AudioBuffer::new(3);
+            graph.process(
+                &playhead,
+                3,
+                audio_in.next_n_frames_ref(3),
+                audio_out.next_n_frames_mut(3),
+                &MessageBuffer(vec![], 3),
+            );
+            out.extend(audio_out.next_n_frames_ref(3).into_iter().map(|(l, _)| *l));
+        }
+        let expected = (0..12).map(|t| (1 + t / 5) as f64).collect::<Vec<_>>();
+        assert_eq!(out, expected);
+
+        graph.remove_audio_feedback_link("acc", "acc").unwrap();
+        graph.prepare(48000.0, 16).unwrap();
+        assert!(run(&mut graph, 0.0).iter().all(|f| *f == (1.0, 1.0)));
+    }
+
+    #[test]
+    fn tap() {
+        let mut graph = Graph::new("test");
+        graph
+            .add_audio_source(Constant("src".to_string(), 0.5))
+            .unwrap();
+        graph
+            .add_audio_effect(Gain("gain".to_string(), 2.0))
+            .unwrap();
+        graph
+            .add_midi_effect(Split("split".to_string(), 1))
+            .unwrap();
+        graph
+            .add_audio_source(Constant("probe".to_string(), 0.5))
+            .unwrap();
+        graph.add_audio_link("src", "gain").unwrap();
+        graph.add_audio_link("gain", A_OUT_NODE).unwrap();
+        graph.add_audio_link(A_IN_NODE, A_OUT_NODE).unwrap();
+        let mut src = graph.add_tap("probe").unwrap();
+        let mut out = graph.add_tap(A_OUT_NODE).unwrap();
+        let mut split = graph.add_tap("split").unwrap();
+        assert!(matches!(
+            graph.add_tap("probe"),
+            Err(TapError::TappedTarget(_))
+        ));
+        assert!(matches!(
+            graph.add_tap("src"),
+            Err(TapError::TapIsLinked(from, to)) if from == "src" && to == "gain"
+        ));
+        assert!(matches!(
+            graph.add_audio_link("probe", "gain"),
+            Err(LinkError::LinkIsTapped(from, to)) if from == "probe" && to == "gain"
+        ));
+        assert!(matches!(
+            graph.add_message_link("split", "gain"),
+            Err(LinkError::LinkIsTapped(_, _))
+        ));
+        assert!(matches!(
+            graph.add_tap("none"),
+            Err(TapError::UnknownName(_))
+        ));
+        graph.prepare(48000.0, 16).unwrap();
+        run(&mut graph, 1.0);
+        run(&mut graph, 1.0);
+
+        let mut frames = vec![0.0; 128];
+        assert_eq!(src.channels(), 2);
+        assert_eq!(src.available(), 32);
+        assert_eq!(src.read(&mut frames), 32);
+        assert!(frames[..64].iter().all(|f| *f == 0.5));
+        assert_eq!(out.read(&mut frames), 32);
+        assert!(frames[..64].iter().all(|f| *f == 2.0));
+        assert_eq!(src.read(&mut frames), 0);
+        assert!(split.try_recv_message().is_none());
+
+        // 控制信息按监听开始后经过的帧数计时
+        graph.add_message_link(A_IN_NODE, "split").unwrap();
+        graph.prepare(48000.0, 16).unwrap();
+        let message = Message {
+            addr: vec![],
+            value: MessageValue::Float(FloatMessage {
+                name: "gain".to_string(),
+                value: 1.5,
+            }),
+        };
+        let long = Message {
+            addr: vec![],
+            value: MessageValue::Float(FloatMessage {
+                name: "g".repeat(TAP_NAME_LEN + 1),
+                value: 1.0,
+            }),
+        };
+        run_with(1.0, |playhead, frames, audio_in, audio_out, _| {
+            let message_in = MessageBuffer(vec![(3, message.clone()), (4, long)], 16);
+            graph.process(playhead, frames, audio_in, audio_out, &message_in)
+        });
+        assert_eq!(split.try_recv_message(), Some((35, message)));
+        assert!(split.try_recv_message().is_none());
+        // 名字太长的控制信息放不进预先分配的空间, 被丢弃并计数
+        assert_eq!(split.dropped_messages(), 1);
+
+        graph.remove_node("probe").unwrap();
+        assert!(matches!(
+            graph.remove_tap("probe"),
+            Err(TapError::UnknownName(_))
+        ));
+        graph.remove_tap(A_OUT_NODE).unwrap();
+    }
+
+    /// 收到名为 gain 的控制信息后改变增益
+    pub(super) struct Volume(pub String, pub f64);
+
+    impl AudioEffectNode for Volume {
+        fn name(&self) -> String {
+            self.0.clone()
+        }
+
+        fn prepare(&mut self, _sample_rate: f64) -> AudioEffectDesc {
+            AudioEffectDesc {
+                audio_in: 1,
+                channels: 2,
+                latency: 0,
+                parameters: vec![Parameter {
+                    addr: vec![],
+                    range: ParaRange::Float(FloatRange {
+                        name: "gain".to_string(),
+                        min: 0.0,
+                        max: 4.0,
+                        default: 1.0,
+                        taper: Taper::Linear,
+                        unit: Unit::None,
+                        step: 0.0,
+                    }),
+                }],
+            }
+        }
+
+        fn process(
+            &mut self,
+            _playhead: &PlayHead,
+            _frames: usize,
+            audio_in: Vec<AudioBufferRef>,
+            audio_out: AudioBufferMut,
+            message_in: &MessageBuffer,
+        ) {
+            for (_, msg) in message_in {
+                if let MessageValue::Float(FloatMessage { name, value }) = &msg.value {
+                    if name == "gain" && msg.addr.is_empty() {
+                        self.1 = *value;
+                    }
+                }
+            }
+            for ((li, ri), (lo, ro)) in audio_in[0].iter().zip(audio_out) {
+                *lo += li * self.1;
+                *ro += ri * self.1;
+            }
+        }
+    }
+
+    #[test]
+    fn subgraph() {
+        let sub = || {
+            let mut sub = Graph::new("sub");
+            sub.add_audio_effect(Volume("vol".to_string(), 1.0))
+                .unwrap();
+            sub.add_audio_effect(Volume("all".to_string(), 1.0))
+                .unwrap();
+            sub.add_audio_link(A_IN_NODE, "vol").unwrap();
+            sub.add_audio_link("vol", "all").unwrap();
+            sub.add_audio_link("all", A_OUT_NODE).unwrap();
+            sub.add_message_link(A_IN_NODE, "all").unwrap();
+            sub
+        };
+
+        let mut graph = Graph::new("test");
+        graph
+            .add_audio_source(Constant("src".to_string(), 0.5))
+            .unwrap();
+        // 采样率未知时不能编译子图
+        assert!(matches!(
+            graph.add_subgraph(sub()),
+            Err(GraphError::NotPrepared(name)) if name == "test"
+        ));
+        graph.prepare(48000.0, 16).unwrap();
+        graph.add_subgraph(sub()).unwrap();
+        graph.add_audio_link("src", "sub").unwrap();
+        graph.add_audio_link("sub", A_OUT_NODE).unwrap();
+        graph.prepare(48000.0, 16).unwrap();
+        assert!(run(&mut graph, 0.0).iter().all(|f| *f == (0.5, 0.5)));
+
+        let gain = |addr: &[&str], value| Message {
+            addr: addr.iter().map(|a| a.to_string()).collect(),
+            value: MessageValue::Float(FloatMessage {
+                name: "gain".to_string(),
+                value,
+            }),
+        };
+        let mut messages = MessageBuffer(vec![], 16);
+        messages.add(0, gain(&["vol", "sub"], 2.0));
+        messages.add(0, gain(&["sub"], 3.0));
+        messages.add(0, gain(&["vol"], 100.0));
+        let out = run_with(0.0, |playhead, frames, audio_in, audio_out, _| {
+            graph.process(playhead, frames, audio_in, audio_out, &messages)
+        });
+        assert!(out.iter().all(|f| *f == (3.0, 3.0)));
+
+        // 有误的子图加入时报错, 绕过检查加入的子图只输出静音
+        let invalid = || {
+            let mut sub = Graph::new("invalid");
+            sub.add_audio_effect(Volume("vol".to_string(), 1.0))
+                .unwrap();
+            sub.add_audio_link(A_IN_NODE, "vol:1").unwrap();
+            sub
+        };
+        assert!(matches!(
+            graph.add_subgraph(invalid()),
+            Err(GraphError::LinkError(LinkError::UnknownPort(_)))
+        ));
+        graph.add_audio_effect(invalid()).unwrap();
+        graph.add_audio_link("src", "invalid").unwrap();
+        graph.add_audio_link("invalid", A_OUT_NODE).unwrap();
+        graph.prepare(48000.0, 16).unwrap();
+        assert!(run(&mut graph, 0.0).iter().all(|f| *f == (3.0, 3.0)));
+    }
+
+    #[test]
+    fn max_block_size() {
+        let mut graph = Graph::new("test");
+        graph
+            .add_audio_source(Constant("src".to_string(), 0.5))
+            .unwrap();
+        graph
+            .add_audio_effect(Volume("vol".to_string(), 1.0))
+            .unwrap();
+        graph.add_audio_link("src", "vol").unwrap();
+        graph.add_audio_link("vol", A_OUT_NODE).unwrap();
+        graph.prepare(48000.0, 4).unwrap();
+        assert_eq!(graph.max_block_size(), 4);
+
+        // 16 帧的块拆成 4 个小块, 消息在所在的小块开始时生效
+        let gain = |value| Message {
+            addr: vec!["vol".to_string()],
+            value: MessageValue::Float(FloatMessage {
+                name: "gain".to_string(),
+                value,
+            }),
+        };
+        let mut messages = MessageBuffer(vec![], 16);
+        messages.add(6, gain(2.0));
+        messages.add(13, gain(3.0));
+        let out = run_with(0.0, |playhead, frames, audio_in, audio_out, _| {
+            graph.process(playhead, frames, audio_in, audio_out, &messages)
+        });
+        assert!(out[..4].iter().all(|f| *f == (0.5, 0.5)));
+        assert!(out[4..12].iter().all(|f| *f == (1.0, 1.0)));
+        assert!(out[12..].iter().all(|f| *f == (1.5, 1.5)));
+    }
+
+    /// 输出收到的 level 值, 在消息的时间点改变
+    struct Level(String, f64);
+
+    impl SplitRender for Level {
+        fn handle_message(&mut self, message: &Message) {
+            if let MessageValue::Float(FloatMessage { value, .. }) = &message.value {
+                self.1 = *value;
+            }
+        }
+    }
+
+    impl AudioSourceNode for Level {
+        fn name(&self) -> String {
+            self.0.clone()
+        }
+
+        fn prepare(&mut self, _sample_rate: f64) -> AudioSourceDesc {
+            AudioSourceDesc {
+                channels: 2,
+                latency: 0,
+                parameters: vec![],
+            }
+        }
+
+        fn process(
+            &mut self,
+            _playhead: &PlayHead,
+            frames: usize,
+            mut audio_out: AudioBufferMut,
+            message_in: &MessageBuffer,
+        ) {
+            split_render(self, frames, message_in, |node, range| {
+                for (l, r) in audio_out.slice_mut(range) {
+                    *l = node.1;
+                    *r = node.1;
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn split_render_at_messages() {
+        let mut graph = Graph::new("test");
+        graph
+            .add_audio_source(Level("level".to_string(), 0.0))
+            .unwrap();
+        graph.add_audio_link("level", A_OUT_NODE).unwrap();
+        graph.prepare(48000.0, 16).unwrap();
+
+        let level = |value| Message {
+            addr: vec!["level".to_string()],
+            value: MessageValue::Float(FloatMessage {
+                name: "level".to_string(),
+                value,
+            }),
+        };
+        let mut messages = MessageBuffer(vec![], 16);
+        messages.add(5, level(1.0));
+        messages.add(11, level(2.0));
+        let out = run_with(0.0, |playhead, frames, audio_in, audio_out, _| {
+            graph.process(playhead, frames, audio_in, audio_out, &messages)
+        });
+        assert!(out[..5].iter().all(|f| *f == (0.0, 0.0)));
+        assert!(out[5..11].iter().all(|f| *f == (1.0, 1.0)));
+        assert!(out[11..].iter().all(|f| *f == (2.0, 2.0)));
+    }
+
+    #[test]
+    fn parameters() {
+        let mut sub = Graph::new("sub");
+        sub.add_audio_effect(Volume("vol".to_string(), 1.0))
+            .unwrap();
+        sub.add_audio_link(A_IN_NODE, "vol").unwrap();
+        sub.add_audio_link("vol", A_OUT_NODE).unwrap();
+        let mut graph = Graph::new("test");
+        graph
+            .add_audio_source(Constant("src".to_string(), 0.5))
+            .unwrap();
+        graph.add_audio_effect(sub).unwrap();
+        graph.add_audio_link("src", "sub").unwrap();
+        graph.add_audio_link("sub", A_OUT_NODE).unwrap();
+        graph.prepare(48000.0, 16).unwrap();
+
+        let addr = vec!["vol".to_string(), "sub".to_string()];
+        let tree = graph.parameters();
+        assert!(tree.get(&addr, "gain").is_some());
+        assert_eq!(tree.node("sub").count(), 1);
+        assert_eq!(tree.node("vol").count(), 0);
+
+        let gain = |name: &str, value| Message {
+            addr: addr.clone(),
+            value: MessageValue::Float(FloatMessage {
+                name: name.to_string(),
+                value,
+            }),
+        };
+        assert!(tree.check(&gain("gain", 2.0)).is_ok());
+        assert!(matches!(
+            tree.check(&gain("gain", 10.0)),
+            Err(ParameterError::OutOfRange(path, _)) if path == "/sub/vol/gain"
+        ));
+        assert!(matches!(
+            tree.check(&gain("level", 1.0)),
+            Err(ParameterError::UnknownParameter(_))
+        ));
+        let mut msg = gain("gain", -1.0);
+        tree.clamp(&mut msg).unwrap();
+        assert_eq!(msg, gain("gain", 0.0));
+        assert_eq!(tree.parse(&addr, "gain", "1.5").unwrap(), gain("gain", 1.5));
+        assert!(matches!(
+            tree.parse(&addr, "gain", "loud"),
+            Err(ParameterError::InvalidText(_, text)) if text == "loud"
+        ));
+
+        // 处理时超出范围的取值被限制到范围内
+        let mut messages = MessageBuffer(vec![], 16);
+        messages.add(0, gain("gain", 10.0));
+        let out = run_with(0.0, |playhead, frames, audio_in, audio_out, _| {
+            graph.process(playhead, frames, audio_in, audio_out, &messages)
+        });
+        assert!(out.iter().all(|f| *f == (2.0, 2.0)));
+
+        // 未知的参数和类型不符的控制信息被丢弃并计数
+        let mut messages = MessageBuffer(vec![], 16);
+        messages.add(0, gain("level", 3.0));
+        messages.add(
+            0,
+            Message {
+                addr: addr.clone(),
+                value: MessageValue::Enum(EnumMessage {
+                    name: "gain".to_string(),
+                    value: 0,
+                }),
+            },
+        );
+        let out = run_with(0.0, |playhead, frames, audio_in, audio_out, _| {
+            graph.process(playhead, frames, audio_in, audio_out, &messages)
+        });
+        assert!(out.iter().all(|f| *f == (2.0, 2.0)));
+        assert_eq!(graph.rejected_messages(), 2);
+
+        // 范围无效的参数在 prepare 时报告, 不会在音频线程限制取值时 panic
+        let float = |min, max, default| {
+            ParaRange::Float(FloatRange {
+                name: "level".to_string(),
+                min,
+                max,
+                default,
+                taper: Taper::Linear,
+                unit: Unit::None,
+                step: 0.0,
+            })
+        };
+        let invalid = [
+            float(1.0, 0.0, 0.5),
+            float(0.0, f64::NAN, 0.5),
+            float(0.0, 1.0, 2.0),
+            ParaRange::Enum(EnumRange {
+                name: "level".to_string(),
+                options: vec![],
+                default: 0,
+            }),
+        ];
+        for range in invalid {
+            graph.remove_node("src").unwrap();
+            graph
+                .add_audio_source(Ranged("src".to_string(), range))
+                .unwrap();
+            graph.add_audio_link("src", "sub").unwrap();
+            assert!(matches!(
+                graph.prepare(48000.0, 16),
+                Err(GraphError::InvalidParameter(path)) if path == "/src/level"
+            ));
+        }
+        if let ParaRange::Float(range) = float(1.0, 0.0, 0.5) {
+            assert_eq!(range.clamp(0.7), 0.0);
+        }
+    }
+
+    /// 声明一个给定范围的参数, 输出静音
+    struct Ranged(String, ParaRange);
+
+    impl AudioSourceNode for Ranged {
+        fn name(&self) -> String {
+            self.0.clone()
+        }
+
+        fn prepare(&mut self, _sample_rate: f64) -> AudioSourceDesc {
+            AudioSourceDesc {
+                parameters: vec![Parameter {
+                    addr: vec![],
+                    range: self.1.clone(),
+                }],
+                ..Default::default()
+            }
+        }
+
+        fn process(
+            &mut self,
+            _playhead: &PlayHead,
+            _frames: usize,
+            _audio_out: AudioBufferMut,
+            _message_in: &MessageBuffer,
+        ) {
+        }
+    }
+
+    #[test]
+    fn preset() {
+        let build = || {
+            let mut sub = Graph::new("sub");
+            sub.add_audio_effect(Volume("vol".to_string(), 1.0))
+                .unwrap();
+            sub.add_audio_link(A_IN_NODE, "vol").unwrap();
+            sub.add_audio_link("vol", A_OUT_NODE).unwrap();
+            let mut graph = Graph::new("test");
+            graph
+                .add_audio_source(Constant("src".to_string(), 0.5))
+                .unwrap();
+            graph.add_audio_effect(sub).unwrap();
+            graph
+                .add_audio_effect(Volume("all".to_string(), 1.0))
+                .unwrap();
+            graph.add_audio_link("src", "sub").unwrap();
+            graph.add_audio_link("sub", "all").unwrap();
+            graph.add_audio_link("all", A_OUT_NODE).unwrap();
+            graph.prepare(48000.0, 16).unwrap();
+            graph
+        };
+        let gain = |addr: &[&str], value| Message {
+            addr: addr.iter().map(|a| a.to_string()).collect(),
+            value: MessageValue::Float(FloatMessage {
+                name: "gain".to_string(),
+                value,
+            }),
+        };
+
+        let mut graph = build();
+        assert_eq!(
+            graph.save_preset().to_string(),
+            "float /all/gain 1\nfloat /sub/vol/gain 1\n"
+        );
+        let mut messages = MessageBuffer(vec![], 16);
+        messages.add(0, gain(&["vol", "sub"], 2.0));
+        messages.add(3, gain(&["all"], 10.0));
+        run_with(0.0, |playhead, frames, audio_in, audio_out, _| {
+            graph.process(playhead, frames, audio_in, audio_out, &messages)
+        });
+        // 保存的是限制到范围内的取值
+        let text = graph.save_preset().to_string();
+        assert_eq!(text, "float /all/gain 4\nfloat /sub/vol/gain 2\n");
+
+        let mut restored = build();
+        restored.load_preset(&text.parse().unwrap()).unwrap();
+        assert_eq!(restored.save_preset().to_string(), text);
+        let out = run_with(0.0, |playhead, frames, audio_in, audio_out, message_in| {
+            restored.process(playhead, frames, audio_in, audio_out, message_in)
+        });
+        assert!(out.iter().all(|f| *f == (4.0, 4.0)));
+
+        let unknown = "# 注释\n\nfloat /none/gain 1".parse().unwrap();
+        assert!(matches!(
+            restored.load_preset(&unknown),
+            Err(GraphError::UnknownName(name)) if name == "none"
+        ));
+        assert!(matches!(
+            "float /all/gain\nenum /all/gain loud".parse::<NodeState>(),
+            Err(PresetError::InvalidLine(1, _))
+        ));
+    }
+
+    #[test]
+    fn description() {
+        let json = r#"{
+            "name": "test",
+            "nodes": [
+                { "type": "test.constant", "name": "src", "args": { "value": 0.5 } },
+                {
+                    "type": "rarity.graph",
+                    "name": "sub",
+                    "args": {
+                        "nodes": [
+                            { "type": "test.volume", "name": "vol", "parameters": { "gain": 2 } }
+                        ],
+                        "audio_links": [["A_IN_NODE", "vol"], ["vol", "A_OUT_NODE"]]
+                    },
+                    "parameters": { "vol/gain": "1.5" }
+                }
+            ],
+            "audio_links": [["src", "sub"], ["sub", "A_OUT_NODE"]]
+        }"#;
+        let mut factory = |node: &NodeDescription| match node.type_id.as_str() {
+            "test.constant" => Ok(BoxedNode::audio_source(Constant(
+                node.name.clone(),
+                node.args["value"].as_f64().unwrap(),
+            ))),
+            "test.volume" => Ok(BoxedNode::audio_effect(Volume(node.name.clone(), 1.0))),
+            _ => Err(DescriptionError::UnknownType(node.type_id.clone())),
+        };
+        let desc = GraphDescription::from_json(json).unwrap();
+        let mut graph = Graph::from_description(&desc, 48000.0, 16, &mut factory).unwrap();
+        // 外层给出的初始值在子图自己的初始值之后设置
+        let out = run_with(0.0, |playhead, frames, audio_in, audio_out, message_in| {
+            graph.process(playhead, frames, audio_in, audio_out, message_in)
+        });
+        assert!(out.iter().all(|f| *f == (0.75, 0.75)));
+
+        let saved = graph.to_description().unwrap();
+        assert_eq!(
+            saved.nodes[1].parameters["vol/gain"],
+            ParameterValue::Number(1.5)
+        );
+        assert_eq!(saved.audio_links, desc.audio_links);
+        let reloaded = GraphDescription::from_json(&saved.to_json().unwrap()).unwrap();
+        assert_eq!(reloaded, saved);
+        let restored = Graph::from_description(&reloaded, 48000.0, 16, &mut factory).unwrap();
+        assert_eq!(restored.save_preset(), graph.save_preset());
+
+        let mut bad = desc.clone();
+        bad.nodes[0].type_id = "test.none".to_string();
+        assert!(matches!(
+            Graph::from_description(&bad, 48000.0, 16, &mut factory),
+            Err(DescriptionError::UnknownType(t)) if t == "test.none"
+        ));
+        let mut bad = desc.clone();
+        bad.nodes[1]
+            .parameters
+            .insert("vol/gain".to_string(), ParameterValue::Number(10.0));
+        assert!(matches!(
+            Graph::from_description(&bad, 48000.0, 16, &mut factory),
+            Err(DescriptionError::ParameterError(
+                ParameterError::OutOfRange(..)
+            ))
+        ));
+        let mut bad = desc.clone();
+        bad.channels = 0;
+        assert!(matches!(
+            Graph::from_description(&bad, 48000.0, 16, &mut factory),
+            Err(DescriptionError::InvalidArgs(name, _)) if name == "test"
+        ));
+        let mut bad = desc.clone();
+        bad.feedback_delay = Some(0);
+        assert!(matches!(
+            Graph::from_description(&bad, 48000.0, 16, &mut factory),
+            Err(DescriptionError::InvalidArgs(..))
+        ));
+    }
+
+    #[test]
+    fn registry() {
+        let volume = || {
+            NodeInfo::new::<Volume>(
+                "test.registry.volume",
+                "Volume",
+                NodeType::AudioEffect,
+                serde_json::json!({ "gain": 1.0 }),
+                |desc| {
+                    let gain = desc.args["gain"].as_f64().ok_or("gain is not a number")?;
+                    Ok(BoxedNode::audio_effect(Volume(desc.name.clone(), gain)))
+                },
+            )
+        };
+        register_node(volume()).unwrap();
+        assert!(matches!(
+            register_node(volume()),
+            Err(RegistryError::RepeatedType(id)) if id == "test.registry.volume"
+        ));
+        assert!(registered_nodes()
+            .iter()
+            .any(|i| i.id == "test.registry.volume" && i.node_type == NodeType::AudioEffect));
+        assert_eq!(
+            node_id_of::<Volume>().as_deref(),
+            Some("test.registry.volume")
+        );
+
+        let desc = GraphDescription::from_json(
+            r#"{
+                "nodes": [
+                    { "type": "test.registry.volume", "name": "vol", "args": { "gain": 3 } },
+                    { "type": "test.registry.volume", "name": "all" }
+                ],
+                "audio_links": [["A_IN_NODE", "vol"], ["vol", "all"], ["all", "A_OUT_NODE"]]
+            }"#,
+        )
+        .unwrap();
+        let mut graph = Graph::from_description(&desc, 48000.0, 16, &mut create_node).unwrap();
+        let out = run_with(0.5, |playhead, frames, audio_in, audio_out, message_in| {
+            graph.process(playhead, frames, audio_in, audio_out, message_in)
+        });
+        assert!(out.iter().all(|f| *f == (1.5, 1.5)));
+
+        // 省略的构造参数取默认值
+        let node = create_node(&desc.nodes[1]).unwrap();
+        assert_eq!(
+            node.spec().unwrap().args,
+            serde_json::json!({ "gain": 1.0 })
+        );
+        let mut bad = desc.nodes[1].clone();
+        bad.args = serde_json::json!({ "gain": "loud" });
+        assert!(matches!(
+            create_node(&bad),
+            Err(DescriptionError::InvalidArgs(name, _)) if name == "all"
+        ));
+        assert!(unregister_node("test.registry.volume").is_some());
+        assert!(matches!(
+            create_node(&desc.nodes[1]),
+            Err(DescriptionError::UnknownType(_))
+        ));
+    }
+
+    /// 两个输入分别原样送到两个输出
+    struct Router(String);
+
+    impl MultiAudioNode for Router {
+        fn name(&self) -> String {
+            self.0.clone()
+        }
+
+        fn prepare(&mut self, _sample_rate: f64) -> MultiAudioDesc {
+            MultiAudioDesc {
+                audio_in: vec!["main".to_string(), "side".to_string()],
+                audio_out: vec!["a".to_string(), "b".to_string()],
+                channels: 2,
+                latency: 0,
+                parameters: vec![],
+            }
+        }
+
+        fn process(
+            &mut self,
+            _playhead: &PlayHead,
+            _frames: usize,
+            audio_in: Vec<AudioBufferRef>,
+            audio_out: Vec<AudioBufferMut>,
+            _message_in: &MessageBuffer,
+        ) {
+            for (audio_in, audio_out) in audio_in.into_iter().zip(audio_out) {
+                for ((li, ri), (lo, ro)) in audio_in.iter().zip(audio_out) {
+                    *lo += li;
+                    *ro += ri;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ports() {
+        let mut graph = Graph::new("test");
+        graph
+            .add_audio_source(Constant("src".to_string(), 0.5))
+            .unwrap();
+        graph.add_multi_audio(Router("router".to_string())).unwrap();
+        graph
+            .add_audio_effect(Gain("gain".to_string(), 2.0))
+            .unwrap();
+        assert!(matches!(
+            graph.add_audio_source(Constant("a:b".to_string(), 0.5)),
+            Err(GraphError::InvalidName(_))
+        ));
+        graph.add_audio_link("src", "router:main").unwrap();
+        graph.add_audio_link(A_IN_NODE, "router:1").unwrap();
+        graph.add_audio_link("router:a", A_OUT_NODE).unwrap();
+        graph.add_audio_link("router:b", "gain").unwrap();
+        graph.add_audio_link("gain", A_OUT_NODE).unwrap();
+        graph.prepare(48000.0, 16).unwrap();
+        assert!(run(&mut graph, 1.0).iter().all(|f| *f == (2.5, 2.5)));
+
+        // 按名字和序号指定的同一端口是同一个连接
+        assert!(matches!(
+            graph.add_audio_link("router:1", "gain:0"),
+            Err(LinkError::DuplicateLink(from, to)) if from == "router:1" && to == "gain"
+        ));
+        graph.remove_audio_link("router:1", "gain:0").unwrap();
+        graph.add_audio_link("router:b", "gain").unwrap();
+        assert!(matches!(
+            graph.add_audio_link("router:c", "gain"),
+            Err(LinkError::UnknownPort(port)) if port == "router:c"
+        ));
+        assert!(matches!(
+            graph.add_audio_link("src", "gain:1"),
+            Err(LinkError::UnknownPort(_))
+        ));
+
+        // 还没有准备的节点在准备时检查端口
+        graph
+            .add_audio_effect(Gain("gain2".to_string(), 2.0))
+            .unwrap();
+        graph.add_audio_link("src", "gain2:1").unwrap();
+        assert!(matches!(
+            graph.prepare(48000.0, 16),
+            Err(GraphError::LinkError(LinkError::UnknownPort(_)))
+        ));
+        graph.remove_audio_link("src", "gain2:1").unwrap();
+        graph
+            .add_audio_effect(Gain("gain3".to_string(), 2.0))
+            .unwrap();
+        graph.add_audio_link("src", "gain3").unwrap();
+        graph.add_audio_link("src", "gain3:0").unwrap();
+        assert!(matches!(
+            graph.prepare(48000.0, 16),
+            Err(GraphError::LinkError(LinkError::DuplicateLink(_, _)))
+        ));
+        graph.remove_audio_link("src", "gain3:0").unwrap();
+        graph.prepare(48000.0, 16).unwrap();
+        assert_eq!(graph.topology.audio_links.len(), 6);
+    }
+
+    /// 用第二个输入压低第一个输入
+    struct Duck(String);
+
+    impl AudioEffectNode for Duck {
+        fn name(&self) -> String {
+            self.0.clone()
+        }
+
+        fn prepare(&mut self, _sample_rate: f64) -> AudioEffectDesc {
+            AudioEffectDesc {
+                audio_in: 2,
+                channels: 2,
+                latency: 0,
+                parameters: vec![],
+            }
+        }
+
+        fn process(
+            &mut self,
+            _playhead: &PlayHead,
+            _frames: usize,
+            audio_in: Vec<AudioBufferRef>,
+            audio_out: AudioBufferMut,
+            _message_in: &MessageBuffer,
+        ) {
+            let frames = audio_in[0].iter().zip(audio_in[1].iter());
+            for (((li, ri), (ls, rs)), (lo, ro)) in frames.zip(audio_out) {
+                *lo += li * (1.0 - ls);
+                *ro += ri * (1.0 - rs);
+            }
+        }
+    }
+
+    #[test]
+    fn sidechain() {
+        let mut graph = Graph::new("test");
+        graph
+            .add_audio_source(Constant("src".to_string(), 1.0))
+            .unwrap();
+        graph.add_audio_effect(Duck("duck".to_string())).unwrap();
+        graph.add_audio_link("src", "duck").unwrap();
+        graph.add_audio_link("duck", A_OUT_NODE).unwrap();
+        graph.prepare(48000.0, 16).unwrap();
+        assert!(run(&mut graph, 0.25).iter().all(|f| *f == (1.0, 1.0)));
+
+        graph.add_audio_link(A_IN_NODE, "duck:1").unwrap();
+        graph.prepare(48000.0, 16).unwrap();
+        assert!(run(&mut graph, 0.25).iter().all(|f| *f == (0.75, 0.75)));
+
+        graph.remove_audio_link("src", "duck").unwrap();
+        graph.remove_audio_link(A_IN_NODE, "duck:1").unwrap();
+        graph.add_audio_link("src", "duck:0").unwrap();
+        graph.add_audio_feedback_link("src", "duck:1").unwrap();
+        graph.prepare(48000.0, 16).unwrap();
+        assert!(run(&mut graph, 0.25).iter().all(|f| *f == (1.0, 1.0)));
+        assert!(run(&mut graph, 0.25).iter().all(|f| *f == (0.0, 0.0)));
+    }
+
+    #[test]
+    fn parallel() {
+        let build = || {
+            let mut graph = Graph::new("test");
+            for i in 0..8 {
+                let (src, gain) = (format!("src{}", i), format!("gain{}", i));
+                graph
+                    .add_audio_source(Constant(src.clone(), i as f64))
+                    .unwrap();
+                graph.add_audio_effect(Gain(gain.clone(), 0.5)).unwrap();
+                graph.add_audio_link(&src, &gain).unwrap();
+                graph.add_audio_link(A_IN_NODE, &gain).unwrap();
+            }
+            graph.add_audio_effect(Duck("mix".to_string())).unwrap();
+            for i in 0..8 {
+                graph.add_audio_link(&format!("gain{}", i), "mix").unwrap();
+            }
+            graph.add_audio_feedback_link("gain7", "mix:1").unwrap();
+            graph.add_audio_link("mix", A_OUT_NODE).unwrap();
+            graph.add_audio_link("gain0", A_OUT_NODE).unwrap();
+            graph.prepare(48000.0, 16).unwrap();
+            graph
+        };
+        let mut serial = build();
+        let mut parallel = build();
+        assert!(parallel.tasks.len() > 2);
+        // 各个分支的源节点之间没有依赖
+        assert_eq!(
+            parallel.tasks.iter().filter(|t| t.deps.is_empty()).count(),
+            9
+        );
+        // 每个工作线程启动时调用一次初始化, 可以在这里设置实时优先级
+        let started = Arc::new(AtomicUsize::new(0));
+        let init = {
+            let started = started.clone();
+            move |_| {
+                started.fetch_add(1, Ordering::SeqCst);
+            }
+        };
+        let executor = Executor::with_init(3, init).unwrap();
+        assert!(parallel.set_executor(executor).is_none());
+        for _ in 0..50 {
+            assert_eq!(run(&mut serial, 0.01), run(&mut parallel, 0.01));
+        }
+        assert_eq!(parallel.take_executor().unwrap().threads(), 3);
+        while started.load(Ordering::SeqCst) < 3 {
+            std::thread::yield_now();
+        }
+        assert_eq!(Executor::new(0).unwrap().threads(), 0);
+    }
+
+    /// 把输入延迟固定帧数后输出, 并如实声明延迟
+    struct Lookahead(String, VecDeque<(f64, f64)>);
+
+    impl AudioEffectNode for Lookahead {
+        fn name(&self) -> String {
+            self.0.clone()
+        }
+
+        fn prepare(&mut self, _sample_rate: f64) -> AudioEffectDesc {
+            AudioEffectDesc {
+                audio_in: 1,
+                channels: 2,
+                latency: self.1.len(),
+                parameters: vec![],
+            }
+        }
+
+        fn process(
+            &mut self,
+            _playhead: &PlayHead,
+            _frames: usize,
+            audio_in: Vec<AudioBufferRef>,
+            audio_out: AudioBufferMut,
+            _message_in: &MessageBuffer,
+        ) {
+            for ((li, ri), (lo, ro)) in audio_in[0].iter().zip(audio_out) {
+                self.1.push_back((*li, *ri));
+                let (l, r) = self.1.pop_front().unwrap();
+                *lo += l;
+                *ro += r;
+            }
+        }
+    }
+
+    #[test]
+    fn latency() {
+        let mut graph = Graph::new("test");
+        graph
+            .add_audio_effect(Lookahead("la".to_string(), VecDeque::from([(0.0, 0.0); 5])))
+            .unwrap();
+        graph
+            .add_audio_effect(Lookahead("lb".to_string(), VecDeque::from([(0.0, 0.0); 2])))
+            .unwrap();
+        graph
+            .add_audio_effect(Gain("dry".to_string(), 1.0))
+            .unwrap();
+        graph.add_audio_link(A_IN_NODE, "la").unwrap();
+        graph.add_audio_link(A_IN_NODE, "lb").unwrap();
+        graph.add_audio_link(A_IN_NODE, "dry").unwrap();
+        graph.add_audio_link("la", A_OUT_NODE).unwrap();
+        graph.add_audio_link("lb", A_OUT_NODE).unwrap();
+        graph.add_audio_link("dry", A_OUT_NODE).unwrap();
+        graph.prepare(48000.0, 16).unwrap();
+        assert_eq!(graph.latency(), 5);
+
+        let out = run(&mut graph, 1.0);
+        assert!(out[..5].iter().all(|f| *f == (0.0, 0.0)));
+        assert!(out[5..].iter().all(|f| *f == (3.0, 3.0)));
+        assert!(run(&mut graph, 1.0).iter().all(|f| *f == (3.0, 3.0)));
+
+        // 侧链输入同样对齐
+        graph.remove_audio_link("dry", A_OUT_NODE).unwrap();
+        graph.add_audio_effect(Duck("duck".to_string())).unwrap();
+        graph.add_audio_link("la", "duck").unwrap();
+        graph.add_audio_link("dry", "duck:1").unwrap();
+        graph.add_audio_link("duck", A_OUT_NODE).unwrap();
+        graph.prepare(48000.0, 16).unwrap();
+        assert_eq!(graph.latency(), 5);
+        assert!(graph
+            .sequences
+            .iter()
+            .any(|op| matches!(op, Operation::AudioDelay(_, _, _, 5))));
+        assert!(graph
+            .sequences
+            .iter()
+            .any(|op| matches!(op, Operation::AudioDelay(_, _, _, 3))));
+    }
+
+    /// 每帧各声道分别输出给定的值, 声道数即值的个数
+    struct Fill(String, Vec<f64>);
+
+    impl AudioSourceNode for Fill {
+        fn name(&self) -> String {
+            self.0.clone()
+        }
+
+        fn prepare(&mut self, _sample_rate: f64) -> AudioSourceDesc {
+            AudioSourceDesc {
+                channels: self.1.len(),
+                latency: 0,
+                parameters: vec![],
+            }
+        }
+
+        fn process(
+            &mut self,
+            _playhead: &PlayHead,
+            _frames: usize,
+            mut audio_out: AudioBufferMut,
+            _message_in: &MessageBuffer,
+        ) {
+            for (channel, value) in audio_out.channels_mut().zip(&self.1) {
+                for sample in channel {
+                    *sample += value;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn channels() {
+        let mut graph = Graph::new("test");
+        graph
+            .add_audio_source(Fill("mono".to_string(), vec![1.0]))
+            .unwrap();
+        graph.add_audio_link("mono", A_OUT_NODE).unwrap();
+        graph.prepare(48000.0, 16).unwrap();
+        assert!(run(&mut graph, 0.0).iter().all(|f| *f == (1.0, 1.0)));
+
+        // 5.1 下混为双声道后再经过双声道的节点
+        graph.remove_node("mono").unwrap();
+        graph
+            .add_audio_source(Fill(
+                "surround".to_string(),
+                vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0],
+            ))
+            .unwrap();
+        graph
+            .add_audio_effect(Gain("gain".to_string(), 1.0))
+            .unwrap();
+        graph.add_audio_link("surround", "gain").unwrap();
+        graph.add_audio_link("gain", A_OUT_NODE).unwrap();
+        graph.prepare(48000.0, 16).unwrap();
+        let k = std::f64::consts::FRAC_1_SQRT_2;
+        let out = run(&mut graph, 0.0);
+        assert!(out.iter().all(|f| *f == (1.0 + k * 8.0, 2.0 + k * 9.0)));
+        assert_eq!(
+            graph
+                .sequences
+                .iter()
+                .filter(|op| matches!(op, Operation::AudioMix(..)))
+                .count(),
+            1
+        );
+
+        // 单声道的图, 输入下混, 输出上混
+        graph.set_channels(1);
+        assert!(graph.is_dirty());
+        graph.remove_node("surround").unwrap();
+        graph
+            .add_audio_source(Fill("stereo".to_string(), vec![1.0, 3.0]))
+            .unwrap();
+        graph.add_audio_link("stereo", A_OUT_NODE).unwrap();
+        graph.add_audio_link(A_IN_NODE, A_OUT_NODE).unwrap();
+        graph.prepare(48000.0, 16).unwrap();
+        assert!(run(&mut graph, 0.5).iter().all(|f| *f == (2.5, 2.5)));
+
+        graph
+            .add_audio_source(Fill("empty".to_string(), vec![]))
+            .unwrap();
+        assert!(matches!(
+            graph.prepare(48000.0, 16),
+            Err(GraphError::NoChannels(name)) if name == "empty"
+        ));
+    }
+
+    /// 以 f32 处理的增益
+    struct Gain32(String, f32);
+
+    impl AudioEffectNode<f32> for Gain32 {
+        fn name(&self) -> String {
+            self.0.clone()
+        }
+
+        fn prepare(&mut self, _sample_rate: f64) -> AudioEffectDesc {
+            AudioEffectDesc {
+                audio_in: 1,
+                channels: 2,
+                latency: 0,
+                parameters: vec![],
+            }
+        }
+
+        fn process(
+            &mut self,
+            _playhead: &PlayHead,
+            _frames: usize,
+            audio_in: Vec<AudioBufferRef<f32>>,
+            audio_out: AudioBufferMut<f32>,
+            _message_in: &MessageBuffer,
+        ) {
+            for ((li, ri), (lo, ro)) in audio_in[0].iter().zip(audio_out) {
+                *lo += li * self.1;
+                *ro += ri * self.1;
+            }
+        }
+    }
+
+    #[test]
+    fn sample_formats() {
+        let converts = |graph: &Graph| {
+            graph
+                .sequences
+                .iter()
+                .filter(|op| matches!(op, Operation::AudioConvert(..)))
+                .count()
+        };
+        let mut graph = Graph::new("test");
+        graph
+            .add_audio_source(Constant("src".to_string(), 0.5))
+            .unwrap();
+        graph
+            .add_audio_effect(Gain32("gain".to_string(), 0.5))
+            .unwrap();
+        graph.add_audio_link("src", "gain").unwrap();
+        graph.add_audio_link("gain", A_OUT_NODE).unwrap();
+        graph.prepare(48000.0, 16).unwrap();
+        assert_eq!(converts(&graph), 2);
+        assert!(run(&mut graph, 0.0).iter().all(|f| *f == (0.25, 0.25)));
+
+        // 整张图以 f32 运行, 只有 f64 的来源需要转换
+        graph.set_sample_format(SampleFormat::F32);
+        graph.add_audio_link(A_IN_NODE, A_OUT_NODE).unwrap();
+        graph.prepare(48000.0, 16).unwrap();
+        assert_eq!(converts(&graph), 1);
+        let playhead = PlayHead {
+            upper: 4,
+            lower: 4,
+            div: 4,
+            samples_per_quarter: 0.0,
+            samples_from_last_bar: 0.0,
+        };
+        let mut audio_in = AudioBuffer::<f32>::with_channels(2, 16);
+        for (l, r) in audio_in.next_n_frames_mut(16) {
+            *l = 1.0;
+            *r = 1.0;
+        }
+        let mut audio_out = AudioBuffer::<f32>::with_channels(2, 16);
+        graph.process(
+            &playhead,
+            16,
+            audio_in.next_n_frames_ref(16),
+            audio_out.next_n_frames_mut(16),
+            &MessageBuffer(vec![], 16),
+        );
+        assert!(audio_out
+            .next_n_frames_ref(16)
+            .iter()
+            .all(|(l, r)| *l == 1.25 && *r == 1.25));
+    }
+
+    /// 按声道切片处理, 检查交给节点的缓冲总是连续的
+    struct Planar(String);
+
+    impl AudioEffectNode for Planar {
+        fn name(&self) -> String {
+            self.0.clone()
+        }
+
+        fn prepare(&mut self, _sample_rate: f64) -> AudioEffectDesc {
+            AudioEffectDesc {
+                audio_in: 1,
+                channels: 2,
+                latency: 0,
+                parameters: vec![],
+            }
+        }
+
+        fn process(
+            &mut self,
+            _playhead: &PlayHead,
+            _frames: usize,
+            audio_in: Vec<AudioBufferRef>,
+            mut audio_out: AudioBufferMut,
+            _message_in: &MessageBuffer,
+        ) {
+            assert!(audio_in[0].is_contiguous() && audio_out.is_contiguous());
+            for (c, out) in audio_out.channels_mut().enumerate() {
+                for (o, i) in out.iter_mut().zip(audio_in[0].channel(c)) {
+                    *o += i;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn planar() {
+        let mut graph = Graph::new("test");
+        graph
+            .add_audio_source(Constant("src".to_string(), 1.0))
+            .unwrap();
+        graph.add_audio_effect(Planar("acc".to_string())).unwrap();
+        graph.add_audio_link("src", "acc").unwrap();
+        graph.add_audio_feedback_link("acc", "acc").unwrap();
+        graph.add_audio_link("acc", A_OUT_NODE).unwrap();
+        graph.set_feedback_delay(FeedbackDelay::Frames(4)).unwrap();
+        graph.prepare(48000.0, 16).unwrap();
+
+        // 外部缓冲交错排列, 块会跨过缓冲末尾
+        let playhead = PlayHead {
+            upper: 4,
+            lower: 4,
+            div: 4,
+            samples_per_quarter: 0.0,
+            samples_from_last_bar: 0.0,
+        };
+        let audio_in = AudioBuffer::<f64>::with_layout(BufferLayout::Interleaved, 2, 24);
+        let mut audio_out = AudioBuffer::<f64>::with_layout(BufferLayout::Interleaved, 2, 24);
+        for _ in 0..3 {
+            audio_out.forward(13);
+            audio_out.next_n_frames_mut(16).clear();
+            graph.process(
+                &playhead,
+                16,
+                audio_in.next_n_frames_ref(16),
+                audio_out.next_n_frames_mut(16),
+                &MessageBuffer(vec![], 16),
+            );
+        }
+        let out = audio_out.next_n_frames_ref(16);
+        assert!(!out.is_contiguous());
+        for i in 0..16 {
+            let expected = (32 + i) / 4 + 1;
+            assert_eq!(out.get(i, 0), expected as f64);
+            assert_eq!(out.get(i, 1), expected as f64);
+        }
+    }
+
+    #[test]
+    fn planar_with_delay() {
+        let mut graph = Graph::new("test");
+        graph.add_audio_effect(Planar("a".to_string())).unwrap();
+        graph.add_audio_effect(Planar("b".to_string())).unwrap();
+        graph
+            .add_audio_effect(Lookahead("c".to_string(), VecDeque::from([(0.0, 0.0); 5])))
+            .unwrap();
+        graph.add_audio_link(A_IN_NODE, "a").unwrap();
+        graph.add_audio_link("a", "b").unwrap();
+        graph.add_audio_link("b", A_OUT_NODE).unwrap();
+        graph.add_audio_link(A_IN_NODE, "c").unwrap();
+        graph.add_audio_link("c", A_OUT_NODE).unwrap();
+        graph.prepare(48000.0, 16).unwrap();
+
+        // 延迟线不能占用其它节点用过的缓冲, 否则它们拿到的块会随延迟线前移而不连续
+        let out = run(&mut graph, 1.0);
+        assert!(out[..5].iter().all(|f| *f == (0.0, 0.0)));
+        assert!(out[5..].iter().all(|f| *f == (2.0, 2.0)));
+        for _ in 0..3 {
+            assert!(run(&mut graph, 1.0).iter().all(|f| *f == (2.0, 2.0)));
+        }
+    }
+}
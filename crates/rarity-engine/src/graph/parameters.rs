@@ -0,0 +1,235 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    Message, MessageValue, NodeState, ParaRange, ParameterError, ParameterResult, RawDesc,
+};
+
+/// 图中所有节点的参数, 按控制信息的地址和参数名查找
+///
+/// 参数的地址为节点声明的 `Parameter.addr` 后接节点名, 与发给该参数的控制信息地址相同,
+/// 例如子图 sub 中节点 vol 的参数地址为 `[vol, sub]`.
+#[derive(Clone, Debug, Default)]
+pub struct ParameterTree(BTreeMap<Vec<String>, Vec<ParaRange>>);
+
+impl ParameterTree {
+    pub(crate) fn new(node_descs: &HashMap<String, RawDesc>) -> Self {
+        let mut tree = BTreeMap::<Vec<String>, Vec<ParaRange>>::new();
+        for (name, desc) in node_descs {
+            for p in &desc.parameters {
+                let mut addr = p.addr.clone();
+                addr.push(name.clone());
+                tree.entry(addr).or_default().push(p.range.clone());
+            }
+        }
+        Self(tree)
+    }
+
+    pub fn get(&self, addr: &[String], name: &str) -> Option<&ParaRange> {
+        self.0.get(addr)?.iter().find(|r| r.name() == name)
+    }
+
+    /// 所有参数的地址和取值范围, 按地址排序
+    pub fn iter(&self) -> impl Iterator<Item = (&[String], &ParaRange)> {
+        self.0
+            .iter()
+            .flat_map(|(addr, ranges)| ranges.iter().map(move |r| (addr.as_slice(), r)))
+    }
+
+    /// 节点 node 及其内部(节点为子图时)的所有参数
+    pub fn node<'a>(
+        &'a self,
+        node: &'a str,
+    ) -> impl Iterator<Item = (&'a [String], &'a ParaRange)> {
+        self.iter()
+            .filter(move |(addr, _)| addr.last().is_some_and(|n| n == node))
+    }
+
+    /// 检查控制信息的参数存在且取值在范围内, MIDI 信息不检查
+    pub fn check(&self, message: &Message) -> ParameterResult<()> {
+        let in_range = match (self.find(message)?, &message.value) {
+            (Some(ParaRange::Float(range)), MessageValue::Float(msg)) => range.contains(msg.value),
+            (Some(ParaRange::Enum(range)), MessageValue::Enum(msg)) => range.contains(msg.value),
+            _ => true,
+        };
+        match (in_range, &message.value) {
+            (true, _) => Ok(()),
+            (false, MessageValue::Float(msg)) => Err(ParameterError::OutOfRange(
+                path(&message.addr, &msg.name),
+                msg.value,
+            )),
+            (false, MessageValue::Enum(msg)) => Err(ParameterError::OutOfRange(
+                path(&message.addr, &msg.name),
+                msg.value as f64,
+            )),
+            (false, MessageValue::Midi(_)) => unreachable!(),
+        }
+    }
+
+    /// 把超出范围的取值限制到范围内, 参数不存在或类型不符时返回错误
+    pub fn clamp(&self, message: &mut Message) -> ParameterResult<()> {
+        let range = self.find(message)?;
+        clamp_value(range, &mut message.value);
+        Ok(())
+    }
+
+    /// 限制发给节点的控制信息的取值, 不分配内存, 供音频线程使用
+    ///
+    /// 节点在控制信息的地址上声明了参数时, 参数不存在或类型不符返回 false, 控制信息应当丢弃;
+    /// 没有声明参数的地址(例如图本身和子图本身)收到的控制信息以及 MIDI 信息原样保留.
+    pub(crate) fn admit(&self, message: &mut Message) -> bool {
+        let name = match &message.value {
+            MessageValue::Midi(_) => return true,
+            MessageValue::Float(msg) => &msg.name,
+            MessageValue::Enum(msg) => &msg.name,
+        };
+        let Some(ranges) = self.0.get(&message.addr) else {
+            return true;
+        };
+        let range = ranges.iter().find(|r| r.name() == name);
+        match (range, &message.value) {
+            (Some(ParaRange::Float(_)), MessageValue::Float(_))
+            | (Some(ParaRange::Enum(_)), MessageValue::Enum(_)) => {
+                clamp_value(range, &mut message.value);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// 按参数的单位或选项标签解析文本, 得到发给该参数的控制信息
+    pub fn parse(&self, addr: &[String], name: &str, text: &str) -> ParameterResult<Message> {
+        let range = self
+            .get(addr, name)
+            .ok_or_else(|| ParameterError::UnknownParameter(path(addr, name)))?;
+        let value = range
+            .parse(text)
+            .ok_or_else(|| ParameterError::InvalidText(path(addr, name), text.to_string()))?;
+        Ok(Message {
+            addr: addr.to_vec(),
+            value,
+        })
+    }
+
+    /// 控制信息对应的参数, 类型与参数相符; MIDI 信息不对应参数, 返回 None
+    fn find(&self, message: &Message) -> ParameterResult<Option<&ParaRange>> {
+        let name = match &message.value {
+            MessageValue::Midi(_) => return Ok(None),
+            MessageValue::Float(msg) => &msg.name,
+            MessageValue::Enum(msg) => &msg.name,
+        };
+        match (self.get(&message.addr, name), &message.value) {
+            (None, _) => Err(ParameterError::UnknownParameter(path(&message.addr, name))),
+            (Some(range @ ParaRange::Float(_)), MessageValue::Float(_))
+            | (Some(range @ ParaRange::Enum(_)), MessageValue::Enum(_)) => Ok(Some(range)),
+            _ => Err(ParameterError::TypeMismatch(path(&message.addr, name))),
+        }
+    }
+}
+
+/// 每个参数最后收到的取值, 没有收到过时为默认值
+///
+/// 与 [`ParameterTree`] 的参数一一对应, 记录和转移取值都不分配内存, 可以在音频线程进行.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ParameterValues(BTreeMap<Vec<String>, Vec<MessageValue>>);
+
+impl ParameterValues {
+    pub fn new(tree: &ParameterTree) -> Self {
+        let values = tree
+            .0
+            .iter()
+            .map(|(addr, ranges)| {
+                let values = ranges.iter().map(ParaRange::default_value).collect();
+                (addr.clone(), values)
+            })
+            .collect();
+        Self(values)
+    }
+
+    /// 记录发给已知参数的控制信息的取值, 取值先被限制到参数的范围内
+    pub fn record(&mut self, tree: &ParameterTree, message: &Message) {
+        let range = match &message.value {
+            MessageValue::Midi(_) => return,
+            MessageValue::Float(msg) => tree.get(&message.addr, &msg.name),
+            MessageValue::Enum(msg) => tree.get(&message.addr, &msg.name),
+        };
+        let Some(value) = self.find_mut(&message.addr, &message.value) else {
+            return;
+        };
+        match (&mut *value, &message.value) {
+            (MessageValue::Float(v), MessageValue::Float(msg)) => v.value = msg.value,
+            (MessageValue::Enum(v), MessageValue::Enum(msg)) => v.value = msg.value,
+            _ => {}
+        }
+        clamp_value(range, value);
+    }
+
+    /// 从旧的取值中取回地址和名字都相同的参数的取值
+    pub fn adopt(&mut self, old: &ParameterValues) {
+        for (addr, values) in self.0.iter_mut() {
+            let Some(old) = old.0.get(addr) else {
+                continue;
+            };
+            for value in values.iter_mut() {
+                let Some(old) = old.iter().find(|o| same_parameter(o, value)) else {
+                    continue;
+                };
+                match (value, old) {
+                    (MessageValue::Float(v), MessageValue::Float(o)) => v.value = o.value,
+                    (MessageValue::Enum(v), MessageValue::Enum(o)) => v.value = o.value,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// 节点 node 及其内部(节点为子图时)所有参数的取值, 地址不含节点名
+    pub fn node(&self, node: &str) -> NodeState {
+        let mut state = NodeState::new();
+        for (addr, values) in &self.0 {
+            if addr.last().is_some_and(|n| n == node) {
+                let addr = addr[..addr.len() - 1].to_vec();
+                state.0.extend(values.iter().map(|value| Message {
+                    addr: addr.clone(),
+                    value: value.clone(),
+                }));
+            }
+        }
+        state
+    }
+
+    fn find_mut(&mut self, addr: &[String], value: &MessageValue) -> Option<&mut MessageValue> {
+        self.0
+            .get_mut(addr)?
+            .iter_mut()
+            .find(|v| same_parameter(v, value))
+    }
+}
+
+/// 两个取值是否属于同一个参数: 类型相同且名字相同
+fn same_parameter(a: &MessageValue, b: &MessageValue) -> bool {
+    match (a, b) {
+        (MessageValue::Float(a), MessageValue::Float(b)) => a.name == b.name,
+        (MessageValue::Enum(a), MessageValue::Enum(b)) => a.name == b.name,
+        _ => false,
+    }
+}
+
+fn clamp_value(range: Option<&ParaRange>, value: &mut MessageValue) {
+    match (range, value) {
+        (Some(ParaRange::Float(range)), MessageValue::Float(msg)) => {
+            msg.value = range.clamp(msg.value)
+        }
+        (Some(ParaRange::Enum(range)), MessageValue::Enum(msg)) => {
+            msg.value = range.clamp(msg.value)
+        }
+        _ => {}
+    }
+}
+
+/// 参数的完整路径, 与 OSC 地址相同, 从外到内以 / 分隔
+pub(crate) fn path(addr: &[String], name: &str) -> String {
+    addr.iter()
+        .rev()
+        .chain([&name.to_string()])
+        .fold(String::new(), |path, a| path + "/" + a)
+}
@@ -0,0 +1,63 @@
+use crate::{
+    AudioBufferMut, AudioBufferRef, AudioEffectDesc, AudioEffectNode, MessageBuffer, NodeState,
+    PlayHead,
+};
+
+use super::Graph;
+
+/// 图可以作为音频效果节点加入另一张图
+///
+/// A_IN_NODE 和 A_OUT_NODE 分别是子图的输入和输出. 控制信息的地址从后往前逐层匹配,
+/// 发给子图中节点 node 的控制信息地址为 `[.., node, 子图名]`;
+/// 地址只有子图名的控制信息经 A_IN_NODE 的控制连接转发.
+/// 子图中所有节点的参数都会以同样的方式加上节点名作为地址, 子图的延迟即子图输出的总延迟,
+/// 声道数即子图输入输出的声道数. 子图与外层以 f64 交换音频, 内部仍按各自的样本格式处理.
+/// 子图沿用自己的最大块长, 外层传入更长的块时在子图内部拆分.
+/// 子图的状态即子图的预设. 用 `add_subgraph` 加入的子图在加入时检查, 其余子图编译失败时输出静音.
+impl AudioEffectNode for Graph {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn prepare(&mut self, sample_rate: f64) -> AudioEffectDesc {
+        // 编译失败的子图保持未准备的状态, 处理时只输出静音
+        let _ = Graph::prepare(self, sample_rate, self.max_block_size);
+        let mut names = self.node_descs.keys().collect::<Vec<_>>();
+        names.sort();
+        let parameters = names
+            .into_iter()
+            .flat_map(|name| {
+                self.node_descs[name].parameters.iter().map(|p| {
+                    let mut p = p.clone();
+                    p.addr.push(name.clone());
+                    p
+                })
+            })
+            .collect();
+        AudioEffectDesc {
+            audio_in: 1,
+            channels: self.channels,
+            latency: self.latency,
+            parameters,
+        }
+    }
+
+    fn process(
+        &mut self,
+        playhead: &PlayHead,
+        frames: usize,
+        audio_in: Vec<AudioBufferRef>,
+        audio_out: AudioBufferMut,
+        message_in: &MessageBuffer,
+    ) {
+        Graph::process(self, playhead, frames, audio_in[0], audio_out, message_in);
+    }
+
+    fn save_state(&self) -> Option<NodeState> {
+        Some(self.save_preset())
+    }
+
+    fn load_state(&mut self, state: &NodeState) -> bool {
+        self.load_preset(state).is_ok()
+    }
+}
@@ -0,0 +1,380 @@
+use std::collections::HashMap;
+
+use crate::{
+    port_index, tap_channel, GraphError, GraphResult, LinkError, LinkResult, NodeType, RawDesc,
+    TapError, TapReceiver, TapResult, TapSender,
+};
+
+use super::{compiler::find_path, Link, A_IN_NODE, A_OUT_NODE};
+
+/// A_IN_NODE 和 A_OUT_NODE 只有一个没有名字的端口
+pub(crate) static IO_PORTS: [String; 1] = [String::new()];
+
+pub(super) fn endpoint(node: &str, port: &Option<String>) -> String {
+    match port {
+        Some(port) => format!("{}:{}", node, port),
+        None => node.to_string(),
+    }
+}
+
+/// 两端相同的连接已经存在, 扇入和扇出本身都是允许的
+fn duplicate(link: &Link) -> LinkError {
+    LinkError::DuplicateLink(endpoint(&link.0, &link.2), endpoint(&link.1, &link.3))
+}
+
+/// 按原样或解析端口后查找音频连接, 端口不存在的连接只能按原样找到
+fn find_link(
+    links: &[Link],
+    from: &str,
+    to: &str,
+    node_descs: &HashMap<String, RawDesc>,
+) -> LinkResult<usize> {
+    let link = Link::parse(from, to);
+    let mut resolved = link.clone();
+    let resolved = resolved.resolve(node_descs).is_ok().then_some(resolved);
+    links
+        .iter()
+        .position(|l| *l == link || Some(l) == resolved.as_ref())
+        .ok_or_else(|| LinkError::UnknownLink(from.to_string(), to.to_string()))
+}
+
+/// 图的拓扑结构: 节点名字, 节点类型以及它们之间的连接
+///
+/// 只记录结构, 不持有节点本身, 因此可以在非音频线程复制一份进行编辑.
+#[derive(Clone, Default)]
+pub(crate) struct Topology {
+    pub node_types: HashMap<String, NodeType>,
+    pub audio_links: Vec<Link>,
+    pub message_links: Vec<Link>,
+    /// 反馈连接, 目标读取来源上一个块的输出, 不参与拓扑排序
+    pub feedback_links: Vec<Link>,
+    /// 被监听的节点, 可以是 A_IN_NODE 和 A_OUT_NODE
+    pub taps: HashMap<String, TapSender>,
+}
+
+impl Topology {
+    pub fn check_name(&self, name: &str) -> GraphResult<()> {
+        if name == A_IN_NODE || name == A_OUT_NODE {
+            Err(GraphError::ReservedName(name.to_string()))
+        } else if name.contains(':') {
+            Err(GraphError::InvalidName(name.to_string()))
+        } else if self.node_types.contains_key(name) {
+            Err(GraphError::RepeatedName(name.to_string()))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn add_node(&mut self, name: &str, node_type: NodeType) -> GraphResult<()> {
+        self.check_name(name)?;
+        self.node_types.insert(name.to_string(), node_type);
+        Ok(())
+    }
+
+    /// 移除节点以及与它相关的所有连接
+    pub fn remove_node(&mut self, name: &str) -> GraphResult<()> {
+        if name == A_IN_NODE || name == A_OUT_NODE {
+            return Err(GraphError::ReservedName(name.to_string()));
+        }
+        self.node_types
+            .remove(name)
+            .ok_or_else(|| GraphError::UnknownName(name.to_string()))?;
+        self.audio_links.retain(|l| l.0 != name && l.1 != name);
+        self.message_links.retain(|l| l.0 != name && l.1 != name);
+        self.feedback_links.retain(|l| l.0 != name && l.1 != name);
+        self.taps.remove(name);
+        Ok(())
+    }
+
+    /// 用 new_name 的新节点替换 name, 原有的连接转移到新节点上
+    pub fn replace_node(
+        &mut self,
+        name: &str,
+        new_name: &str,
+        node_type: NodeType,
+    ) -> GraphResult<()> {
+        if name == A_IN_NODE || name == A_OUT_NODE {
+            return Err(GraphError::ReservedName(name.to_string()));
+        }
+        if !self.node_types.contains_key(name) {
+            return Err(GraphError::UnknownName(name.to_string()));
+        }
+        if new_name != name {
+            self.check_name(new_name)?;
+        }
+        for Link(from, to, ..) in self.audio_links.iter().chain(&self.feedback_links) {
+            if from == name && !node_type.has_audio_out() {
+                return Err(LinkError::InvalidLinkSource(new_name.to_string()).into());
+            }
+            if to == name && !node_type.has_audio_in() {
+                return Err(LinkError::InvalidLinkTarget(new_name.to_string()).into());
+            }
+        }
+        for Link(from, ..) in &self.message_links {
+            if from == name && !node_type.has_message_out() {
+                return Err(LinkError::InvalidLinkSource(new_name.to_string()).into());
+            }
+        }
+        self.node_types.remove(name);
+        self.node_types.insert(new_name.to_string(), node_type);
+        for Link(from, to, ..) in self
+            .audio_links
+            .iter_mut()
+            .chain(&mut self.message_links)
+            .chain(&mut self.feedback_links)
+        {
+            if from == name {
+                *from = new_name.to_string();
+            }
+            if to == name {
+                *to = new_name.to_string();
+            }
+        }
+        if let Some(tap) = self.taps.remove(name) {
+            self.taps.insert(new_name.to_string(), tap);
+        }
+        Ok(())
+    }
+
+    pub fn add_audio_link(
+        &mut self,
+        mut link: Link,
+        node_descs: &HashMap<String, RawDesc>,
+    ) -> LinkResult<()> {
+        link.resolve(node_descs)?;
+        let Link(from, to, ..) = &link;
+        if from == A_OUT_NODE {
+            return Err(LinkError::InvalidLinkSource(from.to_string()));
+        }
+        if to == A_IN_NODE {
+            return Err(LinkError::InvalidLinkTarget(to.to_string()));
+        }
+        if from != A_IN_NODE && !self.node_type(from)?.has_audio_out() {
+            return Err(LinkError::InvalidLinkSource(from.to_string()));
+        }
+        if to != A_OUT_NODE && !self.node_type(to)?.has_audio_in() {
+            return Err(LinkError::InvalidLinkTarget(to.to_string()));
+        }
+        self.check_tapped(from, to)?;
+        if self.audio_links.contains(&link) {
+            return Err(duplicate(&link));
+        }
+        self.check_cycle(from, to)?;
+        self.audio_links.push(link);
+        Ok(())
+    }
+
+    /// 来自 A_IN_NODE 的控制连接接收发给图本身(地址为空)的控制信息
+    pub fn add_message_link(&mut self, from: &str, to: &str) -> LinkResult<()> {
+        if from == A_OUT_NODE {
+            return Err(LinkError::InvalidLinkSource(from.to_string()));
+        }
+        if to == A_IN_NODE || to == A_OUT_NODE {
+            return Err(LinkError::InvalidLinkTarget(to.to_string()));
+        }
+        if from != A_IN_NODE && !self.node_type(from)?.has_message_out() {
+            return Err(LinkError::InvalidLinkSource(from.to_string()));
+        }
+        self.node_type(to)?;
+        self.check_tapped(from, to)?;
+        let link = Link(from.to_string(), to.to_string(), None, None);
+        if self.message_links.contains(&link) {
+            return Err(duplicate(&link));
+        }
+        self.check_cycle(from, to)?;
+        self.message_links.push(link);
+        Ok(())
+    }
+
+    /// 反馈连接可以成环, 也可以连回自身, 但不能连接 A_IN_NODE 和 A_OUT_NODE
+    pub fn add_audio_feedback_link(
+        &mut self,
+        mut link: Link,
+        node_descs: &HashMap<String, RawDesc>,
+    ) -> LinkResult<()> {
+        link.resolve(node_descs)?;
+        let Link(from, to, ..) = &link;
+        if from == A_IN_NODE || from == A_OUT_NODE {
+            return Err(LinkError::InvalidLinkSource(from.to_string()));
+        }
+        if to == A_IN_NODE || to == A_OUT_NODE {
+            return Err(LinkError::InvalidLinkTarget(to.to_string()));
+        }
+        if !self.node_type(from)?.has_audio_out() {
+            return Err(LinkError::InvalidLinkSource(from.to_string()));
+        }
+        if !self.node_type(to)?.has_audio_in() {
+            return Err(LinkError::InvalidLinkTarget(to.to_string()));
+        }
+        self.check_tapped(from, to)?;
+        if self.feedback_links.contains(&link) {
+            return Err(duplicate(&link));
+        }
+        self.feedback_links.push(link);
+        Ok(())
+    }
+
+    pub fn remove_audio_link(
+        &mut self,
+        from: &str,
+        to: &str,
+        node_descs: &HashMap<String, RawDesc>,
+    ) -> LinkResult<()> {
+        let index = find_link(&self.audio_links, from, to, node_descs)?;
+        self.audio_links.remove(index);
+        Ok(())
+    }
+
+    pub fn remove_message_link(&mut self, from: &str, to: &str) -> LinkResult<()> {
+        let index = self
+            .message_links
+            .iter()
+            .position(|l| l.0 == from && l.1 == to)
+            .ok_or_else(|| LinkError::UnknownLink(from.to_string(), to.to_string()))?;
+        self.message_links.remove(index);
+        Ok(())
+    }
+
+    pub fn remove_audio_feedback_link(
+        &mut self,
+        from: &str,
+        to: &str,
+        node_descs: &HashMap<String, RawDesc>,
+    ) -> LinkResult<()> {
+        let index = find_link(&self.feedback_links, from, to, node_descs)?;
+        self.feedback_links.remove(index);
+        Ok(())
+    }
+
+    pub fn add_tap(&mut self, name: &str) -> TapResult<TapReceiver> {
+        if name != A_IN_NODE && name != A_OUT_NODE && !self.node_types.contains_key(name) {
+            return Err(TapError::UnknownName(name.to_string()));
+        }
+        if self.taps.contains_key(name) {
+            return Err(TapError::TappedTarget(name.to_string()));
+        }
+        let linked = self
+            .audio_links
+            .iter()
+            .chain(&self.message_links)
+            .chain(&self.feedback_links)
+            .find(|l| l.0 == name);
+        if let Some(Link(from, to, ..)) = linked {
+            return Err(TapError::TapIsLinked(from.clone(), to.clone()));
+        }
+        let (tx, rx) = tap_channel();
+        self.taps.insert(name.to_string(), tx);
+        Ok(rx)
+    }
+
+    pub fn remove_tap(&mut self, name: &str) -> TapResult<()> {
+        self.taps
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| TapError::UnknownName(name.to_string()))
+    }
+
+    /// 根据节点的描述检查被监听的节点是否有输出
+    pub fn check_taps(&self, node_descs: &HashMap<String, RawDesc>) -> TapResult<()> {
+        for name in self.taps.keys() {
+            if name == A_IN_NODE || name == A_OUT_NODE {
+                continue;
+            }
+            let desc = node_descs
+                .get(name)
+                .ok_or_else(|| TapError::UnknownName(name.clone()))?;
+            if desc.audio_out.is_empty() && desc.message_out == 0 {
+                return Err(TapError::InvalidTapTarget(name.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// 节点的输出只能被连接或者被监听, 不能同时使用
+    fn check_tapped(&self, from: &str, to: &str) -> LinkResult<()> {
+        if self.taps.contains_key(from) {
+            return Err(LinkError::LinkIsTapped(from.to_string(), to.to_string()));
+        }
+        Ok(())
+    }
+
+    fn node_type(&self, name: &str) -> LinkResult<NodeType> {
+        self.node_types
+            .get(name)
+            .copied()
+            .ok_or_else(|| LinkError::UnknownName(name.to_string()))
+    }
+
+    /// 检查新增 from -> to 的连接后是否成环
+    fn check_cycle(&self, from: &str, to: &str) -> LinkResult<()> {
+        if !self.node_types.contains_key(from) || !self.node_types.contains_key(to) {
+            return Ok(());
+        }
+        if from == to {
+            return Err(LinkError::Cycle(vec![from.to_string(), to.to_string()]));
+        }
+        let links = self.links();
+        match find_path(&links, to, from) {
+            Some(path) => Err(LinkError::Cycle(
+                [from.to_string()].into_iter().chain(path).collect(),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    pub fn links(&self) -> Vec<&Link> {
+        self.audio_links.iter().chain(&self.message_links).collect()
+    }
+
+    /// 解析添加时还没有描述的节点的端口, 解析后重复的连接是错误
+    pub fn resolve_links(&mut self, node_descs: &HashMap<String, RawDesc>) -> LinkResult<()> {
+        for links in [&mut self.audio_links, &mut self.feedback_links] {
+            for i in 0..links.len() {
+                links[i].resolve(node_descs)?;
+                if links[..i].contains(&links[i]) {
+                    return Err(duplicate(&links[i]));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 根据节点的描述检查所有连接
+    pub fn check_links(&self, node_descs: &HashMap<String, RawDesc>) -> LinkResult<()> {
+        let desc = |name: &str| {
+            node_descs
+                .get(name)
+                .ok_or_else(|| LinkError::UnknownName(name.to_string()))
+        };
+        for link in self.audio_links.iter().chain(&self.feedback_links) {
+            let Link(from, to, from_port, to_port) = link;
+            let outputs = match from == A_IN_NODE {
+                true => &IO_PORTS[..],
+                false => &desc(from)?.audio_out,
+            };
+            if outputs.is_empty() {
+                return Err(LinkError::InvalidLinkSource(from.clone()));
+            }
+            if port_index(outputs, from_port.as_deref()).is_none() {
+                return Err(LinkError::UnknownPort(endpoint(from, from_port)));
+            }
+            let inputs = match to == A_OUT_NODE {
+                true => &IO_PORTS[..],
+                false => &desc(to)?.audio_in,
+            };
+            if inputs.is_empty() {
+                return Err(LinkError::InvalidLinkTarget(to.clone()));
+            }
+            if port_index(inputs, to_port.as_deref()).is_none() {
+                return Err(LinkError::UnknownPort(endpoint(to, to_port)));
+            }
+        }
+        for Link(from, to, ..) in &self.message_links {
+            if from != A_IN_NODE && desc(from)?.message_out == 0 {
+                return Err(LinkError::InvalidLinkSource(from.clone()));
+            }
+            desc(to)?;
+        }
+        Ok(())
+    }
+}
@@ -1,3 +1,10 @@
+//! # 节点
+//!
+//! 图交给节点的音频缓冲总是平面排列且连续的, 可以用 `channel` 和 `channels_mut` 按声道取出切片.
+//!
+//! 节点的 `save_state` 默认返回 None, 此时图按声明的参数保存它们最后收到的取值.
+//! `load_state` 恢复 `save_state` 保存的状态, 返回 false 时图会把状态作为控制信息在下一个块的开头发给节点.
+
 mod buffer;
 pub use buffer::*;
 mod node;
@@ -10,3 +17,7 @@ mod error;
 pub use error::*;
 mod message_collector;
 pub use message_collector::*;
+mod tap;
+pub use tap::*;
+mod plugin;
+pub use plugin::*;
@@ -1,4 +1,12 @@
 mod raw;
 pub use raw::*;
+mod registry;
+pub use registry::*;
 mod parameter;
 pub use parameter::*;
+mod split;
+pub use split::*;
+mod smoothed;
+pub use smoothed::*;
+mod state;
+pub use state::*;
@@ -1,3 +1,5 @@
+use crate::{EnumMessage, FloatMessage, MessageValue};
+
 #[derive(Clone, Debug)]
 pub struct Parameter {
     pub addr: Vec<String>,
@@ -16,11 +18,328 @@ pub struct FloatRange {
     pub min: f64,
     pub max: f64,
     pub default: f64,
+    /// 归一化值 0..1 与实际值之间的映射
+    pub taper: Taper,
+    pub unit: Unit,
+    /// 取值的步长, 从 min 开始计, 为 0 时连续取值
+    pub step: f64,
 }
 
 #[derive(Clone, Debug)]
 pub struct EnumRange {
     pub name: String,
-    pub len: usize,
+    /// 每个选项的标签, 控制信息中以下标表示选项
+    pub options: Vec<String>,
     pub default: usize,
 }
+
+/// 归一化值到实际值的映射曲线
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum Taper {
+    #[default]
+    Linear,
+    /// 归一化值均匀对应倍数, 适合频率, min 需大于 0
+    Logarithmic,
+    /// 指数曲线, 参数为曲率, 大于 0 时低端更精细, 适合时间
+    Exponential(f64),
+}
+
+/// 参数的单位, 决定显示和解析的格式
+#[derive(Clone, Debug, PartialEq, Default)]
+pub enum Unit {
+    #[default]
+    None,
+    Hertz,
+    Decibel,
+    Millisecond,
+    Second,
+    /// 取值为 0..1 的比例, 显示为百分数
+    Percent,
+    Custom(String),
+}
+
+impl ParaRange {
+    pub fn name(&self) -> &str {
+        match self {
+            ParaRange::Float(range) => &range.name,
+            ParaRange::Enum(range) => &range.name,
+        }
+    }
+    /// 范围是否有效: 浮点参数的 min 不大于 max, 默认值在范围内且步长非负;
+    /// 枚举参数至少有一个选项且默认值是选项的下标
+    pub fn is_valid(&self) -> bool {
+        match self {
+            ParaRange::Float(range) => {
+                // min > max 或有 NaN 时范围不包含任何值
+                (range.min..=range.max).contains(&range.default)
+                    && range.step.is_finite()
+                    && range.step >= 0.0
+            }
+            ParaRange::Enum(range) => range.contains(range.default),
+        }
+    }
+    /// 默认值对应的控制信息
+    pub fn default_value(&self) -> MessageValue {
+        match self {
+            ParaRange::Float(range) => MessageValue::Float(FloatMessage {
+                name: range.name.clone(),
+                value: range.default,
+            }),
+            ParaRange::Enum(range) => MessageValue::Enum(EnumMessage {
+                name: range.name.clone(),
+                value: range.default,
+            }),
+        }
+    }
+    /// 归一化值对应的控制信息
+    pub fn from_normalized(&self, normalized: f64) -> MessageValue {
+        match self {
+            ParaRange::Float(range) => MessageValue::Float(FloatMessage {
+                name: range.name.clone(),
+                value: range.from_normalized(normalized),
+            }),
+            ParaRange::Enum(range) => MessageValue::Enum(EnumMessage {
+                name: range.name.clone(),
+                value: range.from_normalized(normalized),
+            }),
+        }
+    }
+    /// 控制信息的取值对应的归一化值, 类型不符时返回 None
+    pub fn to_normalized(&self, value: &MessageValue) -> Option<f64> {
+        match (self, value) {
+            (ParaRange::Float(range), MessageValue::Float(msg)) => {
+                Some(range.to_normalized(msg.value))
+            }
+            (ParaRange::Enum(range), MessageValue::Enum(msg)) => {
+                Some(range.to_normalized(msg.value))
+            }
+            _ => None,
+        }
+    }
+    /// 控制信息的取值用于显示的文本, 类型不符时返回 None
+    pub fn format(&self, value: &MessageValue) -> Option<String> {
+        match (self, value) {
+            (ParaRange::Float(range), MessageValue::Float(msg)) => Some(range.format(msg.value)),
+            (ParaRange::Enum(range), MessageValue::Enum(msg)) => {
+                range.label(msg.value).map(|l| l.to_string())
+            }
+            _ => None,
+        }
+    }
+    /// 从文本解析出控制信息
+    pub fn parse(&self, text: &str) -> Option<MessageValue> {
+        match self {
+            ParaRange::Float(range) => Some(MessageValue::Float(FloatMessage {
+                name: range.name.clone(),
+                value: range.parse(text)?,
+            })),
+            ParaRange::Enum(range) => Some(MessageValue::Enum(EnumMessage {
+                name: range.name.clone(),
+                value: range.parse(text)?,
+            })),
+        }
+    }
+}
+
+impl FloatRange {
+    pub fn contains(&self, value: f64) -> bool {
+        self.min <= value && value <= self.max
+    }
+    /// 限制到范围内, NaN 取默认值; 范围本身无效时也不会 panic
+    pub fn clamp(&self, value: f64) -> f64 {
+        if value.is_nan() {
+            self.default
+        } else {
+            value.max(self.min).min(self.max)
+        }
+    }
+    /// 对齐到最近的步长并限制到范围内
+    pub fn snap(&self, value: f64) -> f64 {
+        let value = self.clamp(value);
+        if self.step > 0.0 {
+            let steps = ((value - self.min) / self.step).round();
+            self.clamp(self.min + steps * self.step)
+        } else {
+            value
+        }
+    }
+    pub fn to_normalized(&self, value: f64) -> f64 {
+        if self.max <= self.min {
+            return 0.0;
+        }
+        let value = self.clamp(value);
+        let linear = (value - self.min) / (self.max - self.min);
+        match self.taper {
+            Taper::Logarithmic if self.min > 0.0 => {
+                (value / self.min).ln() / (self.max / self.min).ln()
+            }
+            Taper::Exponential(k) if k.abs() > f64::EPSILON => (1.0 + linear * k.exp_m1()).ln() / k,
+            _ => linear,
+        }
+    }
+    /// 归一化值对应的实际值, 已对齐到步长
+    pub fn from_normalized(&self, normalized: f64) -> f64 {
+        let n = if normalized.is_nan() {
+            0.0
+        } else {
+            normalized.clamp(0.0, 1.0)
+        };
+        let value = match self.taper {
+            Taper::Logarithmic if self.min > 0.0 => self.min * (self.max / self.min).powf(n),
+            Taper::Exponential(k) if k.abs() > f64::EPSILON => {
+                self.min + (self.max - self.min) * (k * n).exp_m1() / k.exp_m1()
+            }
+            _ => self.min + (self.max - self.min) * n,
+        };
+        self.snap(value)
+    }
+    /// 带单位的文本, 如 `440.00 Hz`, `50.0 %`
+    pub fn format(&self, value: f64) -> String {
+        match &self.unit {
+            Unit::None => format!("{:.2}", value),
+            Unit::Hertz => format!("{:.2} Hz", value),
+            Unit::Decibel => format!("{:.2} dB", value),
+            Unit::Millisecond => format!("{:.1} ms", value),
+            Unit::Second => format!("{:.3} s", value),
+            Unit::Percent => format!("{:.1} %", value * 100.0),
+            Unit::Custom(unit) => format!("{:.2} {}", value, unit),
+        }
+    }
+    /// 解析文本, 单位可以省略, 结果对齐到步长; 频率接受 kHz, 时间接受 s 和 ms 互换
+    pub fn parse(&self, text: &str) -> Option<f64> {
+        let text = text.trim();
+        let split = text
+            .find(|c: char| !(c.is_ascii_digit() || "+-.eE".contains(c)))
+            .unwrap_or(text.len());
+        let (number, unit) = text.split_at(split);
+        let number = number.parse::<f64>().ok()?;
+        let unit = unit.trim().to_ascii_lowercase();
+        let scale = match (&self.unit, unit.as_str()) {
+            // 百分数省略单位时也按百分数理解
+            (Unit::Percent, "" | "%") => 0.01,
+            (_, "") => 1.0,
+            (Unit::Hertz, "hz") | (Unit::Decibel, "db") => 1.0,
+            (Unit::Hertz, "khz") => 1000.0,
+            (Unit::Millisecond, "ms") | (Unit::Second, "s") => 1.0,
+            (Unit::Millisecond, "s") => 1000.0,
+            (Unit::Second, "ms") => 0.001,
+            (Unit::Custom(u), _) if unit == u.to_ascii_lowercase() => 1.0,
+            _ => return None,
+        };
+        Some(self.snap(number * scale))
+    }
+}
+
+impl EnumRange {
+    pub fn len(&self) -> usize {
+        self.options.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.options.is_empty()
+    }
+    pub fn contains(&self, value: usize) -> bool {
+        value < self.len()
+    }
+    pub fn clamp(&self, value: usize) -> usize {
+        value.min(self.len().saturating_sub(1))
+    }
+    pub fn label(&self, value: usize) -> Option<&str> {
+        self.options.get(value).map(|l| l.as_str())
+    }
+    /// 标签对应的下标, 不区分大小写
+    pub fn index_of(&self, label: &str) -> Option<usize> {
+        self.options
+            .iter()
+            .position(|l| l.eq_ignore_ascii_case(label.trim()))
+    }
+    pub fn to_normalized(&self, value: usize) -> f64 {
+        match self.len() {
+            0 | 1 => 0.0,
+            len => self.clamp(value) as f64 / (len - 1) as f64,
+        }
+    }
+    pub fn from_normalized(&self, normalized: f64) -> usize {
+        let n = if normalized.is_nan() {
+            0.0
+        } else {
+            normalized.clamp(0.0, 1.0)
+        };
+        (n * self.len().saturating_sub(1) as f64).round() as usize
+    }
+    /// 按标签解析, 也接受选项的下标
+    pub fn parse(&self, text: &str) -> Option<usize> {
+        self.index_of(text).or_else(|| {
+            text.trim()
+                .parse::<usize>()
+                .ok()
+                .filter(|i| self.contains(*i))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn range(min: f64, max: f64, taper: Taper, unit: Unit, step: f64) -> FloatRange {
+        FloatRange {
+            name: "p".to_string(),
+            min,
+            max,
+            default: min,
+            taper,
+            unit,
+            step,
+        }
+    }
+
+    #[test]
+    fn normalized() {
+        let freq = range(20.0, 20000.0, Taper::Logarithmic, Unit::Hertz, 0.0);
+        assert!((freq.from_normalized(0.5) - 632.455532).abs() < 1e-6);
+        let time = range(0.0, 10.0, Taper::Exponential(4.0), Unit::Second, 0.0);
+        assert!(time.from_normalized(0.5) < 2.0);
+        for r in [&freq, &time] {
+            for n in [0.0, 0.25, 0.7, 1.0] {
+                assert!((r.to_normalized(r.from_normalized(n)) - n).abs() < 1e-9);
+            }
+        }
+        let stepped = range(0.0, 1.0, Taper::Linear, Unit::None, 0.25);
+        assert_eq!(stepped.from_normalized(0.3), 0.25);
+        assert_eq!(stepped.snap(0.9), 1.0);
+    }
+
+    #[test]
+    fn format_and_parse() {
+        let freq = range(20.0, 20000.0, Taper::Logarithmic, Unit::Hertz, 0.0);
+        assert_eq!(freq.format(440.0), "440.00 Hz");
+        assert_eq!(freq.parse("1.5 kHz"), Some(1500.0));
+        assert_eq!(freq.parse("50000"), Some(20000.0));
+        assert_eq!(freq.parse("3 dB"), None);
+        let level = range(0.0, 1.0, Taper::Linear, Unit::Percent, 0.0);
+        assert_eq!(level.format(0.5), "50.0 %");
+        assert_eq!(level.parse("25%"), Some(0.25));
+        let time = range(0.0, 10.0, Taper::Linear, Unit::Second, 0.0);
+        assert_eq!(time.parse("250 ms"), Some(0.25));
+
+        let shape = ParaRange::Enum(EnumRange {
+            name: "Shape".to_string(),
+            options: vec!["Sine".to_string(), "Saw".to_string(), "Square".to_string()],
+            default: 1,
+        });
+        let square = MessageValue::Enum(EnumMessage {
+            name: "Shape".to_string(),
+            value: 2,
+        });
+        assert_eq!(shape.parse("square"), Some(square.clone()));
+        assert_eq!(shape.format(&square).as_deref(), Some("Square"));
+        assert_eq!(shape.to_normalized(&square), Some(1.0));
+        assert_eq!(
+            shape.from_normalized(0.6),
+            MessageValue::Enum(EnumMessage {
+                name: "Shape".to_string(),
+                value: 1,
+            })
+        );
+    }
+}
@@ -1,27 +1,30 @@
 #![allow(dead_code)]
 use std::{
     alloc::{dealloc, Layout},
-    any::TypeId,
     ptr,
-    sync::Mutex,
 };
 
-use crate::{AudioBufferMut, AudioBufferRef, MessageBuffer, Parameter, PlayHead};
+use serde_json::Value;
 
-pub(crate) static NODE_REGISTER_CENTER: Mutex<Vec<(TypeId, NodeType)>> = Mutex::new(Vec::new());
+use crate::{
+    AudioBufferMut, AudioBufferRef, AudioIo, MessageBuffer, NodeState, Parameter, PlayHead, Sample,
+    SampleFormat,
+};
 
 pub(crate) struct RawNode {
     pub data: *const (),
     pub note_type: NodeType,
+    pub spec: Option<NodeSpec>,
     pub vtable: &'static RawNodeVTable,
 }
 
 impl RawNode {
-    pub fn with_audio_effect<T: AudioEffectNode>(node: T) -> Self {
+    pub fn with_audio_effect<S: Sample, T: AudioEffectNode<S>>(node: T) -> Self {
         let data = Box::into_raw(Box::new(node)) as *const ();
         Self {
             data,
             note_type: NodeType::AudioEffect,
+            spec: None,
             vtable: &RawNodeVTable {
                 name: |d| {
                     let d = unsafe { &*(d as *mut T) };
@@ -29,13 +32,22 @@ impl RawNode {
                 },
                 prepare: |d, sample_rate| {
                     let d = unsafe { &mut *(d as *mut T) };
-                    RawDesc::with_audio_effect(d.prepare(sample_rate))
+                    RawDesc::with_audio_effect(d.prepare(sample_rate), S::FORMAT)
                 },
-                process: |d, playhead, frames, audio_in, audio_out, message_in, _| {
+                process: |d, playhead, frames, audio, message_in, _| {
                     let d = unsafe { &mut *(d as *mut T) };
+                    let (audio_in, audio_out) = S::unwrap_io(audio);
                     let audio_out = audio_out.into_iter().next().unwrap();
                     d.process(playhead, frames, audio_in, audio_out, message_in);
                 },
+                save_state: |d| {
+                    let d = unsafe { &*(d as *mut T) };
+                    d.save_state()
+                },
+                load_state: |d, state| {
+                    let d = unsafe { &mut *(d as *mut T) };
+                    d.load_state(state)
+                },
                 drop: |d| {
                     let d = d as *mut T;
                     unsafe {
@@ -52,6 +64,7 @@ impl RawNode {
         Self {
             data,
             note_type: NodeType::MidiEffect,
+            spec: None,
             vtable: &RawNodeVTable {
                 name: |d| {
                     let d = unsafe { &*(d as *mut T) };
@@ -61,10 +74,18 @@ impl RawNode {
                     let d = unsafe { &mut *(d as *mut T) };
                     RawDesc::with_midi_effect(d.prepare(sample_rate))
                 },
-                process: |d, playhead, frames, _, _, message_in, message_out| {
+                process: |d, playhead, frames, _, message_in, message_out| {
                     let d = unsafe { &mut *(d as *mut T) };
                     d.process(playhead, frames, message_in, message_out);
                 },
+                save_state: |d| {
+                    let d = unsafe { &*(d as *mut T) };
+                    d.save_state()
+                },
+                load_state: |d, state| {
+                    let d = unsafe { &mut *(d as *mut T) };
+                    d.load_state(state)
+                },
                 drop: |d| {
                     let d = d as *mut T;
                     unsafe {
@@ -76,11 +97,12 @@ impl RawNode {
         }
     }
 
-    pub fn with_audio_source<T: AudioSourceNode>(node: T) -> Self {
+    pub fn with_audio_source<S: Sample, T: AudioSourceNode<S>>(node: T) -> Self {
         let data = Box::into_raw(Box::new(node)) as *const ();
         Self {
             data,
             note_type: NodeType::AudioSource,
+            spec: None,
             vtable: &RawNodeVTable {
                 name: |d| {
                     let d = unsafe { &*(d as *mut T) };
@@ -88,13 +110,61 @@ impl RawNode {
                 },
                 prepare: |d, sample_rate| {
                     let d = unsafe { &mut *(d as *mut T) };
-                    RawDesc::with_audio_source(d.prepare(sample_rate))
+                    RawDesc::with_audio_source(d.prepare(sample_rate), S::FORMAT)
                 },
-                process: |d, playhead, frames, _, audio_out, message_in, _| {
+                process: |d, playhead, frames, audio, message_in, _| {
                     let d = unsafe { &mut *(d as *mut T) };
+                    let (_, audio_out) = S::unwrap_io(audio);
                     let audio_out = audio_out.into_iter().next().unwrap();
                     d.process(playhead, frames, audio_out, message_in);
                 },
+                save_state: |d| {
+                    let d = unsafe { &*(d as *mut T) };
+                    d.save_state()
+                },
+                load_state: |d, state| {
+                    let d = unsafe { &mut *(d as *mut T) };
+                    d.load_state(state)
+                },
+                drop: |d| {
+                    let d = d as *mut T;
+                    unsafe {
+                        ptr::drop_in_place(d);
+                        dealloc(d as *mut u8, Layout::new::<T>());
+                    }
+                },
+            },
+        }
+    }
+
+    pub fn with_multi_audio<S: Sample, T: MultiAudioNode<S>>(node: T) -> Self {
+        let data = Box::into_raw(Box::new(node)) as *const ();
+        Self {
+            data,
+            note_type: NodeType::MultiAudio,
+            spec: None,
+            vtable: &RawNodeVTable {
+                name: |d| {
+                    let d = unsafe { &*(d as *mut T) };
+                    d.name()
+                },
+                prepare: |d, sample_rate| {
+                    let d = unsafe { &mut *(d as *mut T) };
+                    RawDesc::with_multi_audio(d.prepare(sample_rate), S::FORMAT)
+                },
+                process: |d, playhead, frames, audio, message_in, _| {
+                    let d = unsafe { &mut *(d as *mut T) };
+                    let (audio_in, audio_out) = S::unwrap_io(audio);
+                    d.process(playhead, frames, audio_in, audio_out, message_in);
+                },
+                save_state: |d| {
+                    let d = unsafe { &*(d as *mut T) };
+                    d.save_state()
+                },
+                load_state: |d, state| {
+                    let d = unsafe { &mut *(d as *mut T) };
+                    d.load_state(state)
+                },
                 drop: |d| {
                     let d = d as *mut T;
                     unsafe {
@@ -110,21 +180,12 @@ impl RawNode {
         &self,
         playhead: &PlayHead,
         frames: usize,
-        audio_in: Vec<AudioBufferRef>,
-        audio_out: Vec<AudioBufferMut>,
+        audio: AudioIo,
         message_in: &MessageBuffer,
         message_out: Vec<&mut MessageBuffer>,
     ) {
         unsafe {
-            (self.vtable.process)(
-                self.data,
-                playhead,
-                frames,
-                audio_in,
-                audio_out,
-                message_in,
-                message_out,
-            );
+            (self.vtable.process)(self.data, playhead, frames, audio, message_in, message_out);
         }
     }
 
@@ -135,6 +196,14 @@ impl RawNode {
     pub fn name(&self) -> String {
         unsafe { (self.vtable.name)(self.data) }
     }
+
+    pub fn save_state(&self) -> Option<NodeState> {
+        unsafe { (self.vtable.save_state)(self.data) }
+    }
+
+    pub fn load_state(&mut self, state: &NodeState) -> bool {
+        unsafe { (self.vtable.load_state)(self.data, state) }
+    }
 }
 
 impl Drop for RawNode {
@@ -143,56 +212,90 @@ impl Drop for RawNode {
     }
 }
 
+/// 节点的描述, 音频输入输出以端口名表示, 没有名字的端口只能用序号指定
+#[derive(Clone)]
 pub(crate) struct RawDesc {
-    pub audio_in: usize,
-    pub audio_out: usize,
+    pub audio_in: Vec<String>,
+    pub audio_out: Vec<String>,
+    /// 所有音频输入输出的声道数, 没有音频时为 0
+    pub channels: usize,
+    /// 音频输入输出的样本格式
+    pub format: SampleFormat,
     pub message_out: usize,
+    pub latency: usize,
     pub parameters: Vec<Parameter>,
 }
 
 impl RawDesc {
-    fn with_audio_effect(desc: AudioEffectDesc) -> Self {
+    fn with_audio_effect(desc: AudioEffectDesc, format: SampleFormat) -> Self {
         Self {
-            audio_in: desc.audio_in,
-            audio_out: 1,
+            audio_in: vec![String::new(); desc.audio_in],
+            audio_out: vec![String::new()],
+            channels: desc.channels,
+            format,
             message_out: 0,
+            latency: desc.latency,
             parameters: desc.parameters,
         }
     }
 
     fn with_midi_effect(desc: MidiEffectDesc) -> Self {
         Self {
-            audio_in: 0,
-            audio_out: 0,
+            audio_in: vec![],
+            audio_out: vec![],
+            channels: 0,
+            format: SampleFormat::default(),
             message_out: desc.message_out,
+            latency: 0,
             parameters: desc.parameters,
         }
     }
 
-    fn with_audio_source(desc: AudioSourceDesc) -> Self {
+    fn with_audio_source(desc: AudioSourceDesc, format: SampleFormat) -> Self {
         Self {
-            audio_in: 0,
-            audio_out: 1,
+            audio_in: vec![],
+            audio_out: vec![String::new()],
+            channels: desc.channels,
+            format,
             message_out: 0,
+            latency: desc.latency,
             parameters: desc.parameters,
         }
     }
+
+    fn with_multi_audio(desc: MultiAudioDesc, format: SampleFormat) -> Self {
+        Self {
+            audio_in: desc.audio_in,
+            audio_out: desc.audio_out,
+            channels: desc.channels,
+            format,
+            message_out: 0,
+            latency: desc.latency,
+            parameters: desc.parameters,
+        }
+    }
+}
+
+/// 在端口列表中查找端口, 先按名字查找, 再按序号查找, 不指定端口时为第一个端口
+pub(crate) fn port_index(ports: &[String], port: Option<&str>) -> Option<usize> {
+    let Some(port) = port else {
+        return (!ports.is_empty()).then_some(0);
+    };
+    ports
+        .iter()
+        .position(|p| !p.is_empty() && p == port)
+        .or_else(|| port.parse().ok().filter(|i| *i < ports.len()))
 }
 
-pub(crate) type FnRawProcess = unsafe fn(
-    *const (),
-    &PlayHead,
-    usize,
-    Vec<AudioBufferRef>,
-    Vec<AudioBufferMut>,
-    &MessageBuffer,
-    Vec<&mut MessageBuffer>,
-);
+pub(crate) type FnRawProcess =
+    unsafe fn(*const (), &PlayHead, usize, AudioIo, &MessageBuffer, Vec<&mut MessageBuffer>);
 
 pub(crate) struct RawNodeVTable {
     pub name: unsafe fn(*const ()) -> String,
     pub prepare: unsafe fn(*const (), f64) -> RawDesc,
     pub process: FnRawProcess,
+    pub save_state: unsafe fn(*const ()) -> Option<NodeState>,
+    pub load_state: unsafe fn(*const (), &NodeState) -> bool,
     drop: unsafe fn(*const ()),
 }
 
@@ -201,28 +304,122 @@ unsafe impl Sync for RawNodeVTable {}
 unsafe impl Send for RawNode {}
 unsafe impl Sync for RawNode {}
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum NodeType {
     AudioEffect,
     MidiEffect,
     AudioSource,
+    MultiAudio,
+}
+
+impl NodeType {
+    pub fn has_audio_in(&self) -> bool {
+        matches!(self, Self::AudioEffect | Self::MultiAudio)
+    }
+
+    pub fn has_audio_out(&self) -> bool {
+        matches!(
+            self,
+            Self::AudioEffect | Self::AudioSource | Self::MultiAudio
+        )
+    }
+
+    pub fn has_message_out(&self) -> bool {
+        matches!(self, Self::MidiEffect)
+    }
+}
+
+/// 擦除了具体类型的节点, 可以加入图或从图中取出
+pub struct BoxedNode(pub(crate) RawNode);
+
+impl BoxedNode {
+    pub fn audio_source<S: Sample, T: AudioSourceNode<S>>(node: T) -> Self {
+        Self(RawNode::with_audio_source(node))
+    }
+
+    pub fn audio_effect<S: Sample, T: AudioEffectNode<S>>(node: T) -> Self {
+        Self(RawNode::with_audio_effect(node))
+    }
+
+    pub fn midi_effect<T: MidiEffectNode>(node: T) -> Self {
+        Self(RawNode::with_midi_effect(node))
+    }
+
+    pub fn multi_audio<S: Sample, T: MultiAudioNode<S>>(node: T) -> Self {
+        Self(RawNode::with_multi_audio(node))
+    }
+
+    pub fn name(&self) -> String {
+        self.0.name()
+    }
+
+    pub fn node_type(&self) -> &NodeType {
+        &self.0.note_type
+    }
+
+    /// 记录创建节点所用的类型和参数, 图据此把节点写进描述
+    pub fn with_spec(mut self, spec: NodeSpec) -> Self {
+        self.0.spec = Some(spec);
+        self
+    }
+
+    pub fn spec(&self) -> Option<&NodeSpec> {
+        self.0.spec.as_ref()
+    }
+}
+
+/// 节点的类型和构造参数
+#[derive(Clone, Debug, PartialEq)]
+pub struct NodeSpec {
+    pub type_id: String,
+    pub args: Value,
 }
 
 pub struct AudioEffectDesc {
+    /// 音频输入的数量, 连接用 `节点名:序号` 指定输入, 不指定时接入第一个输入,
+    /// 例如压缩器可以声明两个输入, 把侧链信号连接到 `compressor:1`
     pub audio_in: usize,
+    /// 输入输出的声道数, 声道数不同的来源会先被上混或下混
+    pub channels: usize,
+    /// 输出相对输入延迟的帧数, 图会延迟其它并行的路径来对齐
+    pub latency: usize,
     pub parameters: Vec<Parameter>,
 }
 
-pub trait AudioEffectNode {
+/// 单个输入的双声道效果器, 没有延迟和参数
+impl Default for AudioEffectDesc {
+    fn default() -> Self {
+        Self {
+            audio_in: 1,
+            channels: 2,
+            latency: 0,
+            parameters: vec![],
+        }
+    }
+}
+
+/// S 是节点处理音频所用的样本类型, 默认为 f64
+///
+/// 缓冲和状态的约定见 [crate 文档](crate#节点).
+pub trait AudioEffectNode<S: Sample = f64> {
     fn name(&self) -> String;
     fn prepare(&mut self, sample_rate: f64) -> AudioEffectDesc;
+    /// audio_in 的长度总是等于描述中的 audio_in, 第 i 项是第 i 个输入,
+    /// 同一输入的多个来源已经混合, 没有连接的输入是静音
     fn process(
         &mut self,
         playhead: &PlayHead,
         frames: usize,
-        audio_in: Vec<AudioBufferRef>,
-        audio_out: AudioBufferMut,
+        audio_in: Vec<AudioBufferRef<S>>,
+        audio_out: AudioBufferMut<S>,
         message_in: &MessageBuffer,
     );
+    fn save_state(&self) -> Option<NodeState> {
+        None
+    }
+    fn load_state(&mut self, _state: &NodeState) -> bool {
+        false
+    }
 }
 
 pub struct MidiEffectDesc {
@@ -230,6 +427,7 @@ pub struct MidiEffectDesc {
     pub parameters: Vec<Parameter>,
 }
 
+/// 状态的约定见 [crate 文档](crate#节点)
 pub trait MidiEffectNode {
     fn name(&self) -> String;
     fn prepare(&mut self, sample_rate: f64) -> MidiEffectDesc;
@@ -240,20 +438,97 @@ pub trait MidiEffectNode {
         message_in: &MessageBuffer,
         message_out: Vec<&mut MessageBuffer>,
     );
+    fn save_state(&self) -> Option<NodeState> {
+        None
+    }
+    fn load_state(&mut self, _state: &NodeState) -> bool {
+        false
+    }
 }
 
 pub struct AudioSourceDesc {
+    /// 输出的声道数
+    pub channels: usize,
+    /// 输出相对控制信息延迟的帧数
+    pub latency: usize,
     pub parameters: Vec<Parameter>,
 }
 
-pub trait AudioSourceNode {
+/// 双声道的音源, 没有延迟和参数
+impl Default for AudioSourceDesc {
+    fn default() -> Self {
+        Self {
+            channels: 2,
+            latency: 0,
+            parameters: vec![],
+        }
+    }
+}
+
+/// S 是节点输出音频所用的样本类型, 默认为 f64
+///
+/// 缓冲和状态的约定见 [crate 文档](crate#节点).
+pub trait AudioSourceNode<S: Sample = f64> {
     fn name(&self) -> String;
     fn prepare(&mut self, sample_rate: f64) -> AudioSourceDesc;
     fn process(
         &mut self,
         playhead: &PlayHead,
         frames: usize,
-        audio_out: AudioBufferMut,
+        audio_out: AudioBufferMut<S>,
+        message_in: &MessageBuffer,
+    );
+    fn save_state(&self) -> Option<NodeState> {
+        None
+    }
+    fn load_state(&mut self, _state: &NodeState) -> bool {
+        false
+    }
+}
+
+/// 任意数量具名音频输入输出的节点, 连接用 `节点名:端口名` 或 `节点名:序号` 指定端口
+pub struct MultiAudioDesc {
+    pub audio_in: Vec<String>,
+    pub audio_out: Vec<String>,
+    /// 所有输入输出的声道数
+    pub channels: usize,
+    /// 所有输出相对输入延迟的帧数
+    pub latency: usize,
+    pub parameters: Vec<Parameter>,
+}
+
+/// 没有端口的双声道节点, 没有延迟和参数
+impl Default for MultiAudioDesc {
+    fn default() -> Self {
+        Self {
+            audio_in: vec![],
+            audio_out: vec![],
+            channels: 2,
+            latency: 0,
+            parameters: vec![],
+        }
+    }
+}
+
+/// S 是节点处理音频所用的样本类型, 默认为 f64
+///
+/// 缓冲和状态的约定见 [crate 文档](crate#节点).
+pub trait MultiAudioNode<S: Sample = f64> {
+    fn name(&self) -> String;
+    fn prepare(&mut self, sample_rate: f64) -> MultiAudioDesc;
+    /// audio_in 和 audio_out 的顺序与描述中端口的顺序一致
+    fn process(
+        &mut self,
+        playhead: &PlayHead,
+        frames: usize,
+        audio_in: Vec<AudioBufferRef<S>>,
+        audio_out: Vec<AudioBufferMut<S>>,
         message_in: &MessageBuffer,
     );
+    fn save_state(&self) -> Option<NodeState> {
+        None
+    }
+    fn load_state(&mut self, _state: &NodeState) -> bool {
+        false
+    }
 }
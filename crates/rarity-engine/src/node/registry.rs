@@ -0,0 +1,116 @@
+use std::{
+    any::TypeId,
+    sync::{Arc, Mutex},
+};
+
+use serde_json::Value;
+
+use crate::{
+    BoxedNode, DescriptionError, DescriptionResult, NodeDescription, NodeSpec, NodeType,
+    RegistryError, RegistryResult,
+};
+
+/// 所有注册的节点类型, 按类型名排序
+pub(crate) static NODE_REGISTER_CENTER: Mutex<Vec<NodeInfo>> = Mutex::new(Vec::new());
+
+/// 按节点的描述创建节点, 描述中的参数已经补全了默认值, 参数不合法时返回原因
+pub type NodeConstructor = Arc<dyn Fn(&NodeDescription) -> Result<BoxedNode, String> + Send + Sync>;
+
+/// 注册的节点类型
+#[derive(Clone)]
+pub struct NodeInfo {
+    /// 全局唯一的类型名, 如 `rarity.simple_saw`, 图的描述以它指定节点类型
+    pub id: String,
+    /// 显示给用户的名字
+    pub name: String,
+    pub node_type: NodeType,
+    /// 节点的 Rust 类型, 动态加载的节点没有
+    pub rust_type: Option<TypeId>,
+    /// 构造参数及其默认值, 描述中省略的参数取默认值
+    pub default_args: Value,
+    pub create: NodeConstructor,
+}
+
+impl NodeInfo {
+    /// Rust 类型为 T 的节点类型
+    pub fn new<T: 'static>(
+        id: &str,
+        name: &str,
+        node_type: NodeType,
+        default_args: Value,
+        create: impl Fn(&NodeDescription) -> Result<BoxedNode, String> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            id: id.to_string(),
+            name: name.to_string(),
+            node_type,
+            rust_type: Some(TypeId::of::<T>()),
+            default_args,
+            create: Arc::new(create),
+        }
+    }
+}
+
+/// 注册节点类型, 类型名已被占用时返回错误
+pub fn register_node(info: NodeInfo) -> RegistryResult<()> {
+    let mut center = NODE_REGISTER_CENTER.lock().unwrap();
+    match center.binary_search_by(|i| i.id.as_str().cmp(&info.id)) {
+        Ok(_) => Err(RegistryError::RepeatedType(info.id)),
+        Err(index) => {
+            center.insert(index, info);
+            Ok(())
+        }
+    }
+}
+
+/// 注销节点类型, 已经创建的节点不受影响
+pub fn unregister_node(id: &str) -> Option<NodeInfo> {
+    let mut center = NODE_REGISTER_CENTER.lock().unwrap();
+    let index = center.iter().position(|i| i.id == id)?;
+    Some(center.remove(index))
+}
+
+/// 所有注册的节点类型, 按类型名排序
+pub fn registered_nodes() -> Vec<NodeInfo> {
+    NODE_REGISTER_CENTER.lock().unwrap().clone()
+}
+
+pub fn node_info(id: &str) -> Option<NodeInfo> {
+    let center = NODE_REGISTER_CENTER.lock().unwrap();
+    center.iter().find(|i| i.id == id).cloned()
+}
+
+/// Rust 类型 T 注册的类型名
+pub fn node_id_of<T: 'static>() -> Option<String> {
+    let center = NODE_REGISTER_CENTER.lock().unwrap();
+    let rust_type = Some(TypeId::of::<T>());
+    center
+        .iter()
+        .find(|i| i.rust_type == rust_type)
+        .map(|i| i.id.clone())
+}
+
+/// 按注册的类型创建节点, 可以直接作为 [`Graph::from_description`](crate::Graph::from_description) 的 factory
+///
+/// 描述中省略的构造参数取注册时的默认值, 创建的节点记录了类型名和补全后的参数.
+/// 类型未注册时返回 [`DescriptionError::UnknownType`], 构造失败时返回 [`DescriptionError::InvalidArgs`].
+pub fn create_node(desc: &NodeDescription) -> DescriptionResult<BoxedNode> {
+    let info = node_info(&desc.type_id)
+        .ok_or_else(|| DescriptionError::UnknownType(desc.type_id.clone()))?;
+    let mut desc = desc.clone();
+    match (&mut desc.args, &info.default_args) {
+        (Value::Null, defaults) => desc.args = defaults.clone(),
+        (Value::Object(args), Value::Object(defaults)) => {
+            for (key, value) in defaults {
+                args.entry(key.clone()).or_insert_with(|| value.clone());
+            }
+        }
+        _ => {}
+    }
+    let node =
+        (info.create)(&desc).map_err(|e| DescriptionError::InvalidArgs(desc.name.clone(), e))?;
+    Ok(node.with_spec(NodeSpec {
+        type_id: desc.type_id,
+        args: desc.args,
+    }))
+}
@@ -0,0 +1,169 @@
+use crate::FloatRange;
+
+/// 平滑参数从当前值变到目标值的方式
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Smoothing {
+    /// 在给定时间内匀速到达目标
+    #[default]
+    Linear,
+    /// 在给定时间内按比例到达目标, 适合频率和增益; 起点与目标异号或为 0 时按线性处理
+    Exponential,
+    /// 一阶低通, 给定时间为时间常数, 约 5 倍时间后到达目标
+    OnePole,
+}
+
+/// 一阶低通与目标的差小于该值时直接到达目标
+const ONE_POLE_EPSILON: f64 = 1e-6;
+
+/// 平滑变化的参数, 设置目标后在处理循环中每个样本调用一次 [`SmoothedValue::next`]
+#[derive(Clone, Debug)]
+pub struct SmoothedValue {
+    smoothing: Smoothing,
+    time_ms: f64,
+    sample_rate: f64,
+    current: f64,
+    target: f64,
+    /// 线性时为每个样本的增量, 指数时为每个样本的倍数, 一阶低通时为系数
+    step: f64,
+    /// 按倍数而不是增量前进
+    multiply: bool,
+    /// 线性和指数还需前进的样本数
+    remaining: usize,
+}
+
+impl SmoothedValue {
+    pub fn new(smoothing: Smoothing, time_ms: f64, value: f64) -> Self {
+        Self {
+            smoothing,
+            time_ms,
+            sample_rate: 48000.0,
+            current: value,
+            target: value,
+            step: 0.0,
+            multiply: false,
+            remaining: 0,
+        }
+    }
+    /// 采样率改变后直接到达目标
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        self.set_value(self.target);
+    }
+    /// 平滑所用的时间, 单位为毫秒, 从下一次设置目标开始生效
+    pub fn set_time(&mut self, time_ms: f64) {
+        self.time_ms = time_ms;
+    }
+    pub fn smoothing(&self) -> Smoothing {
+        self.smoothing
+    }
+    pub fn time(&self) -> f64 {
+        self.time_ms
+    }
+    /// 设置目标, 之后的样本从当前值平滑变到目标
+    pub fn set_target(&mut self, target: f64) {
+        self.target = target;
+        let samples = (self.time_ms * 0.001 * self.sample_rate).round().max(0.0);
+        if samples < 1.0 || target == self.current {
+            self.set_value(target);
+            return;
+        }
+        self.multiply = self.smoothing == Smoothing::Exponential && self.current * target > 0.0;
+        match self.smoothing {
+            _ if self.multiply => {
+                self.step = (target / self.current).powf(1.0 / samples);
+                self.remaining = samples as usize;
+            }
+            Smoothing::Linear | Smoothing::Exponential => {
+                self.step = (target - self.current) / samples;
+                self.remaining = samples as usize;
+            }
+            Smoothing::OnePole => {
+                self.step = (-1.0 / samples).exp();
+                self.remaining = usize::MAX;
+            }
+        }
+    }
+    /// 立即跳到 value, 不经过平滑
+    pub fn set_value(&mut self, value: f64) {
+        self.current = value;
+        self.target = value;
+        self.remaining = 0;
+    }
+    pub fn target(&self) -> f64 {
+        self.target
+    }
+    /// 当前值, 不会前进
+    pub fn value(&self) -> f64 {
+        self.current
+    }
+    /// 是否还没有到达目标, 没有时可以把当前值当作常数处理整个块
+    pub fn is_smoothing(&self) -> bool {
+        self.remaining > 0
+    }
+    /// 前进一个样本并返回新的值
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> f64 {
+        if self.remaining == 0 {
+            return self.current;
+        }
+        match self.smoothing {
+            Smoothing::OnePole => {
+                self.current = self.target + (self.current - self.target) * self.step;
+                if (self.current - self.target).abs() < ONE_POLE_EPSILON {
+                    self.set_value(self.target);
+                }
+            }
+            _ if self.remaining == 1 => self.set_value(self.target),
+            _ if self.multiply => {
+                self.current *= self.step;
+                self.remaining -= 1;
+            }
+            _ => {
+                self.current += self.step;
+                self.remaining -= 1;
+            }
+        }
+        self.current
+    }
+}
+
+impl FloatRange {
+    /// 以默认值为初值的平滑参数
+    pub fn smoothed(&self, smoothing: Smoothing, time_ms: f64) -> SmoothedValue {
+        SmoothedValue::new(smoothing, time_ms, self.default)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ramp(smoothing: Smoothing, from: f64, to: f64) -> Vec<f64> {
+        let mut value = SmoothedValue::new(smoothing, 1.0, from);
+        value.set_sample_rate(4000.0);
+        value.set_target(to);
+        (0..8).map(|_| value.next()).collect()
+    }
+
+    #[test]
+    fn smoothing() {
+        // 1ms 在 4000Hz 下为 4 个样本
+        assert_eq!(
+            ramp(Smoothing::Linear, 0.0, 1.0)[..5],
+            [0.25, 0.5, 0.75, 1.0, 1.0]
+        );
+        let exp = ramp(Smoothing::Exponential, 1.0, 16.0);
+        assert!((exp[0] - 2.0).abs() < 1e-9 && (exp[2] - 8.0).abs() < 1e-9);
+        assert_eq!(exp[3], 16.0);
+        assert_eq!(ramp(Smoothing::Exponential, 0.0, 1.0)[1], 0.5);
+
+        let pole = ramp(Smoothing::OnePole, 0.0, 1.0);
+        assert!(pole.windows(2).all(|w| w[0] < w[1] && w[1] < 1.0));
+        assert!((pole[3] - (1.0 - (-1.0f64).exp())).abs() < 1e-9);
+
+        let mut value = SmoothedValue::new(Smoothing::Linear, 0.0, 1.0);
+        value.set_target(2.0);
+        assert!(!value.is_smoothing());
+        assert_eq!(value.value(), 2.0);
+    }
+}
@@ -0,0 +1,34 @@
+use std::ops::Range;
+
+use crate::{Message, MessageBuffer};
+
+/// 在消息的时间点拆分处理块的节点
+///
+/// 配合 [`split_render`] 使用, 节点只需处理单条消息和渲染一段帧, 不必自己拆分缓冲.
+pub trait SplitRender {
+    fn handle_message(&mut self, message: &Message);
+}
+
+/// 按输入消息的时间把 frames 帧拆成若干段, 每段之前先处理该时间点的消息, 再渲染该段
+///
+/// render 收到的范围是段在块内的帧, 可以用 `slice` 和 `slice_mut` 取出对应的缓冲.
+/// 时间超出块长的消息在最后一段渲染之后处理.
+pub fn split_render<T: SplitRender + ?Sized>(
+    node: &mut T,
+    frames: usize,
+    message_in: &MessageBuffer,
+    mut render: impl FnMut(&mut T, Range<usize>),
+) {
+    let mut start = 0;
+    for (f, msg) in message_in {
+        let f = (*f).min(frames);
+        if start < f {
+            render(node, start..f);
+            start = f;
+        }
+        node.handle_message(msg);
+    }
+    if start < frames {
+        render(node, start..frames);
+    }
+}
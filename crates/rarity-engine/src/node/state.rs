@@ -0,0 +1,109 @@
+use std::{fmt, str::FromStr};
+
+use crate::{path, EnumMessage, FloatMessage, Message, MessageValue, PresetError};
+
+/// 节点的状态, 即恢复状态时要发给节点的控制信息
+///
+/// 控制信息的地址相对节点本身, 不含节点名. 图的预设也是一个状态, 地址不含图名.
+///
+/// 文本格式每行一条取值, 如 `float /sub/vol/gain 1.5` 和 `enum /simple_saw/Shape 1`,
+/// 路径与参数的 OSC 地址相同; 空行和 `#` 开头的行被忽略, MIDI 信息不保存.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NodeState(pub Vec<Message>);
+
+impl NodeState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// 地址最外层为 node 的控制信息, 去掉节点名
+    pub fn node(&self, node: &str) -> NodeState {
+        let messages = self
+            .0
+            .iter()
+            .filter(|m| m.addr.last().is_some_and(|n| n == node))
+            .map(|m| {
+                let mut m = m.clone();
+                m.addr.pop();
+                m
+            })
+            .collect();
+        Self(messages)
+    }
+
+    /// 给所有控制信息的地址加上外层的节点名, 合并进来
+    pub fn extend_node(&mut self, node: &str, state: NodeState) {
+        self.0.extend(state.0.into_iter().map(|mut m| {
+            m.addr.push(node.to_string());
+            m
+        }));
+    }
+}
+
+impl fmt::Display for NodeState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for message in &self.0 {
+            match &message.value {
+                MessageValue::Float(msg) => {
+                    writeln!(f, "float {} {}", path(&message.addr, &msg.name), msg.value)?
+                }
+                MessageValue::Enum(msg) => {
+                    writeln!(f, "enum {} {}", path(&message.addr, &msg.name), msg.value)?
+                }
+                MessageValue::Midi(_) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for NodeState {
+    type Err = PresetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut messages = vec![];
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || PresetError::InvalidLine(i + 1, line.to_string());
+            let mut parts = line.split_whitespace();
+            let (Some(kind), Some(path), Some(value), None) =
+                (parts.next(), parts.next(), parts.next(), parts.next())
+            else {
+                return Err(invalid());
+            };
+            // 路径从外到内, 地址从内到外
+            let mut addr = path
+                .strip_prefix('/')
+                .ok_or_else(invalid)?
+                .split('/')
+                .map(str::to_string)
+                .collect::<Vec<_>>();
+            let name = addr.pop().filter(|n| !n.is_empty()).ok_or_else(invalid)?;
+            addr.reverse();
+            let value = match kind {
+                "float" => MessageValue::Float(FloatMessage {
+                    name,
+                    value: value.parse().map_err(|_| invalid())?,
+                }),
+                "enum" => MessageValue::Enum(EnumMessage {
+                    name,
+                    value: value.parse().map_err(|_| invalid())?,
+                }),
+                _ => return Err(invalid()),
+            };
+            messages.push(Message { addr, value });
+        }
+        Ok(Self(messages))
+    }
+}
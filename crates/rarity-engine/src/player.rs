@@ -1,4 +1,15 @@
-use crate::Graph;
+use std::{
+    mem,
+    sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+};
+
+use crate::{
+    AudioBufferMut, AudioBufferRef, Graph, GraphEditor, GraphResult, MessageBuffer, NodeState,
+    Sample, PENDING_UPDATES,
+};
+
+/// 接收新图的一端, 以及送回旧图的一端
+type Updates = (Receiver<Box<Graph>>, SyncSender<Box<Graph>>);
 
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub struct PlayHead {
@@ -9,12 +20,83 @@ pub struct PlayHead {
     pub samples_from_last_bar: f64,
 }
 
+impl PlayHead {
+    /// 播放头向后移动若干帧, 越过小节线时从新的小节开始计数
+    pub fn advance(&mut self, frames: usize) {
+        self.samples_from_last_bar += frames as f64;
+        let bar = self.samples_per_quarter * 4.0 * self.upper as f64 / self.lower as f64;
+        if bar > 0.0 {
+            self.samples_from_last_bar %= bar;
+        }
+    }
+}
+
 pub struct GraphPlayer {
     pub graph: Graph,
+    updates: Option<Updates>,
+    /// 编辑器已被丢弃时送不回去的旧图, 留到创建新的编辑器或播放器被丢弃时释放
+    ///
+    /// 保留 Box, 拆箱会在音频线程释放它的内存.
+    #[allow(clippy::vec_box)]
+    pub(crate) retired: Vec<Box<Graph>>,
 }
 
 impl GraphPlayer {
     pub fn new(graph: Graph) -> Self {
-        Self { graph }
+        Self {
+            graph,
+            updates: None,
+            retired: vec![],
+        }
+    }
+
+    /// 创建编辑句柄, 之后可以在其它线程修改正在播放的图, 之前创建的句柄随之失效
+    ///
+    /// 图需要已经 prepare 过.
+    pub fn editor(&mut self) -> GraphEditor {
+        assert!(!self.graph.is_dirty(), "图需要先 prepare");
+        let (update_tx, update_rx) = sync_channel(PENDING_UPDATES);
+        let (garbage_tx, garbage_rx) = sync_channel(PENDING_UPDATES);
+        self.updates = Some((update_rx, garbage_tx));
+        // 在途的更新最多 PENDING_UPDATES 个, 预先分配后音频线程放入旧图时不会分配内存
+        self.retired = Vec::with_capacity(PENDING_UPDATES);
+        GraphEditor::new(&self.graph, update_tx, garbage_rx)
+    }
+
+    /// 保存正在播放的图的预设, 需要在处理音频的线程调用
+    ///
+    /// 节点的 save_state 只能在这里调用, 编辑器所在的线程无法读取正在播放的图的状态.
+    pub fn save_preset(&self) -> NodeState {
+        self.graph.save_preset()
+    }
+
+    /// 恢复正在播放的图的预设, 需要在处理音频的线程调用, 其它线程使用 [`GraphEditor::load_preset`]
+    pub fn load_preset(&mut self, preset: &NodeState) -> GraphResult<()> {
+        self.graph.load_preset(preset)
+    }
+
+    /// 换上编辑器提交的新图(如果有), 然后处理一个音频块
+    pub fn process<S: Sample>(
+        &mut self,
+        playhead: &PlayHead,
+        frames: usize,
+        audio_in: AudioBufferRef<S>,
+        audio_out: AudioBufferMut<S>,
+        message_in: &MessageBuffer,
+    ) {
+        if let Some((updates, garbage)) = &self.updates {
+            while let Ok(mut graph) = updates.try_recv() {
+                graph.adopt_nodes(&mut self.graph);
+                mem::swap(&mut self.graph, &mut graph);
+                // 容量与在途更新数一致, 只有编辑器已被丢弃时才会失败, 此时旧图不在音频线程释放
+                if let Err(TrySendError::Full(graph) | TrySendError::Disconnected(graph)) =
+                    garbage.try_send(graph)
+                {
+                    self.retired.push(graph);
+                }
+            }
+        }
+        self.graph
+            .process(playhead, frames, audio_in, audio_out, message_in);
     }
 }
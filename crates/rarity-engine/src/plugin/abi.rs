@@ -0,0 +1,101 @@
+use std::ffi::{c_char, c_void};
+
+/// 插件 ABI 的版本, 与 `include/rarity_plugin.h` 一致, 插件声明的版本不同时拒绝加载
+///
+/// 这里的结构都是 `#[repr(C)]`, 布局有任何改变都需要增加版本.
+pub const PLUGIN_ABI_VERSION: u32 = 1;
+
+/// 插件导出的入口函数名, 函数类型为 [`PluginEntry`]
+pub const PLUGIN_ENTRY: &[u8] = b"rarity_plugin_entry\0";
+
+/// 返回插件的描述, 描述在动态库卸载之前一直有效
+pub type PluginEntry = unsafe extern "C" fn() -> *const RarityPlugin;
+
+pub const NODE_AUDIO_EFFECT: u32 = 0;
+pub const NODE_AUDIO_SOURCE: u32 = 1;
+
+pub const EVENT_FLOAT: u32 = 0;
+pub const EVENT_ENUM: u32 = 1;
+pub const EVENT_NOTE_ON: u32 = 2;
+pub const EVENT_NOTE_OFF: u32 = 3;
+pub const EVENT_CONTROL_CHANGE: u32 = 4;
+pub const EVENT_PITCH_BEND: u32 = 5;
+
+/// 插件的描述
+#[repr(C)]
+pub struct RarityPlugin {
+    pub abi_version: u32,
+    pub node_types: *const RarityNodeType,
+    pub node_type_count: usize,
+}
+
+/// 插件中的一种节点, 字符串都是 UTF-8 编码, 以 0 结尾
+///
+/// create 和 prepare 在编辑图的线程调用, process 在音频线程调用, destroy 在任一线程调用.
+/// 实例随图在线程间移动, 但同一实例的调用不会并发, 实例不能依赖创建它的线程.
+#[repr(C)]
+pub struct RarityNodeType {
+    /// 全局唯一的类型名, 如 `team.reverb`
+    pub id: *const c_char,
+    /// 显示给用户的名字
+    pub name: *const c_char,
+    /// [`NODE_AUDIO_EFFECT`] 或 [`NODE_AUDIO_SOURCE`], 音频效果只有一个输入
+    pub kind: u32,
+    /// 输入输出的声道数, 不能为 0
+    pub channels: u32,
+    pub parameters: *const RarityParameter,
+    pub parameter_count: usize,
+    /// JSON 对象形式的构造参数默认值, 可以为空
+    pub default_args: *const c_char,
+    /// 按 JSON 形式的构造参数创建实例, 失败时返回空指针
+    pub create: unsafe extern "C" fn(args: *const c_char) -> *mut c_void,
+    pub destroy: unsafe extern "C" fn(instance: *mut c_void),
+    /// 准备处理, 返回输出相对输入延迟的帧数
+    pub prepare: unsafe extern "C" fn(instance: *mut c_void, sample_rate: f64) -> u32,
+    /// 处理 frames 帧, 每个声道一个指针, 输出已经清零; 音频源的 input 为空指针
+    ///
+    /// 在音频线程调用, 不能阻塞或分配内存.
+    pub process: unsafe extern "C" fn(
+        instance: *mut c_void,
+        frames: usize,
+        input: *const *const f64,
+        output: *const *mut f64,
+        events: *const RarityEvent,
+        event_count: usize,
+    ),
+}
+
+/// 节点的参数, option_count 为 0 时是浮点参数, 否则是以选项下标取值的枚举参数
+///
+/// 浮点参数的 default 需要在 min 和 max 之间, 枚举参数的 default 需要是选项下标.
+#[repr(C)]
+pub struct RarityParameter {
+    pub name: *const c_char,
+    pub min: f64,
+    pub max: f64,
+    pub default: f64,
+    pub options: *const *const c_char,
+    pub option_count: usize,
+}
+
+/// 不以 0 结尾的 UTF-8 字符串
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct RarityStr {
+    pub ptr: *const u8,
+    pub len: usize,
+}
+
+/// 发给节点的控制信息, 按帧排序, 只在 process 期间有效
+///
+/// 参数事件的 name 为参数名, value 为取值或选项下标; 音符事件的 number 为音高,
+/// value 为力度; 控制器事件的 number 为控制器号; 弯音事件的 value 为弯音值.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct RarityEvent {
+    pub frame: u32,
+    pub kind: u32,
+    pub name: RarityStr,
+    pub number: u8,
+    pub value: f64,
+}
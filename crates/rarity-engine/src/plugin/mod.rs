@@ -0,0 +1,625 @@
+mod abi;
+pub use abi::*;
+use std::{
+    ffi::{c_char, c_void, CStr, CString},
+    path::{Path, PathBuf},
+    ptr, slice,
+    sync::Arc,
+};
+
+use libloading::Library;
+use serde_json::Value;
+
+use crate::{
+    register_node, unregister_node, AudioBufferMut, AudioBufferRef, AudioEffectDesc,
+    AudioEffectNode, AudioSourceDesc, AudioSourceNode, BoxedNode, EnumRange, FloatRange, Message,
+    MessageBuffer, MessageValue, MidiMessage, NodeDescription, NodeInfo, NodeType, ParaRange,
+    Parameter, PlayHead, PluginError, PluginResult, Taper, Unit,
+};
+
+/// 从动态库加载的节点插件
+///
+/// 插件中的节点类型在加载时注册到节点注册表, 插件被丢弃时注销.
+/// 已经创建的节点持有动态库, 动态库在最后一个节点被释放后才卸载.
+pub struct Plugin {
+    path: Option<PathBuf>,
+    node_ids: Vec<String>,
+}
+
+impl Plugin {
+    /// 加载动态库, 注册它导出的所有节点类型
+    ///
+    /// # Safety
+    ///
+    /// 动态库需要导出符合本模块 C ABI 的 `rarity_plugin_entry`, 加载时会执行库的初始化代码.
+    pub unsafe fn load(path: impl AsRef<Path>) -> PluginResult<Self> {
+        let path = path.as_ref();
+        let library = Arc::new(Library::new(path)?);
+        let entry = *library.get::<PluginEntry>(PLUGIN_ENTRY)?;
+        let mut plugin = Self::from_entry(entry(), Some(library))?;
+        plugin.path = Some(path.to_path_buf());
+        Ok(plugin)
+    }
+
+    /// 注册已经链接进来的插件描述中的节点类型
+    ///
+    /// # Safety
+    ///
+    /// plugin 及其中的描述需要在插件和它创建的节点存在期间一直有效.
+    pub unsafe fn from_entry(
+        plugin: *const RarityPlugin,
+        library: Option<Arc<Library>>,
+    ) -> PluginResult<Self> {
+        let plugin = plugin
+            .as_ref()
+            .ok_or_else(|| PluginError::InvalidDescriptor("plugin is null".to_string()))?;
+        if plugin.abi_version != PLUGIN_ABI_VERSION {
+            return Err(PluginError::AbiVersion(plugin.abi_version));
+        }
+        let node_types = array(plugin.node_types, plugin.node_type_count)?;
+        let mut loaded = Self {
+            path: None,
+            node_ids: vec![],
+        };
+        for node_type in node_types {
+            let plugin_type = Arc::new(PluginType::new(node_type, library.clone())?);
+            let info = plugin_type.info()?;
+            let id = info.id.clone();
+            // 出错时 loaded 被丢弃, 已经注册的类型随之注销
+            register_node(info)?;
+            loaded.node_ids.push(id);
+        }
+        Ok(loaded)
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// 插件注册的节点类型名
+    pub fn node_ids(&self) -> &[String] {
+        &self.node_ids
+    }
+}
+
+impl Drop for Plugin {
+    fn drop(&mut self) {
+        for id in &self.node_ids {
+            unregister_node(id);
+        }
+    }
+}
+
+/// 插件中的一种节点及其所在的动态库
+struct PluginType {
+    desc: *const RarityNodeType,
+    parameters: Vec<Parameter>,
+    _library: Option<Arc<Library>>,
+}
+
+unsafe impl Send for PluginType {}
+unsafe impl Sync for PluginType {}
+
+impl PluginType {
+    unsafe fn new(desc: &RarityNodeType, library: Option<Arc<Library>>) -> PluginResult<Self> {
+        if desc.channels == 0 {
+            return Err(PluginError::InvalidDescriptor("channels is 0".to_string()));
+        }
+        let parameters = array(desc.parameters, desc.parameter_count)?
+            .iter()
+            .map(|p| parameter(p))
+            .collect::<PluginResult<_>>()?;
+        Ok(Self {
+            desc,
+            parameters,
+            _library: library,
+        })
+    }
+
+    fn desc(&self) -> &RarityNodeType {
+        unsafe { &*self.desc }
+    }
+
+    fn info(self: &Arc<Self>) -> PluginResult<NodeInfo> {
+        let desc = self.desc();
+        let node_type = match desc.kind {
+            NODE_AUDIO_EFFECT => NodeType::AudioEffect,
+            NODE_AUDIO_SOURCE => NodeType::AudioSource,
+            kind => {
+                return Err(PluginError::InvalidDescriptor(format!(
+                    "node kind {}",
+                    kind
+                )))
+            }
+        };
+        let default_args = if desc.default_args.is_null() {
+            Value::Object(Default::default())
+        } else {
+            let args = unsafe { string(desc.default_args)? };
+            serde_json::from_str(&args)
+                .map_err(|e| PluginError::InvalidDescriptor(e.to_string()))?
+        };
+        let plugin_type = self.clone();
+        Ok(NodeInfo {
+            id: unsafe { string(desc.id)? },
+            name: unsafe { string(desc.name)? },
+            node_type,
+            rust_type: None,
+            default_args,
+            create: Arc::new(move |desc| plugin_type.create(desc)),
+        })
+    }
+
+    fn create(self: &Arc<Self>, node: &NodeDescription) -> Result<BoxedNode, String> {
+        let desc = self.desc();
+        let args = CString::new(node.args.to_string()).map_err(|e| e.to_string())?;
+        let instance = unsafe { (desc.create)(args.as_ptr()) };
+        if instance.is_null() {
+            return Err("plugin failed to create the node".to_string());
+        }
+        let channels = desc.channels as usize;
+        let node_type = self.clone();
+        let plugin_node = PluginNode {
+            name: node.name.clone(),
+            node_type,
+            instance,
+            events: vec![],
+            input: vec![ptr::null(); channels],
+            output: vec![ptr::null_mut(); channels],
+        };
+        match desc.kind {
+            NODE_AUDIO_SOURCE => Ok(BoxedNode::audio_source(plugin_node)),
+            _ => Ok(BoxedNode::audio_effect(plugin_node)),
+        }
+    }
+}
+
+/// 插件创建的节点实例
+struct PluginNode {
+    name: String,
+    node_type: Arc<PluginType>,
+    instance: *mut c_void,
+    /// 每次处理复用的事件和声道指针
+    events: Vec<RarityEvent>,
+    input: Vec<*const f64>,
+    output: Vec<*mut f64>,
+}
+
+impl PluginNode {
+    fn prepare(&mut self, sample_rate: f64) -> usize {
+        let desc = self.node_type.desc();
+        unsafe { (desc.prepare)(self.instance, sample_rate) as usize }
+    }
+
+    fn process(
+        &mut self,
+        frames: usize,
+        audio_in: Option<AudioBufferRef>,
+        mut audio_out: AudioBufferMut,
+        message_in: &MessageBuffer,
+    ) {
+        self.events.clear();
+        self.events
+            .extend(message_in.iter().filter_map(|(f, m)| event(*f, m)));
+        if let Some(audio_in) = audio_in {
+            for (c, p) in self.input.iter_mut().enumerate() {
+                *p = audio_in.channel(c).as_ptr();
+            }
+        }
+        for (p, channel) in self.output.iter_mut().zip(audio_out.channels_mut()) {
+            *p = channel.as_mut_ptr();
+        }
+        let input = match audio_in {
+            Some(_) => self.input.as_ptr(),
+            None => ptr::null(),
+        };
+        let desc = self.node_type.desc();
+        unsafe {
+            (desc.process)(
+                self.instance,
+                frames,
+                input,
+                self.output.as_ptr(),
+                self.events.as_ptr(),
+                self.events.len(),
+            )
+        };
+    }
+}
+
+impl Drop for PluginNode {
+    fn drop(&mut self) {
+        unsafe { (self.node_type.desc().destroy)(self.instance) };
+    }
+}
+
+impl AudioEffectNode for PluginNode {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn prepare(&mut self, sample_rate: f64) -> AudioEffectDesc {
+        AudioEffectDesc {
+            audio_in: 1,
+            channels: self.node_type.desc().channels as usize,
+            latency: PluginNode::prepare(self, sample_rate),
+            parameters: self.node_type.parameters.clone(),
+        }
+    }
+
+    fn process(
+        &mut self,
+        _playhead: &PlayHead,
+        frames: usize,
+        audio_in: Vec<AudioBufferRef>,
+        audio_out: AudioBufferMut,
+        message_in: &MessageBuffer,
+    ) {
+        PluginNode::process(self, frames, Some(audio_in[0]), audio_out, message_in)
+    }
+}
+
+impl AudioSourceNode for PluginNode {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn prepare(&mut self, sample_rate: f64) -> AudioSourceDesc {
+        AudioSourceDesc {
+            channels: self.node_type.desc().channels as usize,
+            latency: PluginNode::prepare(self, sample_rate),
+            parameters: self.node_type.parameters.clone(),
+        }
+    }
+
+    fn process(
+        &mut self,
+        _playhead: &PlayHead,
+        frames: usize,
+        audio_out: AudioBufferMut,
+        message_in: &MessageBuffer,
+    ) {
+        PluginNode::process(self, frames, None, audio_out, message_in)
+    }
+}
+
+/// 发给节点本身的控制信息对应的事件, 发给节点内部地址的控制信息被忽略
+fn event(frame: usize, message: &Message) -> Option<RarityEvent> {
+    if !message.addr.is_empty() {
+        return None;
+    }
+    let mut event = RarityEvent {
+        frame: frame as u32,
+        kind: EVENT_FLOAT,
+        name: RarityStr {
+            ptr: ptr::null(),
+            len: 0,
+        },
+        number: 0,
+        value: 0.0,
+    };
+    match &message.value {
+        MessageValue::Float(msg) => {
+            event.name = str_ref(&msg.name);
+            event.value = msg.value;
+        }
+        MessageValue::Enum(msg) => {
+            event.kind = EVENT_ENUM;
+            event.name = str_ref(&msg.name);
+            event.value = msg.value as f64;
+        }
+        MessageValue::Midi(MidiMessage::NoteOn(msg)) => {
+            event.kind = EVENT_NOTE_ON;
+            event.number = msg.pitch;
+            event.value = msg.velocity as f64;
+        }
+        MessageValue::Midi(MidiMessage::NoteOff(msg)) => {
+            event.kind = EVENT_NOTE_OFF;
+            event.number = msg.pitch;
+        }
+        MessageValue::Midi(MidiMessage::ControlChange(msg)) => {
+            event.kind = EVENT_CONTROL_CHANGE;
+            event.number = msg.number;
+            event.value = msg.value as f64;
+        }
+        MessageValue::Midi(MidiMessage::PitchBend(msg)) => {
+            event.kind = EVENT_PITCH_BEND;
+            event.value = msg.value as f64;
+        }
+    }
+    Some(event)
+}
+
+fn str_ref(s: &str) -> RarityStr {
+    RarityStr {
+        ptr: s.as_ptr(),
+        len: s.len(),
+    }
+}
+
+/// 长度为 len 的数组, 长度不为 0 时空指针是错误
+unsafe fn array<'a, T>(ptr: *const T, len: usize) -> PluginResult<&'a [T]> {
+    match len {
+        0 => Ok(&[]),
+        _ if ptr.is_null() => Err(PluginError::InvalidDescriptor(format!(
+            "array of length {} is null",
+            len
+        ))),
+        _ => Ok(slice::from_raw_parts(ptr, len)),
+    }
+}
+
+/// 以 0 结尾的 UTF-8 字符串, 空指针和非法编码都是错误
+unsafe fn string(s: *const c_char) -> PluginResult<String> {
+    if s.is_null() {
+        return Err(PluginError::InvalidDescriptor("string is null".to_string()));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map(str::to_string)
+        .map_err(|e| PluginError::InvalidDescriptor(e.to_string()))
+}
+
+unsafe fn parameter(p: &RarityParameter) -> PluginResult<Parameter> {
+    let name = string(p.name)?;
+    let invalid = |what: &str| {
+        Err(PluginError::InvalidDescriptor(format!(
+            "parameter {}: {}",
+            name, what
+        )))
+    };
+    let range = match p.option_count {
+        // min > max 或有 NaN 时范围不包含任何值
+        0 if !(p.min..=p.max).contains(&p.default) => {
+            return invalid("default is not in [min, max]")
+        }
+        0 => ParaRange::Float(FloatRange {
+            name,
+            min: p.min,
+            max: p.max,
+            default: p.default,
+            taper: Taper::Linear,
+            unit: Unit::None,
+            step: 0.0,
+        }),
+        n if !(0.0..n as f64).contains(&p.default) || p.default.fract() != 0.0 => {
+            return invalid("default is not an option index")
+        }
+        n => ParaRange::Enum(EnumRange {
+            options: array(p.options, n)?
+                .iter()
+                .map(|o| string(*o))
+                .collect::<PluginResult<_>>()?,
+            name,
+            default: p.default as usize,
+        }),
+    };
+    Ok(Parameter {
+        addr: vec![],
+        range,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::{create_node, node_info, Graph, GraphDescription};
+
+    use super::*;
+
+    static DESTROYED: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe extern "C" fn create(args: *const c_char) -> *mut c_void {
+        let args = CStr::from_ptr(args).to_str().unwrap();
+        let args = serde_json::from_str::<Value>(args).unwrap();
+        match args["gain"].as_f64() {
+            Some(gain) => Box::into_raw(Box::new(gain)) as *mut c_void,
+            None => ptr::null_mut(),
+        }
+    }
+
+    unsafe extern "C" fn destroy(instance: *mut c_void) {
+        drop(Box::from_raw(instance as *mut f64));
+        DESTROYED.fetch_add(1, Ordering::SeqCst);
+    }
+
+    unsafe extern "C" fn prepare(_instance: *mut c_void, _sample_rate: f64) -> u32 {
+        0
+    }
+
+    unsafe extern "C" fn process(
+        instance: *mut c_void,
+        frames: usize,
+        input: *const *const f64,
+        output: *const *mut f64,
+        events: *const RarityEvent,
+        event_count: usize,
+    ) {
+        let gain = &mut *(instance as *mut f64);
+        for event in slice::from_raw_parts(events, event_count) {
+            let name = slice::from_raw_parts(event.name.ptr, event.name.len);
+            if event.kind == EVENT_FLOAT && name == b"gain" {
+                *gain = event.value;
+            }
+        }
+        for c in 0..2 {
+            let input = slice::from_raw_parts(*input.add(c), frames);
+            let output = slice::from_raw_parts_mut(*output.add(c), frames);
+            for (o, i) in output.iter_mut().zip(input) {
+                *o = i * *gain;
+            }
+        }
+    }
+
+    fn gain_parameter() -> RarityParameter {
+        RarityParameter {
+            name: c"gain".as_ptr(),
+            min: 0.0,
+            max: 4.0,
+            default: 1.0,
+            options: ptr::null(),
+            option_count: 0,
+        }
+    }
+
+    fn gain_type(parameter: RarityParameter) -> RarityNodeType {
+        RarityNodeType {
+            id: c"test.plugin.gain".as_ptr(),
+            name: c"Gain".as_ptr(),
+            kind: NODE_AUDIO_EFFECT,
+            channels: 2,
+            parameters: Box::leak(Box::new([parameter])).as_ptr(),
+            parameter_count: 1,
+            default_args: c"{\"gain\": 1.0}".as_ptr(),
+            create,
+            destroy,
+            prepare,
+            process,
+        }
+    }
+
+    fn plugin_of(abi_version: u32, node_type: RarityNodeType) -> *const RarityPlugin {
+        Box::leak(Box::new(RarityPlugin {
+            abi_version,
+            node_types: Box::leak(Box::new([node_type])).as_ptr(),
+            node_type_count: 1,
+        }))
+    }
+
+    fn plugin(abi_version: u32) -> *const RarityPlugin {
+        plugin_of(abi_version, gain_type(gain_parameter()))
+    }
+
+    #[test]
+    fn plugin_node() {
+        let loaded = unsafe { Plugin::from_entry(plugin(PLUGIN_ABI_VERSION), None) }.unwrap();
+        assert_eq!(loaded.node_ids(), ["test.plugin.gain"]);
+        let info = node_info("test.plugin.gain").unwrap();
+        assert_eq!(info.node_type, NodeType::AudioEffect);
+        assert!(info.rust_type.is_none());
+
+        let desc = GraphDescription::from_json(
+            r#"{
+                "nodes": [
+                    {
+                        "type": "test.plugin.gain",
+                        "name": "gain",
+                        "args": { "gain": 2 },
+                        "parameters": { "gain": 3 }
+                    }
+                ],
+                "audio_links": [["A_IN_NODE", "gain"], ["gain", "A_OUT_NODE"]]
+            }"#,
+        )
+        .unwrap();
+        let mut graph = Graph::from_description(&desc, 48000.0, 16, &mut create_node).unwrap();
+        assert!(graph
+            .parameters()
+            .get(&["gain".to_string()], "gain")
+            .is_some());
+        let out = crate::graph::test::run_with(0.5, |playhead, frames, audio_in, audio_out, m| {
+            graph.process(playhead, frames, audio_in, audio_out, m)
+        });
+        assert!(out.iter().all(|f| *f == (1.5, 1.5)));
+
+        // 注销后已经创建的节点仍然可用, 随图释放
+        drop(loaded);
+        assert!(node_info("test.plugin.gain").is_none());
+        let destroyed = DESTROYED.load(Ordering::SeqCst);
+        drop(graph);
+        assert_eq!(DESTROYED.load(Ordering::SeqCst), destroyed + 1);
+
+        assert!(matches!(
+            unsafe { Plugin::from_entry(plugin(2), None) },
+            Err(PluginError::AbiVersion(2))
+        ));
+    }
+
+    #[test]
+    fn invalid_descriptor() {
+        let invalid = |node_type: RarityNodeType| {
+            let plugin = plugin_of(PLUGIN_ABI_VERSION, node_type);
+            matches!(
+                unsafe { Plugin::from_entry(plugin, None) },
+                Err(PluginError::InvalidDescriptor(_))
+            )
+        };
+        let with_parameter = |f: fn(&mut RarityParameter)| {
+            let mut parameter = gain_parameter();
+            f(&mut parameter);
+            gain_type(parameter)
+        };
+        let options = Box::leak(Box::new([c"a".as_ptr(), c"b".as_ptr()]));
+
+        assert!(invalid(RarityNodeType {
+            channels: 0,
+            ..gain_type(gain_parameter())
+        }));
+        assert!(invalid(RarityNodeType {
+            parameters: ptr::null(),
+            ..gain_type(gain_parameter())
+        }));
+        assert!(invalid(with_parameter(|p| p.min = 8.0)));
+        assert!(invalid(with_parameter(|p| p.max = f64::NAN)));
+        assert!(invalid(with_parameter(|p| p.default = f64::NAN)));
+        assert!(invalid(with_parameter(|p| p.option_count = 2)));
+        let enum_parameter = RarityParameter {
+            options: options.as_ptr(),
+            option_count: 2,
+            default: 2.0,
+            ..gain_parameter()
+        };
+        assert!(invalid(gain_type(enum_parameter)));
+        let null_plugin = RarityPlugin {
+            abi_version: PLUGIN_ABI_VERSION,
+            node_types: ptr::null(),
+            node_type_count: 1,
+        };
+        assert!(matches!(
+            unsafe { Plugin::from_entry(&null_plugin, None) },
+            Err(PluginError::InvalidDescriptor(_))
+        ));
+    }
+
+    #[test]
+    fn load_library() {
+        // tests/gain_plugin 编译出的动态库, 放在单独的目标目录以免与正在运行的 cargo 争用锁
+        let target = std::env::current_exe().unwrap();
+        let target = target.ancestors().nth(3).unwrap().join("gain_plugin");
+        let manifest = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/gain_plugin/Cargo.toml");
+        let status = std::process::Command::new(env!("CARGO"))
+            .arg("build")
+            .arg("--manifest-path")
+            .arg(manifest)
+            .arg("--target-dir")
+            .arg(&target)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let path = target
+            .join("debug")
+            .join(libloading::library_filename("rarity_gain_plugin"));
+
+        let loaded = unsafe { Plugin::load(&path) }.unwrap();
+        assert_eq!(loaded.path(), Some(path.as_path()));
+        assert_eq!(loaded.node_ids(), ["fixture.gain"]);
+        let desc = GraphDescription::from_json(
+            r#"{
+                "nodes": [{ "type": "fixture.gain", "name": "gain", "args": { "gain": 2 } }],
+                "audio_links": [["A_IN_NODE", "gain"], ["gain", "A_OUT_NODE"]]
+            }"#,
+        )
+        .unwrap();
+        let mut graph = Graph::from_description(&desc, 48000.0, 16, &mut create_node).unwrap();
+        let out = crate::graph::test::run_with(0.5, |playhead, frames, audio_in, audio_out, m| {
+            graph.process(playhead, frames, audio_in, audio_out, m)
+        });
+        assert!(out.iter().all(|f| *f == (1.0, 1.0)));
+
+        // 节点持有动态库, 插件先于节点释放
+        drop(loaded);
+        assert!(node_info("fixture.gain").is_none());
+        drop(graph);
+    }
+}
@@ -0,0 +1,285 @@
+use std::{
+    cell::UnsafeCell,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    AudioBufferRef, EnumMessage, FloatMessage, Message, MessageBuffer, MessageValue, MidiMessage,
+    PitchBend, Sample,
+};
+
+/// 每个监听通道最多缓存的样本数
+pub(crate) const TAP_SAMPLES: usize = 1 << 17;
+/// 每个监听通道最多缓存的控制信息数
+pub(crate) const TAP_MESSAGES: usize = 1024;
+/// 监听的控制信息的地址最多的层数, 更深的控制信息被丢弃
+pub(crate) const TAP_ADDR_DEPTH: usize = 4;
+/// 监听的控制信息中每个名字最多的字节数, 更长的控制信息被丢弃
+pub(crate) const TAP_NAME_LEN: usize = 32;
+
+/// 环形缓冲中的一条控制信息, 字符串的空间预先分配, 写入时不分配内存
+struct TapSlot {
+    frame: usize,
+    /// 只有前 depth 个有效
+    addr: Vec<String>,
+    depth: usize,
+    name: String,
+    /// 参数名为空, 名字存放在 name 里
+    value: MessageValue,
+}
+
+impl TapSlot {
+    fn new() -> Self {
+        Self {
+            frame: 0,
+            addr: (0..TAP_ADDR_DEPTH)
+                .map(|_| String::with_capacity(TAP_NAME_LEN))
+                .collect(),
+            depth: 0,
+            name: String::with_capacity(TAP_NAME_LEN),
+            value: MessageValue::Midi(MidiMessage::PitchBend(PitchBend { value: 0 })),
+        }
+    }
+
+    /// 复制到预先分配的空间里, 放不下时返回 false
+    fn write(&mut self, frame: usize, message: &Message) -> bool {
+        let (name, value) = match &message.value {
+            MessageValue::Float(msg) => (
+                &msg.name[..],
+                MessageValue::Float(FloatMessage {
+                    name: String::new(),
+                    value: msg.value,
+                }),
+            ),
+            MessageValue::Enum(msg) => (
+                &msg.name[..],
+                MessageValue::Enum(EnumMessage {
+                    name: String::new(),
+                    value: msg.value,
+                }),
+            ),
+            MessageValue::Midi(msg) => ("", MessageValue::Midi(*msg)),
+        };
+        let fits = |s: &str| s.len() <= TAP_NAME_LEN;
+        if message.addr.len() > TAP_ADDR_DEPTH
+            || !message.addr.iter().all(|a| fits(a))
+            || !fits(name)
+        {
+            return false;
+        }
+        for (slot, part) in self.addr.iter_mut().zip(&message.addr) {
+            slot.clear();
+            slot.push_str(part);
+        }
+        self.frame = frame;
+        self.depth = message.addr.len();
+        self.name.clear();
+        self.name.push_str(name);
+        self.value = value;
+        true
+    }
+
+    fn read(&self) -> (usize, Message) {
+        let mut value = self.value.clone();
+        match &mut value {
+            MessageValue::Float(msg) => msg.name = self.name.clone(),
+            MessageValue::Enum(msg) => msg.name = self.name.clone(),
+            MessageValue::Midi(_) => {}
+        }
+        let message = Message {
+            addr: self.addr[..self.depth].to_vec(),
+            value,
+        };
+        (self.frame, message)
+    }
+}
+
+/// 单生产者单消费者的环形缓冲, 样本以位模式存放在原子变量里
+struct TapShared {
+    data: Box<[AtomicU64]>,
+    /// 被监听输出的声道数, 只由音频线程修改
+    channels: AtomicUsize,
+    /// 已写入的样本数, 只由音频线程修改
+    write: AtomicUsize,
+    /// 已读取的样本数, 只由读取端修改
+    read: AtomicUsize,
+    /// 因读取不及时而丢弃的帧数
+    dropped: AtomicUsize,
+    /// 音频线程经过的帧数, 作为控制信息的时间
+    clock: AtomicUsize,
+    /// 控制信息的环形缓冲, 写入端只写 message_write 之后的空位,
+    /// 读取端只读 message_read 和 message_write 之间的控制信息
+    messages: Box<[UnsafeCell<TapSlot>]>,
+    message_write: AtomicUsize,
+    message_read: AtomicUsize,
+    /// 因缓冲写满或放不进预先分配的空间而丢弃的控制信息数
+    dropped_messages: AtomicUsize,
+}
+
+// 只有一个写入端和一个读取端, 两端通过 message_write 和 message_read 交接控制信息的空位
+unsafe impl Sync for TapShared {}
+
+/// 图持有的监听写入端, 在音频线程写入时不加锁也不分配内存
+#[derive(Clone)]
+pub(crate) struct TapSender {
+    shared: Arc<TapShared>,
+}
+
+/// 监听通道的读取端, 可以在任意线程读取被监听节点的输出
+///
+/// 读取不及时导致缓冲写满时, 新的音频帧和控制信息会被丢弃.
+/// 音频按声道交替读出, 被监听的图重新 prepare 后声道数可能改变.
+pub struct TapReceiver {
+    shared: Arc<TapShared>,
+}
+
+pub(crate) fn tap_channel() -> (TapSender, TapReceiver) {
+    let shared = Arc::new(TapShared {
+        data: (0..TAP_SAMPLES).map(|_| AtomicU64::new(0)).collect(),
+        channels: AtomicUsize::new(0),
+        write: AtomicUsize::new(0),
+        read: AtomicUsize::new(0),
+        dropped: AtomicUsize::new(0),
+        clock: AtomicUsize::new(0),
+        messages: (0..TAP_MESSAGES)
+            .map(|_| UnsafeCell::new(TapSlot::new()))
+            .collect(),
+        message_write: AtomicUsize::new(0),
+        message_read: AtomicUsize::new(0),
+        dropped_messages: AtomicUsize::new(0),
+    });
+    (
+        TapSender {
+            shared: shared.clone(),
+        },
+        TapReceiver { shared },
+    )
+}
+
+impl TapSender {
+    /// 写入一个块的输出, 然后把时钟向后推进 frames 帧
+    pub(crate) fn send<S: Sample>(
+        &self,
+        frames: usize,
+        audio: Option<AudioBufferRef<S>>,
+        messages: Option<&MessageBuffer>,
+    ) {
+        let shared = &*self.shared;
+        let clock = shared.clock.load(Ordering::Relaxed);
+        if let Some(audio) = audio {
+            let channels = audio.channels();
+            let write = shared.write.load(Ordering::Relaxed);
+            let read = shared.read.load(Ordering::Acquire);
+            // 声道数只在缓冲读空后改变, 之前的内容仍按原来的声道数读取
+            let space = if shared.channels.load(Ordering::Relaxed) == channels {
+                (shared.data.len() - (write - read)) / channels
+            } else if write == read {
+                shared.channels.store(channels, Ordering::Relaxed);
+                shared.data.len() / channels
+            } else {
+                0
+            };
+            let len = audio.len().min(space);
+            for i in 0..len {
+                for c in 0..channels {
+                    let index = (write + i * channels + c) % shared.data.len();
+                    let sample = audio.get(i, c).to_f64();
+                    shared.data[index].store(sample.to_bits(), Ordering::Relaxed);
+                }
+            }
+            shared
+                .write
+                .store(write + len * channels, Ordering::Release);
+            if len < audio.len() {
+                shared
+                    .dropped
+                    .fetch_add(audio.len() - len, Ordering::Relaxed);
+            }
+        }
+        if let Some(messages) = messages {
+            let mut write = shared.message_write.load(Ordering::Relaxed);
+            let read = shared.message_read.load(Ordering::Acquire);
+            let mut dropped = 0;
+            for (frame, message) in messages {
+                if write - read == shared.messages.len() {
+                    dropped += 1;
+                    continue;
+                }
+                let slot = &shared.messages[write % shared.messages.len()];
+                if unsafe { &mut *slot.get() }.write(clock + frame, message) {
+                    write += 1;
+                } else {
+                    dropped += 1;
+                }
+            }
+            shared.message_write.store(write, Ordering::Release);
+            if dropped > 0 {
+                shared
+                    .dropped_messages
+                    .fetch_add(dropped, Ordering::Relaxed);
+            }
+        }
+        shared.clock.store(clock + frames, Ordering::Relaxed);
+    }
+}
+
+impl TapReceiver {
+    /// 被监听输出的声道数, 尚未收到音频时为 0
+    pub fn channels(&self) -> usize {
+        self.shared.channels.load(Ordering::Acquire)
+    }
+
+    /// 读取尽可能多的完整帧到 out 中, 各声道交替存放, 返回读取的帧数
+    pub fn read(&mut self, out: &mut [f64]) -> usize {
+        let shared = &*self.shared;
+        let read = shared.read.load(Ordering::Relaxed);
+        // 先读取写入位置, 这样读到的声道数不会比数据旧
+        let write = shared.write.load(Ordering::Acquire);
+        let channels = self.channels();
+        if channels == 0 {
+            return 0;
+        }
+        let len = (out.len() / channels).min((write - read) / channels);
+        for (i, sample) in out.iter_mut().take(len * channels).enumerate() {
+            let index = (read + i) % shared.data.len();
+            *sample = f64::from_bits(shared.data[index].load(Ordering::Relaxed));
+        }
+        shared.read.store(read + len * channels, Ordering::Release);
+        len
+    }
+
+    /// 可以读取的帧数
+    pub fn available(&self) -> usize {
+        let shared = &*self.shared;
+        let samples = shared.write.load(Ordering::Acquire) - shared.read.load(Ordering::Relaxed);
+        samples.checked_div(self.channels()).unwrap_or(0)
+    }
+
+    /// 因读取不及时而丢弃的帧数
+    pub fn dropped(&self) -> usize {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+
+    /// 因读取不及时, 或者地址超过 4 层, 名字超过 32 字节而丢弃的控制信息数
+    pub fn dropped_messages(&self) -> usize {
+        self.shared.dropped_messages.load(Ordering::Relaxed)
+    }
+
+    /// 取出一条控制信息, 时间是从开始监听起经过的帧数
+    ///
+    /// 地址超过 4 层或名字超过 32 字节的控制信息不会被监听, 只计入 [`Self::dropped_messages`].
+    pub fn try_recv_message(&mut self) -> Option<(usize, Message)> {
+        let shared = &*self.shared;
+        let read = shared.message_read.load(Ordering::Relaxed);
+        if read == shared.message_write.load(Ordering::Acquire) {
+            return None;
+        }
+        let slot = &shared.messages[read % shared.messages.len()];
+        let message = unsafe { &*slot.get() }.read();
+        shared.message_read.store(read + 1, Ordering::Release);
+        Some(message)
+    }
+}
@@ -0,0 +1,89 @@
+use std::{
+    ffi::{c_char, c_void, CStr},
+    ptr, slice,
+};
+
+use rarity_engine::{
+    RarityEvent, RarityNodeType, RarityParameter, RarityPlugin, EVENT_FLOAT, NODE_AUDIO_EFFECT,
+    PLUGIN_ABI_VERSION,
+};
+
+/// 静态的插件描述, 描述中的指针只指向静态数据
+struct Static<T>(T);
+
+unsafe impl<T> Sync for Static<T> {}
+
+static PARAMETERS: Static<[RarityParameter; 1]> = Static([RarityParameter {
+    name: c"gain".as_ptr(),
+    min: 0.0,
+    max: 4.0,
+    default: 1.0,
+    options: ptr::null(),
+    option_count: 0,
+}]);
+
+static NODE_TYPES: Static<[RarityNodeType; 1]> = Static([RarityNodeType {
+    id: c"fixture.gain".as_ptr(),
+    name: c"Gain".as_ptr(),
+    kind: NODE_AUDIO_EFFECT,
+    channels: 2,
+    parameters: PARAMETERS.0.as_ptr(),
+    parameter_count: 1,
+    default_args: c"{\"gain\": 1.0}".as_ptr(),
+    create,
+    destroy,
+    prepare,
+    process,
+}]);
+
+static PLUGIN: Static<RarityPlugin> = Static(RarityPlugin {
+    abi_version: PLUGIN_ABI_VERSION,
+    node_types: NODE_TYPES.0.as_ptr(),
+    node_type_count: 1,
+});
+
+#[no_mangle]
+pub extern "C" fn rarity_plugin_entry() -> *const RarityPlugin {
+    &PLUGIN.0
+}
+
+unsafe extern "C" fn create(args: *const c_char) -> *mut c_void {
+    let args = CStr::from_ptr(args).to_str().unwrap_or_default();
+    let args = serde_json::from_str::<serde_json::Value>(args).unwrap_or_default();
+    match args["gain"].as_f64() {
+        Some(gain) => Box::into_raw(Box::new(gain)) as *mut c_void,
+        None => ptr::null_mut(),
+    }
+}
+
+unsafe extern "C" fn destroy(instance: *mut c_void) {
+    drop(Box::from_raw(instance as *mut f64));
+}
+
+unsafe extern "C" fn prepare(_instance: *mut c_void, _sample_rate: f64) -> u32 {
+    0
+}
+
+unsafe extern "C" fn process(
+    instance: *mut c_void,
+    frames: usize,
+    input: *const *const f64,
+    output: *const *mut f64,
+    events: *const RarityEvent,
+    event_count: usize,
+) {
+    let gain = &mut *(instance as *mut f64);
+    for event in slice::from_raw_parts(events, event_count) {
+        let name = slice::from_raw_parts(event.name.ptr, event.name.len);
+        if event.kind == EVENT_FLOAT && name == b"gain" {
+            *gain = event.value;
+        }
+    }
+    for c in 0..2 {
+        let input = slice::from_raw_parts(*input.add(c), frames);
+        let output = slice::from_raw_parts_mut(*output.add(c), frames);
+        for (o, i) in output.iter_mut().zip(input) {
+            *o = i * *gain;
+        }
+    }
+}
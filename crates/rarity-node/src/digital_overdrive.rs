@@ -1,26 +1,30 @@
 use rarity_engine::{
-    AudioBufferMut, AudioBufferRef, AudioEffectDesc, AudioEffectNode, FloatRange, Message,
-    MessageBuffer, MessageValue, ParaRange, Parameter, PlayHead,
+    split_render, AudioBufferMut, AudioBufferRef, AudioEffectDesc, AudioEffectNode, FloatRange,
+    Message, MessageBuffer, MessageValue, ParaRange, Parameter, PlayHead, SmoothedValue, Smoothing,
+    SplitRender, Taper, Unit,
 };
 
+use crate::SMOOTHING_MS;
+
 pub struct DigitalOverDrive {
     name: String,
-    drive: f64,
-    level: f64,
+    drive: SmoothedValue,
+    level: SmoothedValue,
 }
 
 impl DigitalOverDrive {
     pub fn new(name: &str) -> Self {
         Self {
             name: name.to_string(),
-            drive: 0.0,
-            level: 1.0,
+            drive: SmoothedValue::new(Smoothing::Linear, SMOOTHING_MS, 0.0),
+            level: SmoothedValue::new(Smoothing::Linear, SMOOTHING_MS, 1.0),
         }
     }
 
     pub fn prepare() -> AudioEffectDesc {
         AudioEffectDesc {
             audio_in: 1,
+            channels: 2,
             parameters: vec![
                 Parameter {
                     addr: vec![],
@@ -29,6 +33,9 @@ impl DigitalOverDrive {
                         min: 0.0,
                         max: 1.0,
                         default: 0.0,
+                        taper: Taper::Linear,
+                        unit: Unit::Percent,
+                        step: 0.0,
                     }),
                 },
                 Parameter {
@@ -38,9 +45,13 @@ impl DigitalOverDrive {
                         min: 0.0,
                         max: 1.0,
                         default: 1.0,
+                        taper: Taper::Linear,
+                        unit: Unit::Percent,
+                        step: 0.0,
                     }),
                 },
             ],
+            ..Default::default()
         }
     }
 
@@ -48,32 +59,12 @@ impl DigitalOverDrive {
         &mut self,
         frames: usize,
         audio_in: AudioBufferRef,
-        audio_out: AudioBufferMut,
+        mut audio_out: AudioBufferMut,
         message_in: &MessageBuffer,
     ) {
-        let mut curr_frame = 0;
-        let mut in_remain = audio_in;
-        let mut out_remain = audio_out;
-        for (f, msg) in message_in {
-            if *f >= frames {
-                break;
-            }
-            let f = *f.min(&frames);
-            if curr_frame < f {
-                let (output, tmp) = out_remain.split_at_mut(f - curr_frame);
-                out_remain = tmp;
-                let (input, tmp) = in_remain.split_at(f - curr_frame);
-                in_remain = tmp;
-                self.forward(input, output);
-                curr_frame = f;
-            }
-            self.set_state(msg);
-        }
-        if curr_frame < frames {
-            let (output, _) = out_remain.split_at_mut(frames - curr_frame);
-            let (input, _) = in_remain.split_at(frames - curr_frame);
-            self.forward(input, output);
-        }
+        split_render(self, frames, message_in, |node, range| {
+            node.forward(audio_in.slice(range.clone()), audio_out.slice_mut(range))
+        });
     }
 
     pub fn set_state(&mut self, message: &Message) {
@@ -90,20 +81,44 @@ impl DigitalOverDrive {
     }
 
     pub fn set_drive(&mut self, value: f64) {
-        self.drive = value;
+        self.drive.set_target(value);
     }
 
     pub fn set_level(&mut self, value: f64) {
-        self.level = value;
+        self.level.set_target(value);
     }
 
-    pub fn forward(&self, input: AudioBufferRef, output: AudioBufferMut) {
-        let clamp = (1.0 - self.drive).max(0.05);
-        let gain = 1.0 / clamp;
-        for ((li, ri), (lo, ro)) in input.iter().zip(output) {
-            *lo += li.clamp(-clamp, clamp) * gain * self.level;
-            *ro += ri.clamp(-clamp, clamp) * gain * self.level;
+    pub fn forward(&mut self, input: AudioBufferRef, mut output: AudioBufferMut) {
+        if !self.drive.is_smoothing() && !self.level.is_smoothing() {
+            let (clamp, gain) = self.shape();
+            for (c, output) in output.channels_mut().enumerate() {
+                for (o, i) in output.iter_mut().zip(input.channel(c)) {
+                    *o += i.clamp(-clamp, clamp) * gain;
+                }
+            }
+            return;
         }
+        // 参数平滑期间逐帧前进
+        for f in 0..output.len() {
+            self.drive.next();
+            self.level.next();
+            let (clamp, gain) = self.shape();
+            for c in 0..output.channels() {
+                *output.get_mut(f, c) += input.get(f, c).clamp(-clamp, clamp) * gain;
+            }
+        }
+    }
+
+    /// 当前参数对应的阈值和增益
+    fn shape(&self) -> (f64, f64) {
+        let clamp = (1.0 - self.drive.value()).max(0.05);
+        (clamp, self.level.value() / clamp)
+    }
+}
+
+impl SplitRender for DigitalOverDrive {
+    fn handle_message(&mut self, message: &Message) {
+        self.set_state(message)
     }
 }
 
@@ -112,7 +127,9 @@ impl AudioEffectNode for DigitalOverDrive {
         self.name.clone()
     }
 
-    fn prepare(&mut self, _sample_rate: f64) -> AudioEffectDesc {
+    fn prepare(&mut self, sample_rate: f64) -> AudioEffectDesc {
+        self.drive.set_sample_rate(sample_rate);
+        self.level.set_sample_rate(sample_rate);
         Self::prepare()
     }
 
@@ -3,4 +3,118 @@ pub use digital_overdrive::*;
 mod simple_saw;
 pub use simple_saw::*;
 mod wave_fold;
-pub use wave_fold::*;
\ No newline at end of file
+pub use wave_fold::*;
+
+use rarity_engine::{
+    node_info, register_node, BoxedNode, NodeInfo, NodeType, RegistryError, RegistryResult,
+};
+use serde_json::json;
+
+/// 参数平滑的时间, 单位为毫秒
+pub(crate) const SMOOTHING_MS: f64 = 20.0;
+
+/// 把本库的节点类型注册到节点注册表, 类型名以 `rarity.` 开头
+///
+/// 本库已经注册过的类型会被跳过, 类型名被其它类型占用时返回错误.
+pub fn register() -> RegistryResult<()> {
+    let nodes = [
+        NodeInfo::new::<SimpleSaw>(
+            "rarity.simple_saw",
+            "Simple Saw",
+            NodeType::AudioSource,
+            json!({ "max_voice": 8 }),
+            |desc| {
+                let max_voice = desc.args["max_voice"]
+                    .as_u64()
+                    .filter(|v| *v > 0)
+                    .ok_or("max_voice must be a positive integer")?;
+                let node = SimpleSaw::new(&desc.name, max_voice as usize);
+                Ok(BoxedNode::audio_source(node))
+            },
+        ),
+        NodeInfo::new::<WaveFold>(
+            "rarity.wave_fold",
+            "Wave Fold",
+            NodeType::AudioEffect,
+            json!({}),
+            |desc| Ok(BoxedNode::audio_effect(WaveFold::new(&desc.name))),
+        ),
+        NodeInfo::new::<DigitalOverDrive>(
+            "rarity.digital_overdrive",
+            "Digital Overdrive",
+            NodeType::AudioEffect,
+            json!({}),
+            |desc| Ok(BoxedNode::audio_effect(DigitalOverDrive::new(&desc.name))),
+        ),
+    ];
+    for info in nodes {
+        let rust_type = info.rust_type;
+        match register_node(info) {
+            Err(RegistryError::RepeatedType(id))
+                if node_info(&id).is_some_and(|i| i.rust_type == rust_type) => {}
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use rarity_engine::{
+        create_node, node_id_of, unregister_node, DescriptionError, Graph, GraphDescription,
+        ParameterError, ParameterValue,
+    };
+
+    use super::*;
+
+    #[test]
+    fn register_nodes() {
+        register().unwrap();
+        // 重复注册本库的类型不是错误
+        register().unwrap();
+        assert_eq!(
+            node_id_of::<SimpleSaw>().as_deref(),
+            Some("rarity.simple_saw")
+        );
+
+        let json = r#"{
+            "nodes": [{ "type": "rarity.simple_saw", "name": "saw", "parameters": { "Shape": 2 } }],
+            "audio_links": [["saw", "A_OUT_NODE"]]
+        }"#;
+        let mut desc = GraphDescription::from_json(json).unwrap();
+        Graph::from_description(&desc, 48000.0, 16, &mut create_node).unwrap();
+        // 枚举参数的数值需要是非负整数
+        for shape in [-1.0, 1.5] {
+            let shape = ParameterValue::Number(shape);
+            desc.nodes[0].parameters.insert("Shape".to_string(), shape);
+            assert!(matches!(
+                Graph::from_description(&desc, 48000.0, 16, &mut create_node),
+                Err(DescriptionError::ParameterError(
+                    ParameterError::OutOfRange(..)
+                ))
+            ));
+        }
+        desc.nodes[0].args = json!({ "max_voice": 0 });
+        assert!(matches!(
+            create_node(&desc.nodes[0]),
+            Err(DescriptionError::InvalidArgs(name, _)) if name == "saw"
+        ));
+
+        // 类型名被其它类型占用
+        let info = unregister_node("rarity.wave_fold").unwrap();
+        let other = NodeInfo::new::<()>(
+            "rarity.wave_fold",
+            "Other",
+            NodeType::AudioEffect,
+            json!({}),
+            |_| Err("not a node".to_string()),
+        );
+        register_node(other).unwrap();
+        assert!(matches!(
+            register(),
+            Err(RegistryError::RepeatedType(id)) if id == "rarity.wave_fold"
+        ));
+        unregister_node("rarity.wave_fold");
+        register_node(info).unwrap();
+    }
+}
@@ -1,10 +1,13 @@
 use std::f64::consts::PI;
 
 use rarity_engine::{
-    AudioBufferMut, AudioSourceDesc, AudioSourceNode, FloatRange, Message, MessageBuffer,
-    MessageValue, MidiMessage, ParaRange, Parameter, PlayHead,
+    split_render, AudioBufferMut, AudioSourceDesc, AudioSourceNode, EnumRange, FloatRange, Message,
+    MessageBuffer, MessageValue, MidiMessage, ParaRange, Parameter, PlayHead, SmoothedValue,
+    Smoothing, SplitRender, Taper, Unit,
 };
 
+use crate::SMOOTHING_MS;
+
 // static PREPARE_SAMPLES: usize = 32;
 // static PREPARE_SAMPLES_F64: f64 = PREPARE_SAMPLES as f64;
 
@@ -15,6 +18,26 @@ pub struct SimpleSaw {
     voice_counter: usize,
 }
 
+/// 振荡器的波形, 以 Shape 参数的选项下标切换
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Shape {
+    #[default]
+    Sine,
+    Saw,
+    Square,
+    Triangle,
+}
+
+impl Shape {
+    pub const LABELS: [&'static str; 4] = ["Sine", "Saw", "Square", "Triangle"];
+
+    pub fn from_index(index: usize) -> Option<Self> {
+        [Self::Sine, Self::Saw, Self::Square, Self::Triangle]
+            .get(index)
+            .copied()
+    }
+}
+
 impl SimpleSaw {
     pub fn new(name: &str, max_voice: usize) -> Self {
         Self {
@@ -31,6 +54,7 @@ impl SimpleSaw {
             v.set_sample_rate(sample_rate);
         }
         AudioSourceDesc {
+            channels: 2,
             parameters: vec![
                 Parameter {
                     addr: vec![],
@@ -39,6 +63,9 @@ impl SimpleSaw {
                         min: 0.0,
                         max: 1.0,
                         default: 1.0,
+                        taper: Taper::Linear,
+                        unit: Unit::Percent,
+                        step: 0.0,
                     }),
                 },
                 Parameter {
@@ -48,6 +75,9 @@ impl SimpleSaw {
                         min: 0.0,
                         max: 10.0,
                         default: 0.0,
+                        taper: Taper::Exponential(4.0),
+                        unit: Unit::Second,
+                        step: 0.0,
                     }),
                 },
                 Parameter {
@@ -57,6 +87,9 @@ impl SimpleSaw {
                         min: 0.0,
                         max: 10.0,
                         default: 0.0,
+                        taper: Taper::Exponential(4.0),
+                        unit: Unit::Second,
+                        step: 0.0,
                     }),
                 },
                 Parameter {
@@ -66,6 +99,17 @@ impl SimpleSaw {
                         min: 0.0,
                         max: 1.0,
                         default: 1.0,
+                        taper: Taper::Linear,
+                        unit: Unit::Percent,
+                        step: 0.0,
+                    }),
+                },
+                Parameter {
+                    addr: vec![],
+                    range: ParaRange::Enum(EnumRange {
+                        name: "Shape".to_string(),
+                        options: Shape::LABELS.iter().map(|l| l.to_string()).collect(),
+                        default: 0,
                     }),
                 },
                 Parameter {
@@ -75,37 +119,25 @@ impl SimpleSaw {
                         min: 0.0,
                         max: 10.0,
                         default: 0.0,
+                        taper: Taper::Exponential(4.0),
+                        unit: Unit::Second,
+                        step: 0.0,
                     }),
                 },
             ],
+            ..Default::default()
         }
     }
 
     pub fn process(
         &mut self,
         frames: usize,
-        audio_out: AudioBufferMut,
+        mut audio_out: AudioBufferMut,
         message_in: &MessageBuffer,
     ) {
-        let mut curr_frame = 0;
-        let mut remain = audio_out;
-        for (f, msg) in message_in {
-            if *f >= frames {
-                break;
-            }
-            let f = *f.min(&frames);
-            if curr_frame < f {
-                let (output, tmp) = remain.split_at_mut(f - curr_frame);
-                remain = tmp;
-                self.forward(output);
-                curr_frame = f;
-            }
-            self.set_state(msg);
-        }
-        if curr_frame < frames {
-            let (output, _) = remain.split_at_mut(frames - curr_frame);
-            self.forward(output);
-        }
+        split_render(self, frames, message_in, |node, range| {
+            node.forward(audio_out.slice_mut(range))
+        });
     }
 
     pub fn set_state(&mut self, message: &Message) {
@@ -136,7 +168,19 @@ impl SimpleSaw {
                     self.set_r(msg.value);
                 }
             }
-            _ => {}
+            MessageValue::Enum(msg) => {
+                if &msg.name == "Shape" {
+                    if let Some(shape) = Shape::from_index(msg.value) {
+                        self.set_shape(shape);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn set_shape(&mut self, shape: Shape) {
+        for voice in self.voices.iter_mut() {
+            voice.osc.shape = shape;
         }
     }
 
@@ -226,6 +270,12 @@ impl SimpleSaw {
     }
 }
 
+impl SplitRender for SimpleSaw {
+    fn handle_message(&mut self, message: &Message) {
+        self.set_state(message)
+    }
+}
+
 impl AudioSourceNode for SimpleSaw {
     fn name(&self) -> String {
         self.name.clone()
@@ -249,7 +299,7 @@ impl AudioSourceNode for SimpleSaw {
 struct Voice {
     counter: usize,
     pitch: u8,
-    volume: f64,
+    volume: SmoothedValue,
     osc: SawOSC,
     amp: ADSR,
     sr: f64,
@@ -263,7 +313,7 @@ impl Voice {
         Self {
             counter: 0,
             pitch: 0,
-            volume: 1.0,
+            volume: SmoothedValue::new(Smoothing::Linear, SMOOTHING_MS, 1.0),
             osc: SawOSC::new(0, 48000.0),
             amp: ADSR::new(0.0, 0.0, 1.0, 0.0, 48000.0),
             sr: 48000.0,
@@ -281,6 +331,7 @@ impl Voice {
         if sample_rate != self.sr {
             self.osc.set_sample_rate(sample_rate);
             self.amp.set_sample_rate(sample_rate);
+            self.volume.set_sample_rate(sample_rate);
             self.sr = sample_rate;
         }
     }
@@ -305,7 +356,7 @@ impl Voice {
     }
 
     fn set_volume(&mut self, volume: f64) {
-        self.volume = volume;
+        self.volume.set_target(volume);
     }
 
     fn set_a(&mut self, a_in_sec: f64) {
@@ -335,7 +386,7 @@ impl Voice {
             // } else {
             let s1 = self.osc.next().unwrap_or_default();
             let s2 = self.amp.next().unwrap_or_default();
-            let s = s1 * s2 * self.volume;
+            let s = s1 * s2 * self.volume.next();
             *l += s;
             *r += s;
             // }
@@ -352,6 +403,7 @@ struct SawOSC {
     volume: f64,
     velocity_volume: f64,
     last_output: f64,
+    shape: Shape,
 }
 
 impl SawOSC {
@@ -364,6 +416,7 @@ impl SawOSC {
             velocity_volume: 0.0,
             step: 440.0 * 2_f64.powf((pitch as f64 - 81.0) / 12.0) / sample_rate,
             last_output: 0.0,
+            shape: Shape::default(),
         }
     }
 
@@ -387,7 +440,13 @@ impl Iterator for SawOSC {
     type Item = f64;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let res = (self.pos * PI * 2.0).sin();
+        let res = match self.shape {
+            Shape::Sine => (self.pos * PI * 2.0).sin(),
+            Shape::Saw => self.pos * 2.0 - 1.0,
+            Shape::Square if self.pos < 0.5 => 1.0,
+            Shape::Square => -1.0,
+            Shape::Triangle => 1.0 - (self.pos * 4.0 - 2.0).abs(),
+        };
         // let res = self.pos * 2.0 - 1.0;
         // let res = (-3..=3)
         //     .map(|i| (self.pos + i as f64 * self.step).clamp(0.0, 1.0) * 2.0 - 1.0)
@@ -532,10 +591,11 @@ impl Iterator for ADSR {
 }
 
 #[cfg(test)]
+#[allow(deprecated)]
 mod test {
-    use std::{fs::File, path::Path};
+    use std::{env, fs::File};
 
-    use rarity_engine::AudioBuffer;
+    use rarity_engine::{AudioBuffer, EnumMessage};
 
     use super::*;
 
@@ -563,13 +623,44 @@ mod test {
         let data = audio
             .next_n_frames_ref(48000 * 5)
             .into_iter()
-            .map(|(l, r)| [*l as f32, *r as f32])
-            .flatten()
+            .flat_map(|(l, r)| [*l as f32, *r as f32])
             .collect::<Vec<_>>();
-        let mut out_file =
-            File::create(Path::new("/Users/chenzhengyang/Desktop/simple_saw.wav")).unwrap();
+        let mut out_file = File::create(env::temp_dir().join("simple_saw.wav")).unwrap();
         let header = wav::Header::new(3, 2, 48000, 32);
         let data = wav::BitDepth::ThirtyTwoFloat(data);
         wav::write(header, &data, &mut out_file).unwrap();
     }
+
+    #[test]
+    fn shape() {
+        let render = |shape: Option<usize>| {
+            let mut saw = SimpleSaw::new("saw", 1);
+            saw.prepare(48000.0);
+            if let Some(value) = shape {
+                saw.set_state(&Message {
+                    addr: vec![],
+                    value: MessageValue::Enum(EnumMessage {
+                        name: "Shape".to_string(),
+                        value,
+                    }),
+                });
+            }
+            saw.set_note_on(69, 100);
+            let mut audio = AudioBuffer::new(4800);
+            saw.forward(audio.next_n_frames_mut(4800));
+            audio
+                .next_n_frames_ref(4800)
+                .into_iter()
+                .map(|(l, _)| *l)
+                .collect::<Vec<_>>()
+        };
+        let sine = render(None);
+        assert!(sine.iter().any(|s| *s != 0.0));
+        assert_eq!(render(Some(0)), sine);
+        // 超出选项范围的下标不改变波形
+        assert_eq!(render(Some(4)), sine);
+        let square = render(Some(2));
+        assert_ne!(square, sine);
+        assert_ne!(render(Some(1)), square);
+    }
 }
@@ -13,17 +13,19 @@ use rosc::{OscPacket, OscType};
 
 use rarity::{
     engine::{
-        AudioBuffer, FloatMessage, Graph, Message, MessageCollector, MessageValue, MidiMessage,
-        NoteOn, PlayHead, A_OUT_NODE,
+        create_node, AudioBuffer, BoxedNode, FloatMessage, Graph, GraphDescription, GraphPlayer,
+        Message, MessageCollector, MessageValue, MidiMessage, NoteOn, ParameterTree, PlayHead,
     },
     node::{DigitalOverDrive, SimpleSaw, WaveFold},
 };
 
+const BLOCK_SIZE: usize = 4096;
+
 fn run<T: SizedSample + FromSample<f32>>(
     device: &Device,
     config: &StreamConfig,
     mut collector: MessageCollector,
-    mut graph: Graph,
+    mut player: GraphPlayer,
 ) -> Stream {
     let channels = config.channels as usize;
     let playhead = PlayHead {
@@ -33,30 +35,33 @@ fn run<T: SizedSample + FromSample<f32>>(
         samples_from_last_bar: 0.0,
         samples_per_quarter: 0.0,
     };
-    let mut audio_in = AudioBuffer::new(4096);
-    let mut audio_out = AudioBuffer::new(4096);
+    let mut audio_in = AudioBuffer::<f32>::with_channels(2, BLOCK_SIZE);
+    let mut audio_out = AudioBuffer::<f32>::with_channels(2, BLOCK_SIZE);
     device
         .build_output_stream(
-            config.into(),
+            config,
             move |data: &mut [T], _| {
-                let frames = data.len() / channels;
-                let audio_in_ref = audio_in.next_n_frames_ref(frames);
-                let audio_out_mut = audio_out.next_n_frames_mut(frames);
-                collector.collect();
-                let message_in = collector.drain_frames(frames);
-                graph.process(&playhead, frames, audio_in_ref, audio_out_mut, &message_in);
-                for ((l, r), f) in audio_out
-                    .next_n_frames_ref(frames)
-                    .into_iter()
-                    .zip(data.chunks_mut(channels))
-                {
-                    f[0] = T::from_sample_(*l as f32);
-                    f[1] = T::from_sample_(*r as f32);
+                // 主机的缓冲可能比中转缓冲大, 按块依次处理
+                for data in data.chunks_mut(BLOCK_SIZE * channels) {
+                    let frames = data.len() / channels;
+                    let audio_in_ref = audio_in.next_n_frames_ref(frames);
+                    let audio_out_mut = audio_out.next_n_frames_mut(frames);
+                    collector.collect();
+                    let message_in = collector.drain_frames(frames);
+                    player.process(&playhead, frames, audio_in_ref, audio_out_mut, &message_in);
+                    for ((l, r), f) in audio_out
+                        .next_n_frames_ref(frames)
+                        .into_iter()
+                        .zip(data.chunks_mut(channels))
+                    {
+                        f[0] = T::from_sample_(*l);
+                        f[1] = T::from_sample_(*r);
+                    }
+                    audio_in.next_n_frames_mut(frames).clear();
+                    audio_out.next_n_frames_mut(frames).clear();
+                    audio_in.forward(frames);
+                    audio_out.forward(frames);
                 }
-                audio_in.next_n_frames_mut(frames).clear();
-                audio_out.next_n_frames_mut(frames).clear();
-                audio_in.forward(frames);
-                audio_out.forward(frames);
             },
             |err| eprintln!("err: {}", err),
             None,
@@ -65,31 +70,40 @@ fn run<T: SizedSample + FromSample<f32>>(
 }
 
 fn main() {
-    let mut graph = Graph::new("mock");
-    graph.add_audio_source(SimpleSaw::new("simple_saw", 3));
-    graph.add_audio_effect(WaveFold::new("overdrive"));
-    graph.add_audio_link("simple_saw", "overdrive");
-    graph.add_audio_link("overdrive", A_OUT_NODE);
+    let desc = GraphDescription::from_json(include_str!("mock_graph.json")).unwrap();
     let mut collector = MessageCollector::new();
     let sender = collector.add_port(vec![]);
+    // 不存在的参数和超出范围的取值直接报告
+    let send = |parameters: &ParameterTree, frame, message: Message| {
+        if let Err(e) = parameters.check(&message) {
+            println!("Error: {}", e);
+            return;
+        }
+        sender.send((frame, message)).unwrap();
+    };
     let host = cpal::default_host();
     let device = host.default_output_device().unwrap();
     let config = device.default_output_config().unwrap();
     let sample_rate = config.sample_rate().0 as f64;
     println!("Sample rate: {}", sample_rate);
-    graph.mock_prepare(sample_rate);
+    rarity::node::register().unwrap();
+    let graph = Graph::from_description(&desc, sample_rate, BLOCK_SIZE, &mut create_node).unwrap();
+    let mut player = GraphPlayer::new(graph);
+    let mut editor = player.editor();
+    let mut parameters = editor.parameters();
+    let mut folded = true;
 
     let stream = match config.sample_format() {
-        SampleFormat::I8 => run::<i8>(&device, &config.into(), collector, graph),
-        SampleFormat::I16 => run::<i16>(&device, &config.into(), collector, graph),
-        SampleFormat::I32 => run::<i32>(&device, &config.into(), collector, graph),
-        SampleFormat::I64 => run::<i64>(&device, &config.into(), collector, graph),
-        SampleFormat::U8 => run::<u8>(&device, &config.into(), collector, graph),
-        SampleFormat::U16 => run::<u16>(&device, &config.into(), collector, graph),
-        SampleFormat::U32 => run::<u32>(&device, &config.into(), collector, graph),
-        SampleFormat::U64 => run::<u64>(&device, &config.into(), collector, graph),
-        SampleFormat::F32 => run::<f32>(&device, &config.into(), collector, graph),
-        SampleFormat::F64 => run::<f64>(&device, &config.into(), collector, graph),
+        SampleFormat::I8 => run::<i8>(&device, &config.into(), collector, player),
+        SampleFormat::I16 => run::<i16>(&device, &config.into(), collector, player),
+        SampleFormat::I32 => run::<i32>(&device, &config.into(), collector, player),
+        SampleFormat::I64 => run::<i64>(&device, &config.into(), collector, player),
+        SampleFormat::U8 => run::<u8>(&device, &config.into(), collector, player),
+        SampleFormat::U16 => run::<u16>(&device, &config.into(), collector, player),
+        SampleFormat::U32 => run::<u32>(&device, &config.into(), collector, player),
+        SampleFormat::U64 => run::<u64>(&device, &config.into(), collector, player),
+        SampleFormat::F32 => run::<f32>(&device, &config.into(), collector, player),
+        SampleFormat::F64 => run::<f64>(&device, &config.into(), collector, player),
         _ => panic!("Unknown SampleFormat"),
     };
     stream.play().unwrap();
@@ -103,43 +117,65 @@ fn main() {
             Ok((size, _addr)) => {
                 let (_, packet) = rosc::decoder::decode_udp(&buf[..size]).unwrap();
                 match packet {
+                    OscPacket::Message(msg) if msg.addr == "/swap" => {
+                        folded = !folded;
+                        let node = if folded {
+                            BoxedNode::audio_effect(WaveFold::new("overdrive"))
+                        } else {
+                            BoxedNode::audio_effect(DigitalOverDrive::new("overdrive"))
+                        };
+                        editor.replace_node("overdrive", node).unwrap();
+                        if let Err(e) = editor.commit() {
+                            println!("Error swapping node: {}", e);
+                        }
+                        parameters = editor.parameters();
+                    }
                     OscPacket::Message(msg) => {
                         for value in msg.args {
-                            if let OscType::Float(v) = value {
-                                let now = Instant::now();
-                                let frame = ((now - start_time).as_secs_f64() * sample_rate).floor()
-                                    as usize;
-                                let mut addr = msg
-                                    .addr
-                                    .split('/')
-                                    .map(|s| s.to_string())
-                                    .collect::<Vec<_>>();
-                                addr.remove(0);
-                                if addr.len() == 1 {
-                                    let name = addr.pop().unwrap();
-                                    addr.push("simple_saw".to_string());
-                                    let msg = match name.parse::<u8>() {
-                                        Ok(pitch) => {
-                                            MessageValue::Midi(MidiMessage::NoteOn(NoteOn {
-                                                pitch,
-                                                velocity: (v * 128.0) as u8,
-                                            }))
-                                        }
-                                        Err(_) => MessageValue::Float(FloatMessage {
-                                            name,
-                                            value: v as f64,
-                                        }),
-                                    };
-                                    sender.send((frame, Message { addr, value: msg })).unwrap();
-                                } else if addr.len() == 2 {
-                                    let name = addr.pop().unwrap();
-                                    let msg = MessageValue::Float(FloatMessage {
+                            let now = Instant::now();
+                            let frame =
+                                ((now - start_time).as_secs_f64() * sample_rate).floor() as usize;
+                            let mut addr = msg
+                                .addr
+                                .split('/')
+                                .map(|s| s.to_string())
+                                .collect::<Vec<_>>();
+                            addr.remove(0);
+                            if addr.len() == 1 {
+                                addr.insert(0, "simple_saw".to_string());
+                            }
+                            if addr.len() != 2 {
+                                continue;
+                            }
+                            let name = addr.remove(1);
+                            let msg = match (value, name.parse::<u8>()) {
+                                (OscType::Float(v), Ok(pitch)) => {
+                                    MessageValue::Midi(MidiMessage::NoteOn(NoteOn {
+                                        pitch,
+                                        velocity: (v * 128.0) as u8,
+                                    }))
+                                }
+                                // OSC 控制器发送 0..1 的归一化值, 按参数的映射换算
+                                (OscType::Float(v), Err(_)) => match parameters.get(&addr, &name) {
+                                    Some(range) => range.from_normalized(v as f64),
+                                    None => MessageValue::Float(FloatMessage {
                                         name,
                                         value: v as f64,
-                                    });
-                                    sender.send((frame, Message { addr, value: msg })).unwrap();
+                                    }),
+                                },
+                                // 字符串按参数的单位或选项标签解析, 如 `/simple_saw/Shape square`
+                                (OscType::String(text), _) => {
+                                    match parameters.parse(&addr, &name, &text) {
+                                        Ok(msg) => msg.value,
+                                        Err(e) => {
+                                            println!("Error: {}", e);
+                                            continue;
+                                        }
+                                    }
                                 }
-                            }
+                                _ => continue,
+                            };
+                            send(&parameters, frame, Message { addr, value: msg });
                         }
                     }
                     OscPacket::Bundle(bundle) => {